@@ -0,0 +1,94 @@
+use std::fmt;
+
+use object::Object;
+
+// 複数のオブジェクトをまとめたライブラリ
+#[derive(Debug,Clone,PartialEq)]
+pub struct Archive {
+    pub members: Vec<Object>,
+}
+
+impl Archive {
+
+    pub fn new() -> Archive {
+        Archive{members: Vec::new()}
+    }
+
+    // 同名のメンバがあれば置き換え，なければ末尾に追加する
+    pub fn insert(&mut self, obj: Object) {
+        match self.members.iter().position(|m| m.name == obj.name) {
+            Some(i) => self.members[i] = obj,
+            None => self.members.push(obj),
+        }
+    }
+
+    pub fn find(&self, symbol: &str) -> Option<&Object> {
+        self.members.iter().find(|m| m.defines(symbol))
+    }
+
+    pub fn parse(s: &str) -> Result<Archive, String> {
+
+        let mut lines = s.lines().peekable();
+
+        if lines.next() != Some("CASL2LIB") {
+            return Err("Not a CASL2 archive".to_string());
+        }
+
+        let mut archive = Archive::new();
+
+        loop {
+            match lines.peek() {
+                Some(l) if l.trim().is_empty() => {
+                    lines.next();
+                },
+                Some(_) => archive.members.push(Object::read(&mut lines)?),
+                None => break,
+            }
+        }
+
+        Ok(archive)
+    }
+}
+
+impl Default for Archive {
+    fn default() -> Archive {
+        Archive::new()
+    }
+}
+
+impl fmt::Display for Archive {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        writeln!(f, "CASL2LIB")?;
+
+        for m in &self.members {
+            write!(f, "{}", m)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_archive() {
+
+    let mut mult = Object::new("MULT");
+    mult.code = vec![0x8100];
+    mult.defs.push(("MULT".to_string(), 0));
+
+    let mut div = Object::new("DIV");
+    div.code = vec![0x8100];
+    div.defs.push(("DIV".to_string(), 0));
+
+    let mut archive = Archive::new();
+    archive.insert(mult.clone());
+    archive.insert(div);
+    mult.code = vec![0x0000, 0x8100];
+    archive.insert(mult);
+
+    assert_eq!(archive.members.len(), 2);
+    assert_eq!(archive.find("MULT").unwrap().code.len(), 2);
+    assert!(archive.find("ITOA").is_none());
+    assert_eq!(Archive::parse(&archive.to_string()), Ok(archive));
+}
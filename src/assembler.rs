@@ -1,6 +1,252 @@
+use ast::{Error,ErrorKind,Operand,Macro,Statement,Program};
+use comet2::{SVC_IN,SVC_OUT};
+use constant::Constant;
+use object::Object;
+use opcode::{get_opcode,Format};
+use parser::parse_programs;
+use register::Register;
+use token::SymbolTable;
+
 pub fn is_assembler(s: &str) -> bool {
-    match s {
-        "START" | "DC" | "DS" | "END" => true,
-        _ => false
+    matches!(s, "START" | "DC" | "DS" | "END")
+}
+
+// ソースコードを再配置可能なオブジェクトに変換する
+//
+// START から END までのプログラムごとに1つのオブジェクトになる
+pub fn assemble(codes: &str) -> Result<Vec<Object>, Vec<Error>> {
+
+    let (programs, mut errors) = parse_programs(codes);
+
+    let objects = programs
+        .iter()
+        .map(|p| assemble_program(p, &mut errors))
+        .collect::<Vec<Object>>();
+
+    if errors.is_empty() {
+        Ok(objects)
+    } else {
+        errors.sort_by_key(|e| e.span);
+        Err(errors)
+    }
+}
+
+// 1回目で各行の番地とラベルを決め，2回目で機械語にする
+pub fn assemble_program(program: &Program, errors: &mut Vec<Error>) -> Object {
+
+    let mut labels = SymbolTable::new();
+    let mut addr: u16 = 0;
+
+    for line in &program.lines {
+
+        if let Some(ref l) = line.label {
+            if labels.contains_key(&l.node) {
+                errors.push(Error::new(ErrorKind::DuplicateLabel, l.span, format!("Duplicate label `{}`", l.node)));
+            } else {
+                labels.insert(l.node.to_string(), addr);
+            }
+        }
+
+        addr = addr.wrapping_add(line.statement.words());
+    }
+
+    // リテラルはENDの後ろに順に置く
+    let mut literals: Vec<u16> = Vec::new();
+    let mut pool: Vec<u16> = Vec::new();
+
+    for line in &program.lines {
+        match line.statement.literal() {
+            Some(c) => {
+                literals.push(addr.wrapping_add(pool.len() as u16));
+                pool.extend(c.values());
+            },
+            None => literals.push(0),
+        }
     }
+
+    let mut obj = Object::new(&program.name);
+
+    for (line, literal) in program.lines.iter().zip(literals) {
+
+        // 語を生成する行だけを行番号表に入れる
+        if line.statement.words() > 0 {
+            obj.lines.push((obj.code.len() as u16, line.number() + 1));
+        }
+
+        match line.statement {
+
+            Statement::Start(ref entry) => {
+
+                // プログラム名はSTARTのラベル，入口はSTARTのオペランド
+                let entry = match *entry {
+                    Some(ref e) => match labels.get(&e.node) {
+                        Some(v) => *v,
+                        None => {
+                            errors.push(Error::new(ErrorKind::UndefinedLabel, e.span, format!("Entry label `{}` is not defined in `{}`", e.node, program.name)));
+                            0
+                        },
+                    },
+                    None => 0,
+                };
+
+                obj.defs.push((program.name.to_string(), entry));
+            },
+
+            Statement::End => {},
+
+            Statement::Ds(n) => {
+                obj.code.extend(vec![0; n as usize]);
+            },
+
+            Statement::Dc(ref constants) => {
+                for c in constants {
+                    match c.node {
+                        Constant::Address(ref s) => {
+                            let v = resolve_label(s, &labels, &mut obj);
+                            obj.code.push(v);
+                        },
+                        _ => obj.code.extend(c.node.values()),
+                    }
+                }
+            },
+
+            Statement::Macro(m, _) => match m {
+
+                // RPUSHは PUSH 0,GRn を7つ展開するので14語
+                Macro::Rpush => {
+                    for i in 1..8 {
+                        obj.code.push((get_opcode("PUSH") << 8) | i);
+                        obj.code.push(0);
+                    }
+                },
+
+                // RPUSHと逆の順に戻す
+                Macro::Rpop => {
+                    for i in (1..8).rev() {
+                        obj.code.push((get_opcode("POP") << 8) | (i << 4));
+                    }
+                },
+
+                // GR1とGR2を退避し，領域と文字数の番地を渡してSVCを呼ぶ (12語)
+                Macro::In | Macro::Out => {
+
+                    obj.code.extend(&[(get_opcode("PUSH") << 8) | 1, 0, (get_opcode("PUSH") << 8) | 2, 0]);
+
+                    for (r, o) in [1, 2].iter().zip(line.statement.operands()) {
+                        let label = o.node.label().unwrap();
+                        obj.code.push((get_opcode("LAD") << 8) | (r << 4));
+                        let v = resolve_label(label, &labels, &mut obj);
+                        obj.code.push(v);
+                    }
+
+                    let n = if m == Macro::In { SVC_IN } else { SVC_OUT };
+                    obj.code.extend(&[get_opcode("SVC") << 8, n, (get_opcode("POP") << 8) | 0x20, (get_opcode("POP") << 8) | 0x10]);
+                },
+            },
+
+            Statement::Instruction(inst, ref operands) => {
+
+                let number = |r: Option<Register>| r.map_or(0, |r| r.number());
+
+                // LD r1,r2 の r2 は x と同じ位置に入る
+                let (r, x) = match inst.format {
+                    Format::R1R2 => (number(operands[0].node.register()), number(operands[1].node.register())),
+                    _ => (number(line.statement.r()), number(line.statement.x())),
+                };
+
+                obj.code.push((inst.code << 8) | (r << 4) | x);
+
+                // 命令表の形式にしたがって2語目を作る
+                if let Some(adr) = line.statement.adr() {
+                    let v = match adr.node {
+                        Operand::Literal(_) => {
+                            obj.relocs.push(obj.code.len() as u16);
+                            literal
+                        },
+                        Operand::Constant(Constant::Address(ref s)) => resolve_label(s, &labels, &mut obj),
+                        Operand::Constant(ref c) => c.values()[0],
+                        Operand::Register(_) => unreachable!(),
+                    };
+                    obj.code.push(v);
+                }
+            },
+        }
+    }
+
+    obj.code.append(&mut pool);
+
+    let mut symbols: Vec<(String,u16)> = labels.into_iter().collect();
+    symbols.sort_by_key(|(name, addr)| (*addr, name.to_string()));
+    obj.labels = symbols;
+
+    obj
+}
+
+// ラベルなら番地を求め，再配置情報を記録する
+//
+// 定義されていないラベルは他のプログラムのものとみなし，リンク時に解決する
+fn resolve_label(label: &str, labels: &SymbolTable, obj: &mut Object) -> u16 {
+
+    let offset = obj.code.len() as u16;
+
+    match labels.get(label) {
+        Some(v) => {
+            obj.relocs.push(offset);
+            *v
+        },
+        None => {
+            obj.refs.push((label.to_string(), offset));
+            0
+        },
+    }
+}
+
+#[test]
+fn test_assemble() {
+
+    let src = "\
+MAIN\tSTART\tBEGIN
+DATA\tDC\t3,'a''b',MAIN,SUB
+BEGIN\tLD\tGR1,DATA,GR2\t; 注釈
+\tADDA\tGR1,=-1
+\tLD\tGR0,GR1
+\tCALL\tSUB
+\tRET
+\tEND
+SUB\tSTART
+\tRET
+\tEND
+";
+
+    let objects = assemble(src).unwrap();
+    assert_eq!(objects.len(), 2);
+
+    let main = &objects[0];
+    assert_eq!(main.defs, vec![("MAIN".to_string(), 6)]);
+    assert_eq!(main.code, vec![
+        0x0003, 0x0061, 0x0027, 0x0062, 0x0000, 0x0000,
+        0x1012, 0x0000,
+        0x2010, 0x000e,
+        0x1401,
+        0x8000, 0x0000,
+        0x8100,
+        0xffff,
+    ]);
+    assert_eq!(main.relocs, vec![4, 7, 9]);
+    assert_eq!(main.refs, vec![("SUB".to_string(), 5), ("SUB".to_string(), 12)]);
+    assert_eq!(main.labels[0], ("DATA".to_string(), 0));
+    assert_eq!(main.lines, vec![(0, 2), (6, 3), (8, 4), (10, 5), (11, 6), (13, 7)]);
+    assert_eq!(objects[1].code, vec![0x8100]);
+
+    let errors = assemble("MAIN START NONE\nL RET\nL RET\n RPOP\n").unwrap_err();
+    let messages = errors.iter().map(|e| (e.span.line, e.message.as_str())).collect::<Vec<(usize,&str)>>();
+    assert_eq!(messages, vec![
+        (0, "Entry label `NONE` is not defined in `MAIN`"),
+        (2, "Duplicate label `L`"),
+        (3, "Missing END of `MAIN`"),
+    ]);
+
+    let objects = assemble("MAIN START\n OUT BUF,LEN\n RET\nBUF DC 'hi'\nLEN DC 2\n END\n").unwrap();
+    assert_eq!(&objects[0].code[..12], &[0x7001, 0, 0x7002, 0, 0x1210, 0x000d, 0x1220, 0x000f, 0xf000, 0x0002, 0x7120, 0x7110]);
+    assert_eq!(objects[0].relocs, vec![5, 7]);
 }
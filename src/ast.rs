@@ -0,0 +1,319 @@
+use std::fmt;
+
+use opcode::{Instruction,Format};
+
+pub use constant::Constant;
+pub use register::Register;
+
+// ソース上の位置
+//
+// 行番号は0始まり，start と end は行頭からのバイト位置
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord,Default)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+
+    pub fn new(line: usize, start: usize, end: usize) -> Span {
+        Span{line, start, end}
+    }
+
+    // 2つの位置を含む範囲 (同じ行のもの)
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.line, self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Spanned<T> {
+        Spanned{node, span}
+    }
+}
+
+// エラーの種類
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ErrorKind {
+    // 命令のあとの余計な字句
+    UnexpectedToken,
+    UnknownInstruction,
+    // ラベルだけで命令がない
+    MissingInstruction,
+    DuplicateLabel,
+    UndefinedLabel,
+    MissingStart,
+    MissingEnd,
+    InvalidLabel,
+    InvalidConstant,
+    InvalidOperand,
+    // 指標レジスタが GR1〜GR7 でない
+    InvalidIndex,
+    // START や END の書き方の誤り
+    InvalidStatement,
+}
+
+impl ErrorKind {
+
+    pub const ALL: [ErrorKind; 12] = [
+        ErrorKind::UnexpectedToken,
+        ErrorKind::UnknownInstruction,
+        ErrorKind::MissingInstruction,
+        ErrorKind::DuplicateLabel,
+        ErrorKind::UndefinedLabel,
+        ErrorKind::MissingStart,
+        ErrorKind::MissingEnd,
+        ErrorKind::InvalidLabel,
+        ErrorKind::InvalidConstant,
+        ErrorKind::InvalidOperand,
+        ErrorKind::InvalidIndex,
+        ErrorKind::InvalidStatement,
+    ];
+
+    // --message-format json などで出す名前
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::UnexpectedToken => "unexpected-token",
+            ErrorKind::UnknownInstruction => "unknown-instruction",
+            ErrorKind::MissingInstruction => "missing-instruction",
+            ErrorKind::DuplicateLabel => "duplicate-label",
+            ErrorKind::UndefinedLabel => "undefined-label",
+            ErrorKind::MissingStart => "missing-start",
+            ErrorKind::MissingEnd => "missing-end",
+            ErrorKind::InvalidLabel => "invalid-label",
+            ErrorKind::InvalidConstant => "invalid-constant",
+            ErrorKind::InvalidOperand => "invalid-operand",
+            ErrorKind::InvalidIndex => "invalid-index",
+            ErrorKind::InvalidStatement => "invalid-statement",
+        }
+    }
+
+    // SARIF の規則の説明
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorKind::UnexpectedToken => "Unexpected token after the operands",
+            ErrorKind::UnknownInstruction => "Unknown instruction",
+            ErrorKind::MissingInstruction => "Label without an instruction",
+            ErrorKind::DuplicateLabel => "Label defined twice in a program",
+            ErrorKind::UndefinedLabel => "Entry label of START is not defined",
+            ErrorKind::MissingStart => "Program does not begin with START",
+            ErrorKind::MissingEnd => "Program does not end with END",
+            ErrorKind::InvalidLabel => "Invalid label name",
+            ErrorKind::InvalidConstant => "Invalid constant or literal",
+            ErrorKind::InvalidOperand => "Invalid operands for the instruction",
+            ErrorKind::InvalidIndex => "Index register is not GR1 to GR7",
+            ErrorKind::InvalidStatement => "Invalid START or END statement",
+        }
+    }
+}
+
+// 位置つきのエラー
+#[derive(Debug,Clone,PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub span: Span,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, span: Span, message: String) -> Error {
+        Error{kind, span, message}
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line + 1, self.span.start + 1, self.message)
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Operand {
+    Register(Register),
+    // 10進定数，16進定数，アドレス定数 (ラベル)
+    Constant(Constant),
+    // =定数
+    Literal(Constant),
+}
+
+impl Operand {
+
+    pub fn register(&self) -> Option<Register> {
+        match *self {
+            Operand::Register(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    // 参照しているラベル
+    pub fn label(&self) -> Option<&str> {
+        match *self {
+            Operand::Constant(Constant::Address(ref s)) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Macro {
+    In,
+    Out,
+    Rpush,
+    Rpop,
+}
+
+impl Macro {
+
+    pub fn parse(s: &str) -> Option<Macro> {
+        match s {
+            "IN" => Some(Macro::In),
+            "OUT" => Some(Macro::Out),
+            "RPUSH" => Some(Macro::Rpush),
+            "RPOP" => Some(Macro::Rpop),
+            _ => None,
+        }
+    }
+
+    // 展開後の語数
+    pub fn words(self) -> u16 {
+        match self {
+            Macro::In | Macro::Out => 12,
+            Macro::Rpush => 14,
+            Macro::Rpop => 7,
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Statement {
+    // START [入口名]
+    Start(Option<Spanned<String>>),
+    End,
+    Ds(u16),
+    Dc(Vec<Spanned<Constant>>),
+    Macro(Macro, Vec<Spanned<Operand>>),
+    // オペランドは命令の形式の順に並ぶ
+    Instruction(&'static Instruction, Vec<Spanned<Operand>>),
+}
+
+impl Statement {
+
+    // 命令語や定数が占める語数 (リテラルは含まない)
+    pub fn words(&self) -> u16 {
+        match *self {
+            Statement::Start(_) | Statement::End => 0,
+            Statement::Ds(n) => n,
+            Statement::Dc(ref v) => v.iter().map(|c| c.node.words()).sum(),
+            Statement::Macro(m, _) => m.words(),
+            Statement::Instruction(inst, _) => inst.words(),
+        }
+    }
+
+    pub fn operands(&self) -> &[Spanned<Operand>] {
+        match *self {
+            Statement::Macro(_, ref v) | Statement::Instruction(_, ref v) => v,
+            _ => &[],
+        }
+    }
+
+    // 命令の r, adr, x
+    pub fn r(&self) -> Option<Register> {
+        match *self {
+            Statement::Instruction(inst, ref v) if inst.format != Format::AdrX => v.first().and_then(|o| o.node.register()),
+            _ => None,
+        }
+    }
+
+    pub fn adr(&self) -> Option<&Spanned<Operand>> {
+        match *self {
+            Statement::Instruction(inst, ref v) => match inst.format {
+                Format::RAdrX => v.get(1),
+                Format::AdrX => v.first(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn x(&self) -> Option<Register> {
+        match *self {
+            Statement::Instruction(inst, ref v) => match inst.format {
+                Format::RAdrX => v.get(2).and_then(|o| o.node.register()),
+                Format::AdrX => v.get(1).and_then(|o| o.node.register()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // adrに書かれたリテラル
+    pub fn literal(&self) -> Option<&Constant> {
+        match self.adr() {
+            Some(&Spanned{node: Operand::Literal(ref c), ..}) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+// 1行分の命令
+#[derive(Debug,Clone,PartialEq)]
+pub struct Line {
+    pub label: Option<Spanned<String>>,
+    // 書かれたとおりの命令名とその位置
+    pub opcode: Spanned<String>,
+    pub statement: Statement,
+    pub comment: Option<Spanned<String>>,
+    // 行全体 (注釈を除く)
+    pub span: Span,
+}
+
+impl Line {
+    pub fn number(&self) -> usize {
+        self.span.line
+    }
+}
+
+// START から END までの1つのプログラム
+#[derive(Debug,Clone,PartialEq)]
+pub struct Program {
+    pub name: String,
+    pub lines: Vec<Line>,
+}
+
+impl Program {
+
+    // プログラムの中で参照しているラベルとその位置
+    pub fn references(&self) -> Vec<(String,Span)> {
+
+        let mut refs: Vec<(String,Span)> = Vec::new();
+
+        for line in &self.lines {
+            match line.statement {
+                Statement::Start(Some(ref e)) => refs.push((e.node.to_string(), e.span)),
+                Statement::Dc(ref v) => {
+                    for c in v {
+                        if let Constant::Address(ref s) = c.node {
+                            refs.push((s.to_string(), c.span));
+                        }
+                    }
+                },
+                _ => {
+                    for o in line.statement.operands() {
+                        if let Some(s) = o.node.label() {
+                            refs.push((s.to_string(), o.span));
+                        }
+                    }
+                },
+            }
+        }
+
+        refs
+    }
+}
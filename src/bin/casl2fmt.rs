@@ -0,0 +1,103 @@
+extern crate getopts;
+extern crate rust_casl2;
+
+use std::fs;
+use std::io::{self,Read,Write};
+use std::process::exit;
+
+use getopts::Options;
+use rust_casl2::cli::print_errors;
+use rust_casl2::diagnostic::MessageFormat;
+use rust_casl2::format::format;
+
+// casl2fmt [--check] [FILE...] : ソースの桁をそろえる
+//
+// ファイルを指定しなければ標準入力を整形して標準出力に書き出す
+fn main() {
+
+    let args: Vec<String> = std::env::args().collect();
+
+    let mut opts = Options::new();
+
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("", "check", "don't rewrite files; exit with 1 if any of them isn't formatted");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            println!("{}", f);
+            exit(1);
+        },
+    };
+
+    if matches.opt_present("h") {
+        println!("{}", opts.usage(&format!("Usage: {} [--check] [FILE...]", args[0])));
+        exit(0);
+    }
+
+    let check = matches.opt_present("check");
+
+    if matches.free.is_empty() {
+
+        let mut src = String::new();
+
+        if let Err(e) = io::stdin().read_to_string(&mut src) {
+            println!("<stdin>: {}", e);
+            exit(1);
+        }
+
+        match format(&src) {
+            Ok(s) if check => exit(if s == src { 0 } else { 1 }),
+            Ok(s) => {
+                io::stdout().write_all(s.as_bytes()).unwrap();
+            },
+            Err(errors) => {
+                for e in errors {
+                    println!("<stdin>:{}", e);
+                }
+                exit(1);
+            },
+        }
+
+        return;
+    }
+
+    let mut failed = false;
+
+    for path in &matches.free {
+
+        let src = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("{}: {}", path, e);
+                failed = true;
+                continue;
+            },
+        };
+
+        let formatted = match format(&src) {
+            Ok(s) => s,
+            Err(errors) => {
+                print_errors(path, &src, &errors, MessageFormat::Human);
+                failed = true;
+                continue;
+            },
+        };
+
+        if formatted == src {
+            continue;
+        }
+
+        if check {
+            println!("{}: not formatted", path);
+            failed = true;
+        } else if let Err(e) = fs::write(path, formatted) {
+            println!("{}: {}", path, e);
+            failed = true;
+        }
+    }
+
+    if failed {
+        exit(1);
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+
+use ast::{Error,Line,Program,Statement};
+use json::Json;
+use opcode::Access;
+use parser::parse_programs;
+use stdlib::ROUTINES;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Json,
+}
+
+impl GraphFormat {
+    pub fn parse(s: &str) -> Option<GraphFormat> {
+        match s {
+            "dot" => Some(GraphFormat::Dot),
+            "json" => Some(GraphFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// 基本ブロック
+//
+// 先頭にラベルがあればそれを名前にし，なければ「プログラム名:行番号」にする
+#[derive(Debug,Clone,PartialEq)]
+pub struct Block {
+    pub name: String,
+    // 先頭の行 (0始まり)
+    pub line: usize,
+    pub code: Vec<String>,
+    // ブロックの命令
+    pub lines: Vec<Line>,
+    // 分岐先のブロックの名前と分岐命令 (次の行へ進むものは "fall")
+    pub succs: Vec<(String,&'static str)>,
+    // 呼び出すサブルーチン
+    pub calls: Vec<String>,
+}
+
+// プログラムの入口か CALL 先から RET までのブロック
+//
+// 他のプログラムと区別するため，CALL 先の id は「プログラム名.ラベル」にする
+#[derive(Debug,Clone,PartialEq)]
+pub struct Subroutine {
+    pub id: String,
+    pub name: String,
+    pub program: String,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Graph {
+    pub subroutines: Vec<Subroutine>,
+    // 呼び出し元と呼び出し先の id
+    pub calls: Vec<(String,String)>,
+}
+
+fn is_code(line: &Line) -> bool {
+    matches!(line.statement, Statement::Instruction(_, _) | Statement::Macro(_, _))
+}
+
+// JMI, JNZ, JZE, JPL, JOV, JUMP, CALL, RET の後ろでブロックを分ける
+fn ends_block(line: &Line) -> bool {
+    match line.statement {
+        Statement::Instruction(inst, _) => inst.access == Access::Branch || inst.mnemonic == "RET",
+        _ => false,
+    }
+}
+
+// 指標レジスタのない分岐先のラベル
+fn target(line: &Line) -> Option<&str> {
+    match line.statement.x() {
+        None => line.statement.adr().and_then(|o| o.node.label()),
+        Some(_) => None,
+    }
+}
+
+fn mnemonic(line: &Line) -> &'static str {
+    match line.statement {
+        Statement::Instruction(inst, _) => inst.mnemonic,
+        _ => "",
+    }
+}
+
+// ソースから制御フローグラフと呼び出しグラフを作る
+pub fn build(src: &str) -> Result<Graph, Vec<Error>> {
+
+    let (programs, errors) = parse_programs(src);
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut graph = Graph::default();
+
+    for p in &programs {
+        let g = build_program(p, src);
+        graph.subroutines.extend(g.subroutines);
+        graph.calls.extend(g.calls);
+    }
+
+    Ok(graph)
+}
+
+fn build_program(p: &Program, src: &str) -> Graph {
+
+    let lines = &p.lines;
+    let texts: Vec<&str> = src.lines().collect();
+
+    let labels: HashMap<&str,usize> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, l)| l.label.as_ref().map(|label| (label.node.as_str(), i)))
+        .collect();
+
+    // 命令の行ごとにブロックの番号を振る
+    let mut block_of: HashMap<usize,usize> = HashMap::new();
+    let mut starts: Vec<usize> = Vec::new();
+
+    for i in 0..lines.len() {
+
+        if !is_code(&lines[i]) {
+            continue;
+        }
+
+        let leader = !is_code(&lines[i-1]) || ends_block(&lines[i-1]) || lines[i].label.is_some();
+
+        if leader {
+            starts.push(i);
+        }
+
+        block_of.insert(i, starts.len() - 1);
+    }
+
+    let name_of = |b: usize| {
+        let l = &lines[starts[b]];
+        l.label.as_ref().map_or(format!("{}:{}", p.name, l.number() + 1), |label| label.node.to_string())
+    };
+
+    // 呼び出し先の id
+    let entry = match lines[0].statement {
+        Statement::Start(Some(ref e)) => labels.get(e.node.as_str()).cloned(),
+        _ => Some(1),
+    };
+
+    let callee = |name: &str| match labels.get(name) {
+        Some(i) if Some(*i) != entry => format!("{}.{}", p.name, name),
+        Some(_) => p.name.to_string(),
+        None => name.to_string(),
+    };
+
+    let mut blocks: Vec<Block> = Vec::new();
+
+    for (b, &start) in starts.iter().enumerate() {
+
+        let mut block = Block{name: name_of(b), line: lines[start].number(), code: Vec::new(), lines: Vec::new(), succs: Vec::new(), calls: Vec::new()};
+
+        let mut i = start;
+
+        loop {
+
+            let l = &lines[i];
+            let text = texts.get(l.number()).cloned().unwrap_or("");
+
+            block.code.push(match l.statement.operands().first() {
+                Some(o) => format!("{} {}", l.opcode.node, &text[o.span.start..l.span.end]),
+                None => l.opcode.node.to_string(),
+            });
+
+            block.lines.push(l.clone());
+
+            if block_of.get(&(i + 1)) != Some(&b) {
+                break;
+            }
+
+            i += 1;
+        }
+
+        let last = &lines[i];
+        let op = mnemonic(last);
+
+        if op == "CALL" {
+            block.calls.extend(target(last).map(&callee));
+        } else if ends_block(last) {
+            if let Some(&t) = target(last).and_then(|t| labels.get(t)).and_then(|t| block_of.get(t)) {
+                block.succs.push((name_of(t), op));
+            }
+        }
+
+        if op != "JUMP" && op != "RET" {
+            if let Some(&next) = block_of.get(&(i + 1)) {
+                block.succs.push((name_of(next), "fall"));
+            }
+        }
+
+        blocks.push(block);
+    }
+
+    // 入口から分岐でたどれるブロックをサブルーチンにする
+    let mut entries: Vec<(usize,String)> = Vec::new();
+
+    if let Some(&b) = entry.and_then(|i| block_of.get(&i)) {
+        entries.push((b, p.name.to_string()));
+    }
+
+    for l in lines {
+        if mnemonic(l) == "CALL" {
+            if let Some(t) = target(l) {
+                if let Some(&b) = labels.get(t).and_then(|i| block_of.get(i)) {
+                    if !entries.iter().any(|(e, _)| *e == b) {
+                        entries.push((b, callee(t)));
+                    }
+                }
+            }
+        }
+    }
+
+    let index: HashMap<String,usize> = blocks.iter().enumerate().map(|(i, b)| (b.name.to_string(), i)).collect();
+
+    let mut graph = Graph::default();
+
+    for (b, id) in entries {
+
+        let mut seen: Vec<usize> = Vec::new();
+        let mut work = vec![b];
+
+        while let Some(b) = work.pop() {
+            if !seen.contains(&b) {
+                seen.push(b);
+                work.extend(blocks[b].succs.iter().rev().map(|(name, _)| index[name]));
+            }
+        }
+
+        for callee in seen.iter().flat_map(|b| blocks[*b].calls.iter()) {
+            if !graph.calls.contains(&(id.to_string(), callee.to_string())) {
+                graph.calls.push((id.to_string(), callee.to_string()));
+            }
+        }
+
+        let name = id.rsplit('.').next().unwrap_or("").to_string();
+
+        graph.subroutines.push(Subroutine{
+            id,
+            name,
+            program: p.name.to_string(),
+            blocks: seen.into_iter().map(|b| blocks[b].clone()).collect(),
+        });
+    }
+
+    graph
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Graph {
+
+    // 呼び出している標準サブルーチン (それが呼ぶものも) を後ろに加えたもの
+    pub fn with_stdlib(&self) -> Graph {
+
+        let mut graph = self.clone();
+
+        loop {
+
+            let missing = ROUTINES
+                .iter()
+                .filter(|(name, _, _)| graph.calls.iter().any(|(_, to)| to == name) && !graph.subroutines.iter().any(|s| s.id == *name))
+                .collect::<Vec<_>>();
+
+            if missing.is_empty() {
+                return graph;
+            }
+
+            for (_, _, src) in missing {
+                if let Ok(g) = build(src) {
+                    graph.subroutines.extend(g.subroutines);
+                    graph.calls.extend(g.calls);
+                }
+            }
+        }
+    }
+
+    // サブルーチンごとの制御フローグラフ
+    pub fn cfg(&self, format: GraphFormat) -> String {
+
+        if format == GraphFormat::Json {
+            let subroutines = self.subroutines.iter().map(|s| Json::object(vec![
+                ("id", Json::string(&s.id)),
+                ("name", Json::string(&s.name)),
+                ("program", Json::string(&s.program)),
+                ("blocks", Json::Array(s.blocks.iter().map(|b| Json::object(vec![
+                    ("name", Json::string(&b.name)),
+                    ("line", Json::from(b.line + 1)),
+                    ("code", Json::Array(b.code.iter().map(|c| Json::string(c)).collect())),
+                    ("successors", Json::Array(b.succs.iter().map(|(to, kind)| Json::object(vec![
+                        ("block", Json::string(to)),
+                        ("kind", Json::string(kind)),
+                    ])).collect())),
+                    ("calls", Json::Array(b.calls.iter().map(|c| Json::string(c)).collect())),
+                ])).collect())),
+            ])).collect();
+            return format!("{}\n", Json::object(vec![("subroutines", Json::Array(subroutines))]));
+        }
+
+        let mut s = "digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n".to_string();
+
+        for sub in &self.subroutines {
+
+            s.push_str(&format!("    subgraph {} {{\n        label={};\n", quote(&format!("cluster_{}", sub.id)), quote(&sub.id)));
+
+            let node = |name: &str| quote(&format!("{}/{}", sub.id, name));
+
+            for b in &sub.blocks {
+                let text = ::std::iter::once(b.name.to_string() + ":").chain(b.code.iter().map(|c| format!("    {}", c))).collect::<Vec<String>>();
+                let label = text.iter().map(|l| l.replace('\\', "\\\\").replace('"', "\\\"") + "\\l").collect::<String>();
+                s.push_str(&format!("        {} [label=\"{}\"];\n", node(&b.name), label));
+            }
+
+            for b in &sub.blocks {
+                for (to, kind) in &b.succs {
+                    match *kind {
+                        "fall" => s.push_str(&format!("        {} -> {};\n", node(&b.name), node(to))),
+                        _ => s.push_str(&format!("        {} -> {} [label={}];\n", node(&b.name), node(to), quote(kind))),
+                    }
+                }
+            }
+
+            s.push_str("    }\n");
+        }
+
+        s.push_str("}\n");
+        s
+    }
+
+    // サブルーチンの呼び出し関係 (定義のないものは破線にする)
+    pub fn call_graph(&self, format: GraphFormat) -> String {
+
+        let mut nodes: Vec<(String,String,Option<String>)> = self.subroutines
+            .iter()
+            .map(|s| (s.id.to_string(), s.name.to_string(), Some(s.program.to_string())))
+            .collect();
+
+        for (_, to) in &self.calls {
+            if !nodes.iter().any(|(id, _, _)| id == to) {
+                nodes.push((to.to_string(), to.to_string(), None));
+            }
+        }
+
+        if format == GraphFormat::Json {
+            let nodes = nodes.iter().map(|(id, name, program)| Json::object(vec![
+                ("id", Json::string(id)),
+                ("name", Json::string(name)),
+                ("program", program.as_ref().map_or(Json::Null, |p| Json::string(p))),
+            ])).collect();
+            let edges = self.calls.iter().map(|(from, to)| Json::object(vec![
+                ("from", Json::string(from)),
+                ("to", Json::string(to)),
+            ])).collect();
+            return format!("{}\n", Json::object(vec![("nodes", Json::Array(nodes)), ("edges", Json::Array(edges))]));
+        }
+
+        let mut s = "digraph callgraph {\n    node [shape=box];\n".to_string();
+
+        for (id, name, program) in &nodes {
+            match *program {
+                Some(_) => s.push_str(&format!("    {} [label={}];\n", quote(id), quote(name))),
+                None => s.push_str(&format!("    {} [label={}, style=dashed];\n", quote(id), quote(name))),
+            }
+        }
+
+        for (from, to) in &self.calls {
+            s.push_str(&format!("    {} -> {};\n", quote(from), quote(to)));
+        }
+
+        s.push_str("}\n");
+        s
+    }
+}
+
+#[test]
+fn test_cfg() {
+
+    let src = "\
+MAIN     START
+         LAD     GR1,3
+LOOP     CALL    TWICE
+         SUBA    GR1,=1
+         JNZ     LOOP
+         CALL    MULU
+         RET
+TWICE    ADDA    GR2,GR2
+         CPA     GR2,=\"100\"
+         JPL     BIG
+         RET
+BIG      LAD     GR2,0
+         RET
+         END
+";
+
+    assert!(build(src).is_err());
+
+    let src = src.replace("=\"100\"", "=100");
+    let graph = build(&src).unwrap();
+
+    let names = |s: &Subroutine| s.blocks.iter().map(|b| b.name.to_string()).collect::<Vec<String>>();
+
+    assert_eq!(graph.subroutines.len(), 2);
+    assert_eq!(graph.subroutines[0].id, "MAIN");
+    assert_eq!(names(&graph.subroutines[0]), vec!["MAIN:2", "LOOP", "MAIN:4", "MAIN:6", "MAIN:7"]);
+    assert_eq!(graph.subroutines[0].blocks[2].succs, vec![("LOOP".to_string(), "JNZ"), ("MAIN:6".to_string(), "fall")]);
+    assert_eq!(graph.subroutines[0].blocks[2].code, vec!["SUBA GR1,=1", "JNZ LOOP"]);
+
+    assert_eq!(graph.subroutines[1].id, "MAIN.TWICE");
+    assert_eq!(graph.subroutines[1].name, "TWICE");
+    assert_eq!(names(&graph.subroutines[1]), vec!["TWICE", "BIG", "MAIN:11"]);
+
+    assert_eq!(graph.calls, vec![
+        ("MAIN".to_string(), "MAIN.TWICE".to_string()),
+        ("MAIN".to_string(), "MULU".to_string()),
+    ]);
+
+    let dot = graph.cfg(GraphFormat::Dot);
+    assert!(dot.contains("    subgraph \"cluster_MAIN.TWICE\" {\n"));
+    assert!(dot.contains("        \"MAIN.TWICE/TWICE\" -> \"MAIN.TWICE/BIG\" [label=\"JPL\"];\n"));
+    assert!(dot.contains("        \"MAIN/LOOP\" [label=\"LOOP:\\l    CALL TWICE\\l\"];\n"));
+
+    let dot = graph.call_graph(GraphFormat::Dot);
+    assert!(dot.contains("    \"MAIN.TWICE\" [label=\"TWICE\"];\n    \"MULU\" [label=\"MULU\", style=dashed];\n"));
+    assert!(dot.contains("    \"MAIN\" -> \"MAIN.TWICE\";\n"));
+
+    let json = Json::parse(&graph.call_graph(GraphFormat::Json)).unwrap();
+    assert_eq!(json.get("edges").and_then(|e| e.as_array()).map(|e| e.len()), Some(2));
+    assert_eq!(json.get("nodes").and_then(|n| n.as_array()).unwrap()[2].get("program"), Some(&Json::Null));
+
+    let json = Json::parse(&graph.cfg(GraphFormat::Json)).unwrap();
+    let blocks = json.get("subroutines").and_then(|s| s.as_array()).unwrap()[0].get("blocks").cloned().unwrap();
+    assert_eq!(blocks.as_array().unwrap()[1].get("line"), Some(&Json::from(3usize)));
+}
@@ -1,29 +1,77 @@
 extern crate getopts;
-use self::getopts::Options;
+use self::getopts::{Options,Matches};
+use std::collections::HashMap;
 use std::path::Path;
 use std::fs::File;
+use std::io::{self,BufWriter,Write};
+use std::process::exit;
+
+use archive::Archive;
+use ast::Error;
+use assembler::assemble;
+use cfg::{self,GraphFormat};
+use clobber;
+use comet2::{Comet2,Hook,MEMORY_SIZE};
+use console::StdConsole;
+use dap;
+use debugger::Debugger;
+use diagnostic::{self,Diagnostic,MessageFormat};
+use debuginfo::DebugInfo;
+use disasm::{disassemble,disassemble_object};
+use gdb;
+use linker::{link,link_symbols,resolve_members};
+use lint;
+use lsp;
+use object::Object;
+use opcode;
+use profile::{CostModel,Profiler};
+use stack;
+use stdlib;
+use watch::{Kind,Stopper,Watchpoint};
+use table::{TableFormat,TraceTable};
+use token::SymbolTable;
+use trace::{Filter,TraceFormat,Tracer};
 
 pub fn init_opts(opts: &mut Options) {
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("d", "dry-run", "only print machine code");
+    opts.optflag("c", "compile", "only assemble into object files (*.o)");
+    opts.optmulti("l", "library", "link with members of the archive", "FILE");
+    opts.optflag("", "stdlib", "link with the bundled subroutine library");
+    opts.optflag("g", "debug-info", "emit source line tables (in *.o, or as FILE.dbg next to the output)");
+    opts.optopt("", "trace", "run: write one record per executed instruction to FILE (- for stdout)", "FILE");
+    opts.optopt("", "trace-format", "run: trace format, jsonl (default) or csv", "FORMAT");
+    opts.optmulti("", "trace-only", "run: trace only FROM-TO, a program, or a single LOC", "RANGE");
+    opts.optmulti("", "table", "run: print a table of registers, flags and labels (e.g. GR1:d,GR2,ZF,SUM)", "COLUMNS");
+    opts.optmulti("", "table-at", "run: add a row each time execution reaches LOC", "LOC");
+    opts.optopt("", "table-format", "run: table format, markdown (default) or csv", "FORMAT");
+    opts.optmulti("", "stop-if", "run: stop when EXPR becomes true (e.g. 'GR1 == 0 && ZF')", "EXPR");
+    opts.optmulti("", "watch", "run: stop when LOC is written (LOC [if EXPR])", "LOC");
+    opts.optopt("", "max-steps", "run: stop with an error after N instructions (default 10000000)", "N");
+    opts.optopt("", "history", "debug: number of steps to keep for reverse execution (default 100000)", "N");
+    opts.optopt("", "port", "gdb: TCP port to listen on at 127.0.0.1 (default 1234)", "PORT");
+    opts.optflag("", "profile", "run: print instruction counts per label and a call graph");
+    opts.optopt("", "graph-format", "cfg, callgraph: output format, dot (default) or json", "FORMAT");
+    opts.optopt("", "message-format", "errors and warnings of lint, stack and regs as human (default), json (one object per line) or sarif", "FORMAT");
+    opts.optopt("", "profile-cost", "run: estimate cycles with a cost model (lines of `MNEMONIC CYCLES`)", "FILE");
 }
 
 pub fn read_source_code(buf: &mut String, path: &str) {
-    
+
     use std::io::prelude::*;
-    
+
     let path = Path::new(path);
-    let mut file = File::open(&path).unwrap();
+    let mut file = File::open(path).unwrap();
     file.read_to_string(buf).unwrap();
 }
 
 pub fn write_machine_code(vec: &Vec<u16>, path: &str) {
-    
-    
+
+
     use std::io::{BufWriter, Write};
-    
+
     println!("[*] Create object file `{}`", path);
-    let fs = File::create(&path).unwrap();
+    let fs = File::create(path).unwrap();
     let mut f = BufWriter::new(fs);
 
     for v in vec {
@@ -38,3 +86,891 @@ pub fn print_machine_code(vec: &Vec<u16>) {
         println!("{:0>4x}", v);
     }
 }
+
+pub fn read_machine_code(path: &str) -> Vec<u16> {
+
+    let mut buf = String::new();
+    read_source_code(&mut buf, path);
+
+    let mut vec: Vec<u16> = Vec::new();
+
+    for (i, l) in buf.lines().enumerate() {
+        match u16::from_str_radix(l.trim(), 16) {
+            Ok(v) => vec.push(v),
+            Err(_) => {
+                println!("{}:{}: Invalid machine code: `{}`", path, i + 1, l);
+                exit(1);
+            }
+        }
+    }
+
+    vec
+}
+
+fn write_text(text: &str, path: &str) {
+
+    use std::io::Write;
+
+    let mut f = File::create(path).unwrap();
+    if let Err(why) = f.write_all(text.as_bytes()) {
+        panic!("{}", why);
+    }
+}
+
+pub fn read_object(path: &str) -> Object {
+
+    let mut buf = String::new();
+    read_source_code(&mut buf, path);
+
+    match Object::parse(&buf) {
+        Ok(obj) => obj,
+        Err(e) => {
+            println!("{}: {}", path, e);
+            exit(1);
+        }
+    }
+}
+
+pub fn write_object(obj: &Object, path: &str) {
+    println!("[*] Create object file `{}`", path);
+    write_text(&obj.to_string(), path);
+}
+
+// アセンブルできないソースのパス，ソース，エラー
+pub type Failure = (String, String, Vec<Error>);
+
+// ソースをアセンブルする．エラーならソースと一緒に返す
+fn assemble_source(path: &str) -> Result<Vec<Object>, Failure> {
+
+    let mut buf = String::new();
+    read_source_code(&mut buf, path);
+
+    match assemble(&buf) {
+        Ok(mut objects) => {
+            for obj in &mut objects {
+                obj.file = path.to_string();
+            }
+            Ok(objects)
+        },
+        Err(errors) => Err((path.to_string(), buf, errors)),
+    }
+}
+
+// ソースを全てアセンブルし，エラーがあれば全てのファイルの分を表示して終了する
+pub fn assemble_files(paths: &[String], format: MessageFormat) -> Vec<Vec<Object>> {
+
+    let (assembled, failures): (Vec<_>, Vec<_>) = paths.iter().map(|path| assemble_source(path)).partition(|r| r.is_ok());
+
+    if !failures.is_empty() {
+        exit_with_errors(&failures.into_iter().filter_map(Result::err).collect::<Vec<Failure>>(), format);
+    }
+
+    assembled.into_iter().filter_map(Result::ok).collect()
+}
+
+// 全てのファイルのエラーを表示して終了する．SARIF は1つのログにまとめる
+pub fn exit_with_errors(failures: &[Failure], format: MessageFormat) -> ! {
+
+    if format == MessageFormat::Sarif {
+        let diagnostics = failures
+            .iter()
+            .flat_map(|(path, src, errors)| errors.iter().map(move |e| (path.to_string(), Diagnostic::error(src, e))))
+            .collect::<Vec<(String,Diagnostic)>>();
+        println!("{}", diagnostic::sarif(&diagnostics));
+    } else {
+        for (path, src, errors) in failures {
+            print_errors(path, src, errors, format);
+        }
+    }
+
+    exit(1);
+}
+
+// エラーごとにソースの行を示し，命令の書き方を添えて表示する
+pub fn print_errors(path: &str, src: &str, errors: &[Error], format: MessageFormat) {
+
+    let color = diagnostic::use_color();
+    let diagnostics = errors.iter().map(|e| Diagnostic::error(src, e));
+
+    match format {
+        MessageFormat::Human => diagnostics.for_each(|d| println!("{}", d.render(path, src, color))),
+        MessageFormat::Json => diagnostics.for_each(|d| println!("{}", d.to_json(path))),
+        MessageFormat::Sarif => println!("{}", diagnostic::sarif(&diagnostics.map(|d| (path.to_string(), d)).collect::<Vec<_>>())),
+    }
+}
+
+// --message-format
+pub fn message_format(matches: &Matches) -> MessageFormat {
+    match matches.opt_str("message-format") {
+        None => MessageFormat::Human,
+        Some(s) => or_exit(MessageFormat::parse(&s).ok_or(format!("Unknown message format: `{}` (human, json or sarif)", s))),
+    }
+}
+
+// *.o はそのまま読み込み，それ以外はソースとしてアセンブルする
+pub fn load_objects(paths: &[String], format: MessageFormat) -> Vec<Object> {
+
+    let mut objects: Vec<Object> = Vec::new();
+    let mut failures: Vec<Failure> = Vec::new();
+
+    for path in paths {
+        if path.ends_with(".o") {
+            objects.push(read_object(path));
+            continue;
+        }
+        match assemble_source(path) {
+            Ok(mut o) => objects.append(&mut o),
+            Err(f) => failures.push(f),
+        }
+    }
+
+    if !failures.is_empty() {
+        exit_with_errors(&failures, format);
+    }
+
+    objects
+}
+
+pub fn load_archives(matches: &Matches) -> Vec<Archive> {
+
+    let mut archives: Vec<Archive> = matches
+        .opt_strs("l")
+        .iter()
+        .map(|path| read_archive(path))
+        .collect();
+
+    if matches.opt_present("stdlib") {
+        archives.push(stdlib::archive());
+    }
+
+    archives
+}
+
+// 必要なメンバを取り込んでリンクし，機械語とラベル表を返す
+pub fn link_objects(objects: &mut Vec<Object>, archives: &[Archive]) -> (Vec<u16>, SymbolTable) {
+
+    resolve_members(objects, archives);
+
+    match link(objects) {
+        Ok(memory) => (memory, link_symbols(objects)),
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+pub fn run_assemble(matches: &Matches) {
+
+    // 1つのソースに複数のプログラムがあれば，プログラムごとに「ソース名.プログラム名.o」にする
+    if matches.opt_present("c") {
+        let paths = matches.free.iter().filter(|p| !p.ends_with(".o")).cloned().collect::<Vec<String>>();
+        for (path, mut objects) in paths.iter().zip(assemble_files(&paths, message_format(matches))) {
+            let stem = path.replace(".casl2", "");
+            let count = objects.len();
+            for obj in &mut objects {
+                if !matches.opt_present("g") {
+                    obj.strip();
+                }
+                if count == 1 {
+                    write_object(obj, &(stem.to_string() + ".o"));
+                } else {
+                    write_object(obj, &format!("{}.{}.o", stem, obj.name));
+                }
+            }
+        }
+        return;
+    }
+
+    let mut objects = load_objects(&matches.free, message_format(matches));
+
+    let (mut code, _) = link_objects(&mut objects, &load_archives(matches));
+
+    // 先頭の1語はリンクした機械語の語数 (65536語なら0になる．読み込むときはファイルの語数を使う)
+    let mut memory: Vec<u16> = vec![code.len() as u16];
+    memory.append(&mut code);
+
+    if matches.opt_present("d") {
+        print_machine_code(&memory);
+    } else {
+        let out_path: &str = &or_exit(output_path(&matches.free));
+        write_machine_code(&memory, out_path);
+        if matches.opt_present("g") {
+            let path = or_exit(refuse_input(format!("{}.dbg", out_path), &matches.free));
+            println!("[*] Create debug information `{}`", path);
+            write_text(&DebugInfo::link(&objects).to_string(), &path);
+        }
+    }
+}
+
+// リンクした機械語の書き出し先．最初の入力から「.casl2」か「.o」を除いたもの
+pub fn output_path(inputs: &[String]) -> Result<String, String> {
+    let first = &inputs[0];
+    let stem = first.strip_suffix(".casl2").or_else(|| first.strip_suffix(".o")).unwrap_or(first);
+    refuse_input(stem.to_string(), inputs)
+}
+
+// 入力のファイルは上書きしない
+fn refuse_input(path: String, inputs: &[String]) -> Result<String, String> {
+    if inputs.contains(&path) {
+        return Err(format!("Output `{}` would overwrite an input file", path));
+    }
+    Ok(path)
+}
+
+// オブジェクト (*.o) かソースならリンクしてデバッグ情報と入口を求め，
+// それ以外はアセンブル結果のファイル (先頭の1語は飛ばす) とみなして0番地から始める．
+// 実行ファイルの横に「.dbg」があれば読み込む
+pub fn load_program(paths: &[String], matches: &Matches) -> (Vec<u16>, DebugInfo, u16) {
+
+    if paths[0].ends_with(".o") || paths[0].ends_with(".casl2") {
+        let mut objects = load_objects(paths, message_format(matches));
+        let (code, _) = link_objects(&mut objects, &load_archives(matches));
+        let entry = objects[0].defs.first().map_or(0, |(_, addr)| *addr);
+        (code, DebugInfo::link(&objects), entry)
+    } else {
+        let code = read_machine_code(&paths[0]);
+        let dbg = format!("{}.dbg", paths[0]);
+        let info = if Path::new(&dbg).exists() {
+            read_debug_info(&dbg)
+        } else {
+            DebugInfo::default()
+        };
+        if code.len() > MEMORY_SIZE + 1 {
+            println!("{}: program exceeds 65536 words", paths[0]);
+            exit(1);
+        }
+        (code.into_iter().skip(1).collect(), info, 0)
+    }
+}
+
+pub fn read_debug_info(path: &str) -> DebugInfo {
+
+    let mut buf = String::new();
+    read_source_code(&mut buf, path);
+
+    match DebugInfo::parse(&buf) {
+        Ok(info) => info,
+        Err(e) => {
+            println!("{}: {}", path, e);
+            exit(1);
+        }
+    }
+}
+
+// disasm FILE : 機械語をCASL2のソースに戻す
+pub fn run_disasm(matches: &Matches) {
+
+    let paths = &matches.free[1..];
+
+    if paths.is_empty() {
+        println!("Usage: disasm FILE...");
+        exit(1);
+    }
+
+    // オブジェクトだけならリンクせずに1つずつ戻す (外部ラベルが未解決でもよい)
+    if paths.iter().all(|path| path.ends_with(".o")) {
+        for path in paths {
+            print!("{}", disassemble_object(&read_object(path)));
+        }
+        return;
+    }
+
+    let (code, info, _) = load_program(paths, matches);
+
+    print!("{}", disassemble(&code, &info.labels));
+}
+
+// エラーなら表示して終了する
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+// --trace FILE : 実行した命令を1つずつ書き出すもの
+fn open_tracer(path: &str, matches: &Matches, info: &DebugInfo) -> Tracer<BufWriter<Box<dyn Write>>> {
+
+    let format = match matches.opt_str("trace-format") {
+        None => TraceFormat::Jsonl,
+        Some(s) => or_exit(TraceFormat::parse(&s).ok_or(format!("Unknown trace format: `{}` (jsonl or csv)", s))),
+    };
+
+    let filter = or_exit(Filter::parse(&matches.opt_strs("trace-only"), info));
+
+    let out: Box<dyn Write> = if path == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(or_exit(File::create(path).map_err(|e| format!("{}: {}", path, e))))
+    };
+
+    Tracer::new(BufWriter::new(out), format, filter, info)
+}
+
+// --table COLUMNS --table-at LOC : 決めた番地に来るたびに値を記録する表
+fn open_table(matches: &Matches, info: &DebugInfo) -> TraceTable {
+
+    let columns = matches
+        .opt_strs("table")
+        .iter()
+        .flat_map(|s| s.split(',').map(|c| c.trim().to_string()).collect::<Vec<String>>())
+        .collect::<Vec<String>>();
+
+    let points = matches.opt_strs("table-at");
+
+    if points.is_empty() {
+        println!("--table needs --table-at LOC");
+        exit(1);
+    }
+
+    or_exit(TraceTable::new(&columns, &points, info))
+}
+
+// --max-steps を指定しないときに実行する命令数の上限 (止まらないプログラムを打ち切る)
+const MAX_STEPS: u64 = 10_000_000;
+
+// run FILE : アセンブルしてCOMET2で実行し，終了時のレジスタを表示する
+//
+// --trace があれば実行した命令を1つずつ書き出し，--table や --profile があれば終了後に表示する．
+// --stop-if の式が真になるか --watch の番地に書き込むか，--max-steps の命令数を超えると，そこで止めてエラーにする
+pub fn run_program(matches: &Matches) {
+
+    let paths = &matches.free[1..];
+
+    if paths.is_empty() {
+        println!("Usage: run FILE...");
+        exit(1);
+    }
+
+    let limit = match matches.opt_str("max-steps") {
+        None => MAX_STEPS,
+        Some(n) => or_exit(n.parse::<u64>().map_err(|_| format!("Invalid step count: `{}`", n))),
+    };
+
+    let (code, info, entry) = load_program(paths, matches);
+
+    let mut cpu = Comet2::load(&code, entry);
+
+    let mut tracer = matches.opt_str("trace").map(|path| open_tracer(&path, matches, &info));
+    let mut table = if matches.opt_present("table") { Some(open_table(matches, &info)) } else { None };
+
+    let mut profiler = if matches.opt_present("profile") || matches.opt_present("profile-cost") {
+        let model = matches.opt_str("profile-cost").map(|path| {
+            let mut buf = String::new();
+            read_source_code(&mut buf, &path);
+            or_exit(CostModel::parse(&buf).map_err(|e| format!("{}:{}", path, e)))
+        });
+        Some(Profiler::new(model))
+    } else {
+        None
+    };
+
+    let mut stopper = {
+        let mut points = Vec::new();
+        for e in matches.opt_strs("stop-if") {
+            points.push(or_exit(Watchpoint::parse(Kind::When, &e, &info, &cpu)));
+        }
+        for loc in matches.opt_strs("watch") {
+            points.push(or_exit(Watchpoint::parse(Kind::Write, &loc, &info, &cpu)));
+        }
+        Stopper{points}
+    };
+
+    let table_format = match matches.opt_str("table-format") {
+        None => TableFormat::Markdown,
+        Some(s) => or_exit(TableFormat::parse(&s).ok_or(format!("Unknown table format: `{}` (markdown or csv)", s))),
+    };
+
+    let result = {
+
+        let mut hooks: Vec<&mut dyn Hook<StdConsole>> = Vec::new();
+
+        if let Some(ref mut t) = tracer {
+            hooks.push(t);
+        }
+        if let Some(ref mut t) = table {
+            hooks.push(t);
+        }
+        if let Some(ref mut p) = profiler {
+            hooks.push(p);
+        }
+        if !stopper.points.is_empty() {
+            hooks.push(&mut stopper);
+        }
+
+        cpu.run_with(limit, &mut hooks)
+    };
+
+    if let Some(ref mut t) = tracer {
+        or_exit(t.finish());
+    }
+
+    if let Some(ref t) = table {
+        match table_format {
+            TableFormat::Markdown => print!("{}", t.to_markdown()),
+            TableFormat::Csv => print!("{}", t.to_csv()),
+        }
+    }
+
+    if let Some(ref p) = profiler {
+        print!("{}", p.report(&info.labels));
+    }
+
+    if let Err(e) = result {
+        println!("{}", e);
+        eprintln!("{}", cpu);
+        exit(1);
+    }
+
+    eprintln!("[*] Halted after {} steps", cpu.steps);
+    eprintln!("{}", cpu);
+}
+
+pub fn read_archive(path: &str) -> Archive {
+
+    let mut buf = String::new();
+    read_source_code(&mut buf, path);
+
+    match Archive::parse(&buf) {
+        Ok(archive) => archive,
+        Err(e) => {
+            println!("{}: {}", path, e);
+            exit(1);
+        }
+    }
+}
+
+// debug FILE : 対話的なデバッガで実行する
+pub fn run_debug(matches: &Matches) {
+
+    use std::io::{self,BufRead,Write};
+
+    let paths = &matches.free[1..];
+
+    if paths.is_empty() {
+        println!("Usage: debug FILE...");
+        exit(1);
+    }
+
+    let (code, info, entry) = load_program(paths, matches);
+
+    let mut dbg = Debugger::new(Comet2::load(&code, entry), info);
+
+    if let Some(n) = matches.opt_str("history") {
+        dbg.history.set_limit(or_exit(n.parse::<usize>().map_err(|_| format!("Invalid history limit: `{}`", n))));
+    }
+
+    println!("{}", dbg.current());
+
+    let stdin = io::stdin();
+
+    loop {
+
+        print!("(casl2) ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {},
+        }
+
+        let out = dbg.execute(&line);
+        if !out.is_empty() {
+            println!("{}", out);
+        }
+
+        if dbg.quit {
+            break;
+        }
+    }
+}
+
+// 標準入出力で Debug Adapter Protocol を話す．プログラムは launch で指定する
+pub fn run_dap() {
+    if let Err(e) = dap::serve() {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+// 標準入出力で Language Server Protocol を話す
+pub fn run_lsp() {
+    if let Err(e) = lsp::serve() {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+// GDBから target remote :PORT で接続できるようにする
+pub fn run_gdb(matches: &Matches) {
+
+    use std::net::TcpListener;
+
+    let paths = &matches.free[1..];
+
+    if paths.is_empty() {
+        println!("Usage: gdb FILE... [--port PORT]");
+        exit(1);
+    }
+
+    let (code, _, entry) = load_program(paths, matches);
+
+    let port = match matches.opt_str("port") {
+        Some(p) => or_exit(p.parse::<u16>().map_err(|_| format!("Invalid port: `{}`", p))),
+        None => 1234,
+    };
+
+    let listener = or_exit(TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("Cannot listen on port {}: {}", port, e)));
+
+    println!("[*] Listening on 127.0.0.1:{}", port);
+
+    let (mut stream, addr) = or_exit(listener.accept().map_err(|e| e.to_string()));
+
+    println!("[*] Connected from {}", addr);
+
+    let mut stub = gdb::Stub::new(Comet2::load(&code, entry));
+
+    if let Err(e) = gdb::serve(&mut stub, &mut stream) {
+        println!("{}", e);
+        exit(1);
+    }
+}
+
+// ar LIB          : メンバと定義しているラベルを一覧表示する
+// ar LIB OBJ...   : オブジェクトをアーカイブに追加する (同名のメンバは置き換える)
+pub fn run_ar(args: &[String]) {
+
+    if args.is_empty() {
+        println!("Usage: ar LIB [OBJ...]");
+        exit(1);
+    }
+
+    let path = &args[0];
+
+    if args.len() == 1 {
+        for m in &read_archive(path).members {
+            let defs = m.defs.iter().map(|(name, _)| name.as_str()).collect::<Vec<&str>>();
+            println!("{}\t{}", m.name, defs.join(" "));
+        }
+        return;
+    }
+
+    let mut archive = if Path::new(path).exists() {
+        read_archive(path)
+    } else {
+        Archive::new()
+    };
+
+    for obj in &args[1..] {
+        archive.insert(read_object(obj));
+    }
+
+    println!("[*] Create archive `{}`", path);
+    write_text(&archive.to_string(), path);
+}
+
+// lint FILE... : エラーにはならないが間違いらしいところを警告する
+//
+// 終了コードはアセンブルできないソースがあれば2，警告だけなら1
+pub fn run_lint(matches: &Matches) {
+
+    let args = &matches.free[1..];
+    let format = message_format(matches);
+
+    if args.is_empty() {
+        println!("Usage: lint FILE...");
+        println!();
+        for (rule, description, _) in lint::RULES.iter() {
+            println!("{:<18}{}", rule, description);
+        }
+        exit(1);
+    }
+
+    let mut warned = false;
+    let mut failed = false;
+    // SARIF は最後にまとめて書き出す
+    let mut diagnostics: Vec<(String,Diagnostic)> = Vec::new();
+
+    for path in args {
+
+        let mut buf = String::new();
+        read_source_code(&mut buf, path);
+
+        match lint::lint(&buf) {
+            Ok(warnings) => {
+                for w in &warnings {
+                    match format {
+                        MessageFormat::Human => println!("{}:{}", path, w),
+                        MessageFormat::Json => println!("{}", Diagnostic::warning(w).to_json(path)),
+                        MessageFormat::Sarif => diagnostics.push((path.to_string(), Diagnostic::warning(w))),
+                    }
+                }
+                warned |= !warnings.is_empty();
+            },
+            Err(errors) if format == MessageFormat::Sarif => {
+                diagnostics.extend(errors.iter().map(|e| (path.to_string(), Diagnostic::error(&buf, e))));
+                failed = true;
+            },
+            Err(errors) => {
+                print_errors(path, &buf, &errors, format);
+                failed = true;
+            },
+        }
+    }
+
+    if format == MessageFormat::Sarif {
+        println!("{}", diagnostic::sarif(&diagnostics));
+    }
+
+    if failed {
+        exit(2);
+    }
+
+    if warned {
+        exit(1);
+    }
+}
+
+// ソースをまとめたグラフと，サブルーチンがどのファイルにあるか
+fn read_graph(paths: &[String], format: MessageFormat) -> (cfg::Graph, HashMap<String,String>) {
+
+    let mut graph = cfg::Graph::default();
+    let mut files: HashMap<String,String> = HashMap::new();
+    let mut failures: Vec<Failure> = Vec::new();
+
+    for path in paths {
+
+        let mut buf = String::new();
+        read_source_code(&mut buf, path);
+
+        match cfg::build(&buf) {
+            Ok(g) => {
+                for sub in &g.subroutines {
+                    files.insert(sub.id.to_string(), path.to_string());
+                }
+                graph.subroutines.extend(g.subroutines);
+                graph.calls.extend(g.calls);
+            },
+            Err(errors) => failures.push((path.to_string(), buf, errors)),
+        }
+    }
+
+    if !failures.is_empty() {
+        exit_with_errors(&failures, format);
+    }
+
+    (graph, files)
+}
+
+// cfg FILE...       : サブルーチンごとの制御フローグラフを書き出す
+// callgraph FILE... : サブルーチンの呼び出しグラフを書き出す
+pub fn run_graph(matches: &Matches) {
+
+    let command = &matches.free[0];
+    let paths = &matches.free[1..];
+
+    if paths.is_empty() {
+        println!("Usage: {} FILE... [--graph-format dot|json]", command);
+        exit(1);
+    }
+
+    let format = match matches.opt_str("graph-format") {
+        None => GraphFormat::Dot,
+        Some(s) => or_exit(GraphFormat::parse(&s).ok_or(format!("Unknown graph format: `{}` (dot or json)", s))),
+    };
+
+    let (graph, _) = read_graph(paths, message_format(matches));
+
+    if command == "cfg" {
+        print!("{}", graph.cfg(format));
+    } else {
+        print!("{}", graph.call_graph(format));
+    }
+}
+
+// stack や regs の警告を JSON か SARIF で書き出す．警告があれば終了コードは1
+fn print_warnings(diagnostics: &[(String,Diagnostic)], format: MessageFormat) {
+
+    if format == MessageFormat::Sarif {
+        println!("{}", diagnostic::sarif(diagnostics));
+    } else {
+        for (path, d) in diagnostics {
+            println!("{}", d.to_json(path));
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        exit(1);
+    }
+}
+
+// stack FILE... : サブルーチンごとのスタックの使用量と，PUSH と POP の釣り合わないところ
+//
+// 釣り合わないところがあれば終了コードは1
+pub fn run_stack(matches: &Matches) {
+
+    let args = &matches.free[1..];
+
+    if args.is_empty() {
+        println!("Usage: stack FILE...");
+        exit(1);
+    }
+
+    let format = message_format(matches);
+    let (graph, files) = read_graph(args, format);
+    let report = stack::analyze(&graph);
+
+    // JSON や SARIF では釣り合わないところだけを書き出す
+    if format != MessageFormat::Human {
+        let diagnostics = report.problems.iter().map(|p| {
+            let mut d = Diagnostic::warning(&lint::Warning{rule: "stack-balance", span: p.span, message: p.message.to_string()});
+            d.notes.push(format!("path: {}", p.path.join(" -> ")));
+            (files[&p.subroutine].to_string(), d)
+        }).collect::<Vec<(String,Diagnostic)>>();
+        print_warnings(&diagnostics, format);
+        return;
+    }
+
+    for p in &report.problems {
+        println!("{}:{}:{}: {}", files[&p.subroutine], p.span.line + 1, p.span.start + 1, p.message);
+        println!("    path: {}", p.path.join(" -> "));
+    }
+
+    let words = |w: Option<u32>| w.map_or("?".to_string(), |w| w.to_string());
+
+    if !report.problems.is_empty() {
+        println!();
+    }
+
+    println!("{:<16} {:>6} {:>6}", "subroutine", "local", "worst");
+
+    for u in &report.usages {
+        let note = if u.unknown.is_empty() { String::new() } else { format!("  (undefined: {})", u.unknown.join(", ")) };
+        println!("{:<16} {:>6} {:>6}{}", u.id, words(u.local), words(u.worst), note);
+    }
+
+    match report.total() {
+        Some(w) => println!("\nWorst-case stack usage: {} word(s)", w),
+        None => println!("\nWorst-case stack usage: unbounded (a loop pushes a varying number of words, or a call is recursive)"),
+    }
+
+    if !report.problems.is_empty() {
+        exit(1);
+    }
+}
+
+// regs FILE... : サブルーチンごとに入口の値を読むレジスタと変えるレジスタを表示し，
+//                注釈の約束 (@in，@out，@preserves) と比べる
+pub fn run_regs(matches: &Matches) {
+
+    let args = &matches.free[1..];
+
+    if args.is_empty() {
+        println!("Usage: regs FILE...");
+        exit(1);
+    }
+
+    let format = message_format(matches);
+    let (graph, files) = read_graph(args, format);
+    let summaries = clobber::analyze(&graph);
+
+    let mut diagnostics: Vec<(String,Diagnostic)> = Vec::new();
+
+    for path in args {
+
+        let mut buf = String::new();
+        read_source_code(&mut buf, path);
+
+        let own = summaries.iter().filter(|s| files[&s.id] == *path).cloned().collect::<Vec<clobber::Summary>>();
+
+        for (span, message) in clobber::violations(&buf, &own) {
+            diagnostics.push((path.to_string(), Diagnostic::warning(&lint::Warning{rule: "register-contract", span, message})));
+        }
+    }
+
+    // JSON や SARIF では約束と合わないところだけを書き出す
+    if format != MessageFormat::Human {
+        print_warnings(&diagnostics, format);
+        return;
+    }
+
+    for (path, d) in &diagnostics {
+        println!("{}:{}:{}: {}", path, d.span.line + 1, d.span.start + 1, d.message);
+    }
+
+    let count = diagnostics.len();
+
+    if count > 0 {
+        println!();
+    }
+
+    println!("{:<16} {:<24} clobbers", "subroutine", "inputs");
+
+    for s in &summaries {
+        let note = if s.unknown.is_empty() { String::new() } else { format!("  (undefined: {})", s.unknown.join(", ")) };
+        println!("{:<16} {:<24} {}{}", s.id, clobber::names(s.inputs), clobber::names(s.clobbers), note);
+    }
+
+    if count > 0 {
+        exit(1);
+    }
+}
+
+// stdlib      : 同梱しているサブルーチンを一覧表示する
+// stdlib NAME : サブルーチンのソースを表示する
+pub fn run_stdlib(args: &[String]) {
+
+    if args.is_empty() {
+        for (name, summary, _) in stdlib::ROUTINES.iter() {
+            println!("{:<8}{}", name, summary);
+        }
+        return;
+    }
+
+    for name in args {
+        match stdlib::source(name) {
+            Some(src) => print!("{}", src),
+            None => {
+                println!("Not found subroutine: `{}`", name);
+                exit(1);
+            }
+        }
+    }
+}
+
+// opcodes : 命令表をMarkdownの表で表示する
+pub fn run_opcodes() {
+    print!("{}", opcode::reference());
+}
+
+#[test]
+fn test_output_path() {
+
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("rust-casl2-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // オブジェクトからリンクしても入力を上書きしない
+    let path = dir.join("sample.o").to_string_lossy().to_string();
+    let objects = assemble("MAIN     START\n         RET\n         END\n").unwrap();
+    fs::write(&path, objects[0].to_string()).unwrap();
+
+    let inputs = vec![path.to_string()];
+    let mut objects = load_objects(&inputs, MessageFormat::Human);
+    let (code, _) = link_objects(&mut objects, &[]);
+    assert_eq!(code, vec![0x8100]);
+    assert_eq!(output_path(&inputs), Ok(dir.join("sample").to_string_lossy().to_string()));
+
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(output_path(&["a.casl2".to_string(), "b.o".to_string()]), Ok("a".to_string()));
+    assert_eq!(output_path(&["prog".to_string()]), Err("Output `prog` would overwrite an input file".to_string()));
+    assert!(output_path(&["a.casl2".to_string(), "a".to_string()]).is_err());
+}
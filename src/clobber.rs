@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+
+use ast::{Line,Macro,Operand,Span,Statement};
+use cfg::{Graph,Subroutine};
+use constant::Constant;
+use opcode::Format;
+use register::Register;
+use token::{tokenize,TokenType};
+
+// レジスタの集合 (ビット n が GRn)
+pub type Registers = u8;
+
+const ALL: Registers = 0xff;
+
+// サブルーチンが入口の値を読むレジスタと，戻るときに入口の値でなくなっているレジスタ
+//
+// PUSH 0,GRn (RPUSH) で退避して POP GRn (RPOP) で戻したものは変えたことにしない
+#[derive(Debug,Clone,PartialEq)]
+pub struct Summary {
+    pub id: String,
+    pub name: String,
+    // 入口の行
+    pub span: Span,
+    pub inputs: Registers,
+    pub clobbers: Registers,
+    // 定義の見つからない呼び出し先 (レジスタを使わないものとみなす)
+    pub unknown: Vec<String>,
+}
+
+// 注釈に書いた約束 (書いていない項目は None)
+//
+// ; @in GR1,GR2 @out GR0 @preserves GR2-GR7
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Contract {
+    pub inputs: Option<Registers>,
+    pub outputs: Option<Registers>,
+    pub preserves: Option<Registers>,
+}
+
+// 「GR1,GR2」「GR2-GR7」のように並べる (なければ「-」)
+pub fn names(set: Registers) -> String {
+
+    let mut v: Vec<String> = Vec::new();
+    let mut n = 0;
+
+    while n < 8 {
+
+        if set & (1 << n) == 0 {
+            n += 1;
+            continue;
+        }
+
+        let mut last = n;
+        while last < 7 && set & (1 << (last + 1)) != 0 {
+            last += 1;
+        }
+
+        if last - n >= 2 {
+            v.push(format!("GR{}-GR{}", n, last));
+        } else {
+            v.extend((n..last + 1).map(|r| format!("GR{}", r)));
+        }
+
+        n = last + 1;
+    }
+
+    if v.is_empty() {
+        "-".to_string()
+    } else {
+        v.join(",")
+    }
+}
+
+fn bit(r: Option<Register>) -> Registers {
+    r.map_or(0, |r| 1 << r.number())
+}
+
+// 命令が読むレジスタと書き込むレジスタ
+fn effect(line: &Line) -> (Registers, Registers) {
+
+    let (inst, operands) = match line.statement {
+        Statement::Instruction(inst, ref operands) => (inst, operands),
+        _ => return (0, 0),
+    };
+
+    let x = bit(line.statement.x());
+
+    match inst.format {
+        Format::R1R2 => {
+            let (r1, r2) = (bit(operands[0].node.register()), bit(operands[1].node.register()));
+            match inst.mnemonic {
+                // LD GRn,GRn はフラグを設定するだけで値は変わらない
+                "LD" if r1 == r2 => (r2, 0),
+                "LD" => (r2, r1),
+                "CPA" | "CPL" => (r1 | r2, 0),
+                _ => (r1 | r2, r1),
+            }
+        },
+        Format::RAdrX => {
+            let r = bit(line.statement.r());
+            match inst.mnemonic {
+                "LD" | "LAD" => (x, r),
+                "ST" | "CPA" | "CPL" => (r | x, 0),
+                _ => (r | x, r),
+            }
+        },
+        // POP r
+        Format::R => (0, bit(operands[0].node.register())),
+        _ => (x, 0),
+    }
+}
+
+// PUSH 0,GRn で退避するレジスタ
+fn saved(line: &Line) -> Option<Register> {
+    match line.statement {
+        Statement::Instruction(inst, _) if inst.mnemonic == "PUSH" => match line.statement.adr() {
+            Some(o) if o.node == Operand::Constant(Constant::Decimal(0)) || o.node == Operand::Constant(Constant::Hex(0)) => line.statement.x(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// ブロックの先頭での状態
+//
+// 入口の値のままのレジスタと，スタックに積んだもの (退避したレジスタの番号)
+#[derive(Debug,Clone,PartialEq)]
+struct State {
+    original: Registers,
+    stack: Vec<Option<u16>>,
+}
+
+impl State {
+
+    // 合流したときは両方で入口の値のままのものだけ残す
+    fn join(&self, other: &State) -> State {
+
+        let stack = if self.stack.len() == other.stack.len() {
+            self.stack.iter().zip(&other.stack).map(|(a, b)| if a == b { *a } else { None }).collect()
+        } else {
+            self.stack.clone()
+        };
+
+        State{original: self.original & other.original, stack}
+    }
+
+    fn pop(&mut self, r: u16) {
+        match self.stack.pop() {
+            Some(Some(n)) if n == r => self.original |= 1 << r,
+            _ => self.original &= !(1 << r),
+        }
+    }
+}
+
+// 1つのサブルーチンを呼び出し先の要約を使って調べる
+fn summarize(sub: &Subroutine, summaries: &HashMap<String,(Registers,Registers)>) -> (Registers, Registers, Vec<String>) {
+
+    let index: HashMap<&str,usize> = sub.blocks.iter().enumerate().map(|(i, b)| (b.name.as_str(), i)).collect();
+
+    let mut states: Vec<Option<State>> = vec![None; sub.blocks.len()];
+    let mut inputs: Registers = 0;
+    let mut clobbers: Registers = 0;
+    let mut unknown: Vec<String> = Vec::new();
+
+    if sub.blocks.is_empty() {
+        return (inputs, clobbers, unknown);
+    }
+
+    states[0] = Some(State{original: ALL, stack: Vec::new()});
+    let mut work = vec![0];
+
+    while let Some(b) = work.pop() {
+
+        let block = &sub.blocks[b];
+        let mut state = states[b].clone().unwrap();
+
+        for l in &block.lines {
+
+            match l.statement {
+                Statement::Macro(Macro::Rpush, _) => {
+                    for r in 1..8 {
+                        let original = state.original & (1 << r) != 0;
+                        state.stack.push(if original { Some(r) } else { None });
+                    }
+                    continue;
+                },
+                Statement::Macro(Macro::Rpop, _) => {
+                    for r in (1..8).rev() {
+                        state.pop(r);
+                    }
+                    continue;
+                },
+                _ => {},
+            }
+
+            if let Some(r) = saved(l) {
+                let original = state.original & bit(Some(r)) != 0;
+                state.stack.push(if original { Some(r.number()) } else { None });
+                continue;
+            }
+
+            let (reads, writes) = effect(l);
+            inputs |= reads & state.original;
+
+            match l.statement {
+                Statement::Instruction(inst, _) if inst.mnemonic == "PUSH" => state.stack.push(None),
+                Statement::Instruction(inst, _) if inst.mnemonic == "POP" => {
+                    state.pop(l.statement.operands()[0].node.register().map_or(0, |r| r.number()));
+                    continue;
+                },
+                Statement::Instruction(inst, _) if inst.mnemonic == "RET" => clobbers |= !state.original,
+                _ => {},
+            }
+
+            state.original &= !writes;
+        }
+
+        // CALL はブロックの最後にある
+        if let Some(c) = block.calls.first() {
+            match summaries.get(c) {
+                Some(&(i, o)) => {
+                    inputs |= i & state.original;
+                    state.original &= !o;
+                },
+                None if !unknown.contains(c) => unknown.push(c.to_string()),
+                None => {},
+            }
+        }
+
+        for (to, _) in &block.succs {
+
+            let s = index[to.as_str()];
+
+            let next = match states[s] {
+                Some(ref old) => old.join(&state),
+                None => state.clone(),
+            };
+
+            if states[s].as_ref() != Some(&next) {
+                states[s] = Some(next);
+                work.push(s);
+            }
+        }
+    }
+
+    (inputs, clobbers, unknown)
+}
+
+// サブルーチンごとに入力と変えるレジスタを求める
+//
+// 呼び出している標準サブルーチンも調べ，再帰があっても変わらなくなるまで繰り返す
+pub fn analyze(graph: &Graph) -> Vec<Summary> {
+
+    let subroutines = graph.with_stdlib().subroutines;
+    let mut summaries: HashMap<String,(Registers,Registers)> = subroutines.iter().map(|s| (s.id.to_string(), (0, 0))).collect();
+
+    loop {
+
+        let mut changed = false;
+
+        for sub in &subroutines {
+            let (i, o, _) = summarize(sub, &summaries);
+            if summaries[&sub.id] != (i, o) {
+                summaries.insert(sub.id.to_string(), (i, o));
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    subroutines
+        .iter()
+        .take(graph.subroutines.len())
+        .map(|sub| {
+            let (inputs, clobbers, unknown) = summarize(sub, &summaries);
+            let span = sub.blocks.first().and_then(|b| b.lines.first()).map_or(Span::new(0, 0, 0), |l| l.span);
+            Summary{id: sub.id.to_string(), name: sub.name.to_string(), span, inputs, clobbers, unknown}
+        })
+        .collect()
+}
+
+// 「GR1」「GR2-GR7」を並べたもの
+fn parse_registers(words: &[&str]) -> Option<Registers> {
+
+    let mut set: Registers = 0;
+
+    for w in words {
+        let (a, b) = match w.find('-') {
+            Some(i) => (&w[..i], &w[i+1..]),
+            None => (*w, *w),
+        };
+        let (a, b) = (Register::parse(a)?.number(), Register::parse(b)?.number());
+        for r in a.min(b)..a.max(b) + 1 {
+            set |= 1 << r;
+        }
+    }
+
+    Some(set)
+}
+
+// 入口の行とその上に続く注釈から約束を読む
+//
+// プログラムの入口なら START の行とその上の注釈も見る
+pub fn contract(src: &str, entry: usize) -> Result<Option<Contract>, String> {
+
+    let lines: Vec<&str> = src.lines().collect();
+    let mut comments: Vec<String> = Vec::new();
+    let mut n = entry;
+
+    loop {
+
+        let tokens = tokenize(n, lines.get(n).cloned().unwrap_or("")).unwrap_or_default();
+        let opcode = tokens.iter().find(|t| t.kind == TokenType::Opcode).map(|t| t.value.as_str());
+
+        if n != entry && opcode.is_some() && opcode != Some("START") {
+            break;
+        }
+
+        comments.extend(tokens.iter().filter(|t| t.kind == TokenType::Comment).map(|t| t.value.to_string()));
+
+        if n == 0 {
+            break;
+        }
+
+        n -= 1;
+    }
+
+    let mut contract: Option<Contract> = None;
+
+    for c in comments {
+
+        let words = c.trim_start_matches(';').split(|c: char| c.is_whitespace() || c == ',').filter(|w| !w.is_empty()).collect::<Vec<&str>>();
+
+        let mut i = 0;
+
+        while i < words.len() {
+
+            if !words[i].starts_with('@') {
+                i += 1;
+                continue;
+            }
+
+            let end = words[i+1..].iter().position(|w| w.starts_with('@')).map_or(words.len(), |p| i + 1 + p);
+            let set = parse_registers(&words[i+1..end]).ok_or(format!("Invalid registers in `{}`", words[i..end].join(" ")))?;
+            let contract = contract.get_or_insert_with(Contract::default);
+
+            match words[i] {
+                "@in" => contract.inputs = Some(set),
+                "@out" => contract.outputs = Some(set),
+                "@preserves" => contract.preserves = Some(set),
+                w => return Err(format!("Unknown contract `{}` (@in, @out or @preserves)", w)),
+            }
+
+            i = end;
+        }
+    }
+
+    Ok(contract)
+}
+
+// 約束と違うところ
+pub fn check(s: &Summary, c: &Contract) -> Vec<String> {
+
+    let mut messages: Vec<String> = Vec::new();
+
+    if let Some(inputs) = c.inputs {
+        let extra = s.inputs & !inputs;
+        if extra != 0 {
+            messages.push(format!("`{}` reads {} before writing, but it isn't declared in @in", s.name, names(extra)));
+        }
+    }
+
+    if let Some(outputs) = c.outputs {
+        let missing = outputs & !s.clobbers;
+        if missing != 0 {
+            messages.push(format!("`{}` never sets {}, which is declared in @out", s.name, names(missing)));
+        }
+    }
+
+    if let Some(preserves) = c.preserves {
+        let broken = s.clobbers & preserves;
+        if broken != 0 {
+            messages.push(format!("`{}` modifies {}, which is declared in @preserves", s.name, names(broken)));
+        }
+    }
+
+    messages
+}
+
+// src にあるサブルーチンの約束を読んで調べる
+//
+// 約束の書き方の誤りも入口の行に報告する
+pub fn violations(src: &str, summaries: &[Summary]) -> Vec<(Span,String)> {
+
+    let mut v: Vec<(Span,String)> = Vec::new();
+
+    for s in summaries {
+        match contract(src, s.span.line) {
+            Ok(Some(c)) => v.extend(check(s, &c).into_iter().map(|m| (s.span, m))),
+            Ok(None) => {},
+            Err(e) => v.push((s.span, e)),
+        }
+    }
+
+    v
+}
+
+#[test]
+fn test_clobber() {
+
+    use cfg;
+
+    let src = "\
+; @in GR1 @out GR0 @preserves GR2-GR7
+MAIN     START
+         LD      GR0,GR1
+         CALL    SUB
+         RET
+; @in GR1
+; @preserves GR1,GR3
+SUB      PUSH    0,GR1
+         PUSH    0,GR2
+         ADDA    GR1,GR3
+         LD      GR4,GR4
+         LAD     GR2,1
+         CALL    MULU
+         POP     GR2
+         JZE     DONE
+         POP     GR1
+         RET
+DONE     POP     GR3
+         RET
+         END
+";
+
+    let summaries = analyze(&cfg::build(src).unwrap());
+
+    assert_eq!(summaries[1].id, "MAIN.SUB");
+    // LD GR4,GR4 は読むだけ
+    assert_eq!(names(summaries[1].inputs), "GR1,GR3,GR4");
+    // MULU は GR0 を変え，DONE の経路では GR1 を戻さず GR3 に積んだ値を入れる
+    assert_eq!(names(summaries[1].clobbers), "GR0,GR1,GR3");
+    assert_eq!(names(summaries[0].inputs), "GR1,GR3,GR4");
+    assert_eq!(names(summaries[0].clobbers), "GR0,GR1,GR3");
+
+    let c = contract(src, 2).unwrap().unwrap();
+    assert_eq!(c, Contract{inputs: Some(0b10), outputs: Some(0b1), preserves: Some(0b11111100)});
+    assert_eq!(check(&summaries[0], &c), vec![
+        "`MAIN` reads GR3,GR4 before writing, but it isn't declared in @in",
+        "`MAIN` modifies GR3, which is declared in @preserves",
+    ]);
+
+    let c = contract(src, 7).unwrap().unwrap();
+    assert_eq!(c.outputs, None);
+    assert_eq!(check(&summaries[1], &c), vec![
+        "`SUB` reads GR3,GR4 before writing, but it isn't declared in @in",
+        "`SUB` modifies GR1,GR3, which is declared in @preserves",
+    ]);
+
+    assert_eq!(contract(src, 3), Ok(None));
+    assert!(contract("; @in GR8\n", 0).is_err());
+    assert_eq!(names(0b11101101), "GR0,GR2,GR3,GR5-GR7");
+}
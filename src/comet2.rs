@@ -0,0 +1,487 @@
+use std::fmt;
+
+use console::{Console,StdConsole};
+use disasm::{decode,Operands};
+use register::Register;
+
+pub const MEMORY_SIZE: usize = 65536;
+
+// 1命令実行した後の状態
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum State {
+    Running,
+    // スタックが空の状態でRETした (OSに戻った)
+    Halted,
+}
+
+// IN, OUT を展開したSVCの番号
+pub const SVC_IN: u16 = 1;
+pub const SVC_OUT: u16 = 2;
+
+// 1レコードの最大文字数
+pub const RECORD_SIZE: usize = 256;
+
+// 1命令ごとに呼ばれるもの (トレースや表など)
+pub trait Hook<C: Console> {
+    // 命令を実行する前
+    fn before(&mut self, _cpu: &Comet2<C>) -> Result<(), String> {
+        Ok(())
+    }
+    // 実行した後．pr は実行した命令の番地
+    fn after(&mut self, _cpu: &Comet2<C>, _pr: u16) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// COMET2
+//
+// SPは0から始まり，最初のPUSHやCALLで #FFFF から下に積む．
+// スタックが空のときのRETで実行を終える
+#[derive(Clone)]
+pub struct Comet2<C: Console = StdConsole> {
+    pub memory: Vec<u16>,
+    pub gr: [u16; 8],
+    pub sp: u16,
+    pub pr: u16,
+    pub of: bool,
+    pub sf: bool,
+    pub zf: bool,
+    // 実行した命令の数
+    pub steps: u64,
+    // 直前の命令が読み出した番地 (命令の取り出しは除く)
+    pub reads: Vec<u16>,
+    // 直前の命令が書き込んだ番地と，書き込む前の値
+    pub writes: Vec<(u16,u16)>,
+    pub console: C,
+}
+
+impl Comet2 {
+
+    // 標準入力と標準出力につないだもの
+    pub fn new() -> Comet2 {
+        Comet2::with_console(StdConsole)
+    }
+
+    pub fn load(code: &[u16], entry: u16) -> Comet2 {
+        Comet2::load_with_console(code, entry, StdConsole)
+    }
+}
+
+impl<C: Console> Comet2<C> {
+
+    pub fn with_console(console: C) -> Comet2<C> {
+        Comet2{
+            memory: vec![0; MEMORY_SIZE],
+            gr: [0; 8],
+            sp: 0,
+            pr: 0,
+            of: false,
+            sf: false,
+            zf: false,
+            steps: 0,
+            reads: Vec::new(),
+            writes: Vec::new(),
+            console,
+        }
+    }
+
+    // 機械語を0番地から置き，entryから実行を始める
+    //
+    // 主記憶に入り切らない分は置かない (リンクや読み込みのときに65536語までに限っている)
+    pub fn load_with_console(code: &[u16], entry: u16, console: C) -> Comet2<C> {
+        let mut cpu = Comet2::with_console(console);
+        let len = code.len().min(MEMORY_SIZE);
+        cpu.memory[..len].copy_from_slice(&code[..len]);
+        cpu.pr = entry;
+        cpu
+    }
+
+    pub fn read(&self, addr: u16) -> u16 {
+        self.memory[addr as usize]
+    }
+
+    // 命令がデータとして読み出す (reads に残す)
+    fn read_data(&mut self, addr: u16) -> u16 {
+        self.reads.push(addr);
+        self.read(addr)
+    }
+
+    pub fn write(&mut self, addr: u16, v: u16) {
+        self.writes.push((addr, self.memory[addr as usize]));
+        self.memory[addr as usize] = v;
+    }
+
+    fn set_flags(&mut self, v: u16, of: bool) {
+        self.of = of;
+        self.sf = v & 0x8000 != 0;
+        self.zf = v == 0;
+    }
+
+    fn push(&mut self, v: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        let sp = self.sp;
+        self.write(sp, v);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let v = self.read_data(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        v
+    }
+
+    // PRの命令を1つ実行する
+    pub fn step(&mut self) -> Result<State, String> {
+
+        self.reads.clear();
+        self.writes.clear();
+
+        let pr = self.pr;
+        let words = [self.read(pr), self.read(pr.wrapping_add(1))];
+
+        let d = match decode(&words) {
+            Some(d) => d,
+            None => return Err(format!("Illegal instruction #{:0>4X} at #{:0>4X}", words[0], pr)),
+        };
+
+        self.pr = pr.wrapping_add(d.words());
+        self.steps += 1;
+
+        // 実効番地と，r1,r2形式の2つ目のレジスタ
+        let (r, e, r2) = match d.operands {
+            Operands::Nothing => (0, 0, 0),
+            Operands::R(r) => (r.number(), 0, 0),
+            Operands::RR(r1, r2) => (r1.number(), 0, self.gr[r2.number() as usize]),
+            Operands::RAdr(r, adr, x) => (r.number(), self.effective(adr, x), 0),
+            Operands::Adr(adr, x) => (0, self.effective(adr, x), 0),
+        };
+
+        let r = r as usize;
+        let code = d.inst.code;
+
+        // 実効番地の内容かr2のどちらかをとる命令
+        let operand = match code {
+            0x14 | 0x24..=0x27 | 0x34..=0x36 | 0x44 | 0x45 => r2,
+            0x10 | 0x20..=0x23 | 0x30..=0x32 | 0x40 | 0x41 => self.read_data(e),
+            _ => 0,
+        };
+
+        match code {
+
+            0x00 => {},
+
+            0x10 | 0x14 => {
+                self.gr[r] = operand;
+                self.set_flags(operand, false);
+            },
+
+            0x11 => self.write(e, self.gr[r]),
+
+            0x12 => self.gr[r] = e,
+
+            0x20 | 0x24 => {
+                let v = (self.gr[r] as i16 as i32) + (operand as i16 as i32);
+                self.gr[r] = v as u16;
+                self.set_flags(v as u16, !(-32768..=32767).contains(&v));
+            },
+
+            0x21 | 0x25 => {
+                let v = (self.gr[r] as i16 as i32) - (operand as i16 as i32);
+                self.gr[r] = v as u16;
+                self.set_flags(v as u16, !(-32768..=32767).contains(&v));
+            },
+
+            0x22 | 0x26 => {
+                let (v, of) = self.gr[r].overflowing_add(operand);
+                self.gr[r] = v;
+                self.set_flags(v, of);
+            },
+
+            0x23 | 0x27 => {
+                let (v, of) = self.gr[r].overflowing_sub(operand);
+                self.gr[r] = v;
+                self.set_flags(v, of);
+            },
+
+            0x30 | 0x34 => {
+                self.gr[r] &= operand;
+                self.set_flags(self.gr[r], false);
+            },
+
+            0x31 | 0x35 => {
+                self.gr[r] |= operand;
+                self.set_flags(self.gr[r], false);
+            },
+
+            0x32 | 0x36 => {
+                self.gr[r] ^= operand;
+                self.set_flags(self.gr[r], false);
+            },
+
+            0x40 | 0x44 => {
+                let (a, b) = (self.gr[r] as i16, operand as i16);
+                self.of = false;
+                self.sf = a < b;
+                self.zf = a == b;
+            },
+
+            0x41 | 0x45 => {
+                let (a, b) = (self.gr[r], operand);
+                self.of = false;
+                self.sf = a < b;
+                self.zf = a == b;
+            },
+
+            0x50..=0x53 => {
+                let (v, of) = shift(code, self.gr[r], e);
+                self.gr[r] = v;
+                self.set_flags(v, of);
+            },
+
+            0x61 => if self.sf { self.pr = e },
+            0x62 => if !self.zf { self.pr = e },
+            0x63 => if self.zf { self.pr = e },
+            0x64 => self.pr = e,
+            0x65 => if !self.sf && !self.zf { self.pr = e },
+            0x66 => if self.of { self.pr = e },
+
+            0x70 => self.push(e),
+
+            0x71 => self.gr[r] = self.pop(),
+
+            0x80 => {
+                let ret = self.pr;
+                self.push(ret);
+                self.pr = e;
+            },
+
+            0x81 => {
+                if self.sp == 0 {
+                    return Ok(State::Halted);
+                }
+                self.pr = self.pop();
+            },
+
+            0xf0 => self.svc(e, pr)?,
+
+            _ => return Err(format!("Not supported instruction {} at #{:0>4X}", d.inst.mnemonic, pr)),
+        }
+
+        Ok(State::Running)
+    }
+
+    // GR1 = 領域の先頭番地，GR2 = 文字数を置く番地
+    fn svc(&mut self, n: u16, pr: u16) -> Result<(), String> {
+
+        let (buf, len) = (self.gr[1], self.gr[2]);
+
+        match n {
+
+            // 1行読んで1文字1語で置く．入力の終わりなら文字数を-1にする
+            SVC_IN => match self.console.read_line() {
+                Some(line) => {
+                    let chars = line.chars().take(RECORD_SIZE).collect::<Vec<char>>();
+                    for (i, c) in chars.iter().enumerate() {
+                        self.write(buf.wrapping_add(i as u16), *c as u16);
+                    }
+                    self.write(len, chars.len() as u16);
+                },
+                None => self.write(len, 0xffff),
+            },
+
+            SVC_OUT => {
+                let n = (self.read_data(len) as usize).min(RECORD_SIZE);
+                let line = (0..n)
+                    .map(|i| self.read_data(buf.wrapping_add(i as u16)))
+                    .map(|w| ::std::char::from_u32(w as u32).unwrap_or('?'))
+                    .collect::<String>();
+                self.console.write_line(&line);
+            },
+
+            _ => return Err(format!("Unknown SVC {} at #{:0>4X}", n, pr)),
+        }
+
+        Ok(())
+    }
+
+    // 終了するまで実行する．limit命令を超えたらエラー
+    pub fn run(&mut self, limit: u64) -> Result<(), String> {
+        self.run_with(limit, &mut [])
+    }
+
+    // 1命令ごとにhooksを呼びながら実行する
+    pub fn run_with(&mut self, limit: u64, hooks: &mut [&mut dyn Hook<C>]) -> Result<(), String> {
+
+        let start = self.steps;
+
+        while self.steps - start < limit {
+
+            for hook in hooks.iter_mut() {
+                hook.before(self)?;
+            }
+
+            let pr = self.pr;
+            let state = self.step()?;
+
+            for hook in hooks.iter_mut() {
+                hook.after(self, pr)?;
+            }
+
+            if state == State::Halted {
+                return Ok(());
+            }
+        }
+
+        Err(format!("Step limit exceeded: {} instructions", limit))
+    }
+
+    fn effective(&self, adr: u16, x: Option<Register>) -> u16 {
+        match x {
+            Some(x) => adr.wrapping_add(self.gr[x.number() as usize]),
+            None => adr,
+        }
+    }
+}
+
+impl Default for Comet2 {
+    fn default() -> Comet2 {
+        Comet2::new()
+    }
+}
+
+impl<C: Console> fmt::Display for Comet2<C> {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        for (i, v) in self.gr.iter().enumerate() {
+            write!(f, "GR{}=#{:0>4X} ", i, v)?;
+        }
+
+        write!(f, "SP=#{:0>4X} PR=#{:0>4X} OF={} SF={} ZF={}",
+               self.sp, self.pr, self.of as u8, self.sf as u8, self.zf as u8)
+    }
+}
+
+// シフト命令の結果と，最後に送り出されたビット (OF)
+//
+// 17ビット以上のシフトは16ビットのときと結果が変わらない
+fn shift(code: u16, v: u16, n: u16) -> (u16, bool) {
+
+    let mut v = v;
+    let mut of = false;
+
+    for _ in 0..n.min(17) {
+        match code {
+            0x50 => {
+                of = v & 0x4000 != 0;
+                v = (v & 0x8000) | ((v << 1) & 0x7fff);
+            },
+            0x51 => {
+                of = v & 1 != 0;
+                v = (v & 0x8000) | (v >> 1);
+            },
+            0x52 => {
+                of = v & 0x8000 != 0;
+                v <<= 1;
+            },
+            _ => {
+                of = v & 1 != 0;
+                v >>= 1;
+            },
+        }
+    }
+
+    (v, of)
+}
+
+#[test]
+fn test_comet2() {
+
+    // LD GR1,=#7FFF / ADDA GR1,=1 / JOV OV / RET / OV: CPA GR1,GR2 / SRA GR1,3 / RET
+    let code = [
+        0x1010, 0x000e,
+        0x2010, 0x000f,
+        0x6600, 0x0007,
+        0x8100,
+        0x4412,
+        0x5110, 0x0003,
+        0x8000, 0x000d,
+        0x8100,
+        0x8100,
+        0x7fff, 0x0001,
+    ];
+
+    let mut cpu = Comet2::load(&code, 0);
+
+    cpu.step().unwrap();
+    assert_eq!((cpu.gr[1], cpu.of, cpu.sf, cpu.zf), (0x7fff, false, false, false));
+    cpu.step().unwrap();
+    assert_eq!((cpu.gr[1], cpu.of, cpu.sf), (0x8000, true, true));
+    cpu.step().unwrap();
+    assert_eq!(cpu.pr, 7);
+    cpu.step().unwrap();
+    assert_eq!((cpu.of, cpu.sf, cpu.zf), (false, true, false));
+    cpu.step().unwrap();
+    assert_eq!((cpu.gr[1], cpu.of), (0xf000, false));
+
+    // CALLで積んだ戻り番地に戻り，最後のRETで止まる
+    cpu.step().unwrap();
+    assert_eq!((cpu.sp, cpu.read(0xffff), cpu.pr), (0xffff, 12, 13));
+    assert_eq!(cpu.writes, vec![(0xffff, 0)]);
+    assert!(cpu.reads.is_empty());
+    assert_eq!(cpu.step(), Ok(State::Running));
+    assert_eq!(cpu.pr, 12);
+    assert_eq!(cpu.step(), Ok(State::Halted));
+    assert_eq!(cpu.steps, 8);
+
+    assert_eq!(shift(0x52, 0x8001, 1), (0x0002, true));
+    assert_eq!(shift(0x50, 0xc001, 1), (0x8002, true));
+    assert_eq!(shift(0x51, 0x8000, 20), (0xffff, true));
+    assert_eq!(shift(0x53, 0xffff, 0), (0xffff, false));
+
+    let mut cpu = Comet2::load(&[0xff00], 0);
+    assert!(cpu.step().is_err());
+
+    let mut cpu = Comet2::load(&[0x6400, 0x0000], 0);
+    assert_eq!(cpu.run(100), Err("Step limit exceeded: 100 instructions".to_string()));
+
+    // 主記憶より大きくても止まらない
+    let cpu = Comet2::load(&vec![0x8100; MEMORY_SIZE + 1], 0);
+    assert_eq!(cpu.read(0xffff), 0x8100);
+}
+
+#[test]
+fn test_console() {
+
+    use assembler::assemble;
+    use console::Buffer;
+    use linker::link;
+
+    // 入力の終わりまで1行ずつ読んで書き出す
+    let src = "\
+ECHO     START
+LOOP     IN      BUF,LEN
+         LD      GR0,LEN
+         JMI     FIN
+         OUT     BUF,LEN
+         JUMP    LOOP
+FIN      RET
+BUF      DS      256
+LEN      DS      1
+         END
+";
+
+    let code = link(&assemble(src).unwrap()).unwrap();
+    let mut cpu = Comet2::load_with_console(&code, 0, Buffer::new("hello\n\nこんにちは\n"));
+
+    cpu.gr[1] = 0x1234;
+    cpu.run(1000).unwrap();
+
+    assert_eq!(cpu.console.output, "hello\n\nこんにちは\n");
+    assert_eq!(cpu.read(code.len() as u16 - 1), 0xffff);
+
+    // GR1, GR2 は元に戻る
+    assert_eq!((cpu.gr[1], cpu.gr[2]), (0x1234, 0));
+
+    let mut cpu = Comet2::load_with_console(&[0xf000, 0x0009], 0, Buffer::new(""));
+    assert!(cpu.step().is_err());
+}
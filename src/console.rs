@@ -0,0 +1,63 @@
+use std::collections::VecDeque;
+use std::io::{self,BufRead,Write};
+
+// IN, OUT のSVCが読み書きする入出力装置
+//
+// 1レコードを1行として扱う
+pub trait Console {
+    // 1行読む．入力の終わりならNone
+    fn read_line(&mut self) -> Option<String>;
+    fn write_line(&mut self, line: &str);
+}
+
+// 標準入力と標準出力
+#[derive(Debug,Clone,Default)]
+pub struct StdConsole;
+
+impl Console for StdConsole {
+
+    fn read_line(&mut self) -> Option<String> {
+
+        let mut buf = String::new();
+
+        match io::stdin().lock().read_line(&mut buf) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(buf.trim_end_matches(['\n', '\r']).to_string()),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let _ = writeln!(out, "{}", line);
+        let _ = out.flush();
+    }
+}
+
+// テストや採点用に，入力を渡して出力をためておくもの
+#[derive(Debug,Clone,Default)]
+pub struct Buffer {
+    pub input: VecDeque<String>,
+    pub output: String,
+}
+
+impl Buffer {
+    pub fn new(input: &str) -> Buffer {
+        Buffer{
+            input: input.lines().map(|l| l.to_string()).collect(),
+            output: String::new(),
+        }
+    }
+}
+
+impl Console for Buffer {
+
+    fn read_line(&mut self) -> Option<String> {
+        self.input.pop_front()
+    }
+
+    fn write_line(&mut self, line: &str) {
+        self.output.push_str(line);
+        self.output.push('\n');
+    }
+}
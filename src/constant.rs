@@ -1,64 +1,87 @@
 use register::is_register;
-use token::SymbolTable;
 
 use self::Constant::*;
 
-#[derive(PartialEq)]
+// 定数
+//
+// 10進定数は -32768〜65535 を受け付け，負の数は2の補数の語になる
+#[derive(Debug,Clone,PartialEq)]
 pub enum Constant {
-    Decimal,
-    Hex,
-    Char,
-    Address,
-    Other,
+    Decimal(i32),
+    Hex(u16),
+    // 前後の「'」を外し，「''」を「'」に戻した文字列
+    Char(String),
+    // ラベル
+    Address(String),
 }
 
-pub fn is_constant(s: &str) -> bool {
-    is_constant_of(s) != Other 
-}
+impl Constant {
+
+    pub fn parse(s: &str) -> Option<Constant> {
+
+        if is_decimal(s) {
+            Some(Decimal(s.parse::<i32>().unwrap()))
+        } else if is_hex(s) {
+            Some(Hex(u16::from_str_radix(&s[1..], 16).unwrap()))
+        } else if is_char(s) {
+            Some(Char(s[1..s.len()-1].replace("''", "'")))
+        } else if is_label(s) {
+            Some(Address(s.to_string()))
+        } else {
+            None
+        }
+    }
 
-pub fn is_constant_of(s: &str) -> Constant {
-    
-    if is_decimal(s) {
-        return Decimal;
-    } else if is_hex(s) {
-        return Hex;
-    } else if is_char(s) {
-        return Char;
-    } else if is_label(s) {
-        return Address
-    } else {
-        return Other
+    // 定数が占める語数
+    pub fn words(&self) -> u16 {
+        match *self {
+            Char(ref s) => s.chars().count() as u16,
+            _ => 1,
+        }
+    }
+
+    // ラベル以外の定数の値
+    pub fn values(&self) -> Vec<u16> {
+        match *self {
+            Decimal(v) => vec![v as u16],
+            Hex(v) => vec![v],
+            Char(ref s) => s.chars().map(|c| c as u16).collect(),
+            Address(_) => vec![0],
+        }
     }
-    
 }
 
 pub fn is_decimal(s: &str) -> bool {
-    match s.parse::<u16>() {
-        Ok(_) => true,
+
+    let digits = s.strip_prefix('-').unwrap_or(s);
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+
+    match s.parse::<i32>() {
+        Ok(v) => (-32768..=65535).contains(&v),
         Err(_) => false,
     }
 }
 
 pub fn is_hex(s: &str) -> bool {
-    match u16::from_str_radix(&s[1..], 16) {
-        Ok(_) => true,
-        Err(_) => false,
-    }
+    s.starts_with('#') && s.len() > 1 && s[1..].chars().all(|c| c.is_ascii_hexdigit()) && u16::from_str_radix(&s[1..], 16).is_ok()
 }
 
 pub fn is_char(s: &str) -> bool {
-    
-    if !(s.starts_with('\'') && s.ends_with('\'')) {
+
+    if !(s.len() >= 2 && s.starts_with('\'') && s.ends_with('\'')) {
         return false;
     }
 
     // 先頭と末尾の「'」を取り除く
     let content = &s[1..s.len()-1];
-    
+
     let mut cnt = 0;
-    
+
     for c in content.chars() {
-        
+
         if c == '\'' {
             cnt += 1;
         } else {
@@ -68,66 +91,43 @@ pub fn is_char(s: &str) -> bool {
             cnt = 0;
         }
     }
-    
-    true
+
+    cnt % 2 == 0
 }
 
 pub fn is_label(s: &str) -> bool {
-    
-    if is_register(s) {
-        println!("Label name can't use GR0 ~ GR7");
-        return false;
-    }
-    
-    if !(1 <= s.len() && s.len() <= 8) {
-        println!("Label name length is 1 to 8");
-        return false;
-    }
+    label_error(s).is_none()
+}
 
-    fn is_upper(c: char) -> bool {
-        (65 <= c as u8 && c as u8 <= 90)
+// ラベルとして使えない理由
+pub fn label_error(s: &str) -> Option<&'static str> {
+
+    if is_register(s) {
+        return Some("Label name can't use GR0 ~ GR7");
     }
 
-    fn is_digit(c: char) -> bool {
-        (0x30 <= c as u8 && c as u8 <= 0x39)
+    if s.is_empty() || s.len() > 8 {
+        return Some("Label name length is 1 to 8");
     }
 
     let mut chars = s.chars();
 
     // 1文字目は英大文字しか使えない
-    match chars.nth(0) {
-        Some(v) => {
-            if !is_upper(v) {
-                println!("1st letter of label name is uppercase");
-                return false;
-            }
-        },
-        None => return false,
+    match chars.next() {
+        Some(v) if v.is_ascii_uppercase() => {},
+        _ => return Some("1st letter of label name is uppercase"),
     }
 
     // 2文字目以降が英大文字か数字しか使えない
-    for c in chars {
-
-        if !(is_digit(c) || is_upper(c)) {
-            println!("2nd or later letter of label name is uppsercase or digit");
-            return false;
-        }
+    if !chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+        return Some("2nd or later letter of label name is uppercase or digit");
     }
 
-    true
-}
-
-pub fn constant_of_char_len(s: &str) -> u16 {
-    
-    if !is_char(s) {
-        panic!("Invalid constant of char");
-    }
-    
-    let replaced = s.replace("''", "'");
-    replaced.len() as u16
+    None
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_is_char() {
     assert_eq!(is_char("'hoge'"), true);
     assert_eq!(is_char("hoge'"), false);
@@ -139,16 +139,17 @@ fn test_is_char() {
     assert_eq!(is_char("'ho'g'''e'"), false);    
     assert_eq!(is_char("'hog''''e'"), true);
     assert_eq!(is_char("'h'''og''''e'"), false);
+    assert_eq!(is_char("'"), false);
 }
 
-pub fn get_constant_value(s: &str, labels: &mut SymbolTable) -> u16 {
-    if is_decimal(s) {
-        return s.parse::<u16>().unwrap();
-    } else if is_hex(s) {
-        return u16::from_str_radix(&s[1..], 16).unwrap();
-    } else if labels.contains_key(s) {
-        return *labels.get(s).unwrap();
-    } else {
-        panic!("Invalid constant");
-    }
+#[test]
+fn test_constant() {
+    assert_eq!(Constant::parse("-1"), Some(Decimal(-1)));
+    assert_eq!(Constant::parse("65536"), None);
+    assert_eq!(Constant::parse("#00ff"), Some(Hex(0xff)));
+    assert_eq!(Constant::parse("'it''s'"), Some(Char("it's".to_string())));
+    assert_eq!(Constant::parse("LOOP1"), Some(Address("LOOP1".to_string())));
+    assert_eq!(Constant::parse("GR1"), None);
+    assert_eq!(Decimal(-1).values(), vec![0xffff]);
+    assert_eq!(Char("it's".to_string()).words(), 4);
 }
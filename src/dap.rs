@@ -0,0 +1,895 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self,BufRead,Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+use archive::Archive;
+use assembler::assemble;
+use comet2::Comet2;
+use console::Buffer;
+use debugger::Debugger;
+use debuginfo::DebugInfo;
+use expr::Expr;
+use json::Json;
+use linker::{link,resolve_members};
+use object::Object;
+use register::Register;
+use stdlib;
+use watch::{Kind,Watchpoint};
+
+// 変数の一覧の番号
+const REGISTERS: i64 = 1;
+const LABELS: i64 = 2;
+
+// 一時停止の要求を確かめる間隔
+const POLL_INTERVAL: u64 = 4096;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Mode {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+    StepBack,
+    ReverseContinue,
+}
+
+// 止まった理由 (DAPの reason と説明)
+enum Stop {
+    Stopped(&'static str, Option<String>),
+    Exited,
+}
+
+// Debug Adapter Protocol で Debugger を操作する
+//
+// プログラムの入力は launch の input で渡し，出力は output イベントで送る
+pub struct Adapter {
+    pub dbg: Option<Debugger<Buffer>>,
+    pub quit: bool,
+    seq: i64,
+    // 送るメッセージ
+    outbox: Vec<Json>,
+    // ソースのパスごとのブレークポイント
+    sources: Vec<(String,Vec<Watchpoint>)>,
+    // ラベルのメモリのウォッチポイント
+    data: Vec<Watchpoint>,
+    next_id: i64,
+    stop_on_entry: bool,
+    configured: bool,
+    started: bool,
+    // 応答を送った後に実行するもの
+    pending: Option<Mode>,
+}
+
+impl Default for Adapter {
+    fn default() -> Adapter {
+        Adapter::new()
+    }
+}
+
+impl Adapter {
+
+    pub fn new() -> Adapter {
+        Adapter{
+            dbg: None,
+            quit: false,
+            seq: 0,
+            outbox: Vec::new(),
+            sources: Vec::new(),
+            data: Vec::new(),
+            next_id: 1,
+            stop_on_entry: false,
+            configured: false,
+            started: false,
+            pending: None,
+        }
+    }
+
+    fn event(&mut self, name: &str, body: Json) {
+        let mut fields = vec![("type", Json::string("event")), ("event", Json::string(name))];
+        if body != Json::Null {
+            fields.push(("body", body));
+        }
+        self.outbox.push(Json::object(fields));
+    }
+
+    // 送る順に seq を付ける
+    fn drain(&mut self) -> Vec<Json> {
+        let mut messages = self.outbox.split_off(0);
+        for m in &mut messages {
+            if let Json::Object(ref mut fields) = *m {
+                self.seq += 1;
+                fields.insert(0, ("seq".to_string(), Json::from(self.seq)));
+            }
+        }
+        messages
+    }
+
+    // 要求を1つ処理し，応答とイベントを返す
+    pub fn handle(&mut self, request: &Json) -> Vec<Json> {
+
+        let command = request.get("command").and_then(|c| c.as_str()).unwrap_or("").to_string();
+        let empty = Json::Object(Vec::new());
+        let args = request.get("arguments").unwrap_or(&empty);
+
+        let result = match command.as_str() {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(Json::Null),
+            "dataBreakpointInfo" => self.data_breakpoint_info(args),
+            "setDataBreakpoints" => self.set_data_breakpoints(args),
+            "configurationDone" => {
+                self.configured = true;
+                Ok(Json::Null)
+            },
+            "threads" => Ok(Json::object(vec![
+                ("threads", Json::Array(vec![Json::object(vec![("id", Json::from(1i64)), ("name", Json::string("COMET2"))])])),
+            ])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Json::object(vec![("scopes", Json::Array(vec![
+                Json::object(vec![("name", Json::string("Registers")), ("presentationHint", Json::string("registers")), ("variablesReference", Json::from(REGISTERS)), ("expensive", Json::from(false))]),
+                Json::object(vec![("name", Json::string("Labels")), ("variablesReference", Json::from(LABELS)), ("expensive", Json::from(false))]),
+            ]))])),
+            "variables" => self.variables(args),
+            "setVariable" => self.set_variable(args),
+            "evaluate" => self.evaluate(args),
+            "source" => self.source(args),
+            "continue" => self.resume(Mode::Continue).map(|_| Json::object(vec![("allThreadsContinued", Json::from(true))])),
+            "next" => self.resume(Mode::Next),
+            "stepIn" => self.resume(Mode::StepIn),
+            "stepOut" => self.resume(Mode::StepOut),
+            "stepBack" => self.resume(Mode::StepBack),
+            "reverseContinue" => self.resume(Mode::ReverseContinue),
+            // 実行中の一時停止は serve が受け取る
+            "pause" => Ok(Json::Null),
+            "disconnect" | "terminate" => {
+                self.quit = true;
+                Ok(Json::Null)
+            },
+            _ => Err(format!("Unknown command `{}`", command)),
+        };
+
+        let mut fields = vec![
+            ("type", Json::string("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Number(0))),
+            ("success", Json::from(result.is_ok())),
+            ("command", Json::String(command)),
+        ];
+
+        match result {
+            Ok(Json::Null) => {},
+            Ok(body) => fields.push(("body", body)),
+            Err(e) => fields.push(("message", Json::String(e))),
+        }
+
+        // イベントは応答の後に送る
+        self.outbox.insert(0, Json::object(fields));
+
+        self.start();
+
+        self.drain()
+    }
+
+    fn debugger(&self) -> Result<&Debugger<Buffer>, String> {
+        self.dbg.as_ref().ok_or_else(|| "The program is not launched".to_string())
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+
+        let paths = match args.get("program") {
+            Some(Json::String(s)) => vec![s.to_string()],
+            Some(Json::Array(v)) => v.iter().filter_map(|p| p.as_str()).map(|p| p.to_string()).collect(),
+            _ => Vec::new(),
+        };
+
+        let libraries = args.get("libraries").and_then(|l| l.as_array()).map_or(Vec::new(), |v| {
+            v.iter().filter_map(|p| p.as_str()).map(|p| p.to_string()).collect()
+        });
+
+        let stdlib = args.get("stdlib").and_then(|b| b.as_bool()).unwrap_or(false);
+        let input = args.get("input").and_then(|s| s.as_str()).unwrap_or("");
+
+        let (code, info, entry) = load(&paths, &libraries, stdlib)?;
+
+        let mut dbg = Debugger::new(Comet2::load_with_console(&code, entry, Buffer::new(input)), info);
+
+        if let Some(n) = args.get("history").and_then(|n| n.as_i64()) {
+            dbg.history.set_limit(n.max(0) as usize);
+        }
+
+        self.dbg = Some(dbg);
+        self.stop_on_entry = args.get("stopOnEntry").and_then(|b| b.as_bool()).unwrap_or(false);
+
+        // 読み込めたのでブレークポイントを受け付ける
+        self.event("initialized", Json::Null);
+
+        Ok(Json::Null)
+    }
+
+    // 読み込みと設定が済んだら実行を始める
+    fn start(&mut self) {
+
+        if self.started || !self.configured || self.dbg.is_none() {
+            return;
+        }
+
+        self.started = true;
+
+        if self.stop_on_entry {
+            self.stopped("entry", None);
+        } else {
+            self.pending = Some(Mode::Continue);
+        }
+    }
+
+    fn resume(&mut self, mode: Mode) -> Result<Json, String> {
+        self.debugger()?;
+        self.pending = Some(mode);
+        Ok(Json::Null)
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) {
+
+        let mut body = vec![
+            ("reason", Json::string(reason)),
+            ("threadId", Json::from(1i64)),
+            ("allThreadsStopped", Json::from(true)),
+        ];
+
+        if let Some(t) = text {
+            body.push(("text", Json::String(t)));
+        }
+
+        self.event("stopped", Json::object(body));
+    }
+
+    // continue や step を実行し，止まったことを知らせるイベントを返す
+    //
+    // interrupted が真を返すとそこで一時停止する
+    pub fn run(&mut self, interrupted: &mut dyn FnMut() -> bool) -> Vec<Json> {
+
+        let mode = match self.pending.take() {
+            Some(m) => m,
+            None => return Vec::new(),
+        };
+
+        let stop = match self.dbg {
+            Some(ref mut dbg) => execute(dbg, mode, interrupted),
+            None => return Vec::new(),
+        };
+
+        let output = self.dbg.as_mut().map_or(String::new(), |d| d.cpu.console.output.split_off(0));
+
+        if !output.is_empty() {
+            self.event("output", Json::object(vec![("category", Json::string("stdout")), ("output", Json::String(output))]));
+        }
+
+        match stop {
+            Stop::Stopped(reason, text) => self.stopped(reason, text),
+            Stop::Exited => {
+                self.event("exited", Json::object(vec![("exitCode", Json::from(0i64))]));
+                self.event("terminated", Json::Null);
+            },
+        }
+
+        self.drain()
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+
+        let path = args.get("source").and_then(|s| s.get("path")).and_then(|p| p.as_str()).unwrap_or("").to_string();
+        let requested = args.get("breakpoints").and_then(|b| b.as_array()).cloned().unwrap_or_default();
+
+        let mut points: Vec<Watchpoint> = Vec::new();
+        let mut results: Vec<Json> = Vec::new();
+
+        for b in &requested {
+
+            let line = b.get("line").and_then(|l| l.as_i64()).unwrap_or(0);
+            let condition = b.get("condition").and_then(|c| c.as_str()).filter(|c| !c.trim().is_empty());
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            let resolved = self.debugger().and_then(|dbg| {
+                let addr = source_file(&dbg.info, &path)
+                    .and_then(|f| dbg.info.address_of(&f, line.max(0) as usize))
+                    .ok_or_else(|| "No code at this line".to_string())?;
+                let spec = match condition {
+                    Some(c) => format!("#{:0>4X} if {}", addr, c),
+                    None => format!("#{:0>4X}", addr),
+                };
+                let w = Watchpoint::parse(Kind::Break, &spec, &dbg.info, &dbg.cpu)?;
+                let line = dbg.info.find(addr).map_or(line, |e| e.line as i64);
+                Ok((w, line))
+            });
+
+            results.push(match resolved {
+                Ok((w, line)) => {
+                    points.push(w);
+                    Json::object(vec![("id", Json::from(id)), ("verified", Json::from(true)), ("line", Json::from(line))])
+                },
+                Err(e) => Json::object(vec![("id", Json::from(id)), ("verified", Json::from(false)), ("line", Json::from(line)), ("message", Json::String(e))]),
+            });
+        }
+
+        self.sources.retain(|(p, _)| *p != path);
+        self.sources.push((path, points));
+        self.sync();
+
+        Ok(Json::object(vec![("breakpoints", Json::Array(results))]))
+    }
+
+    // ラベルのついたメモリだけを監視できる
+    fn data_breakpoint_info(&self, args: &Json) -> Result<Json, String> {
+
+        let dbg = self.debugger()?;
+        let name = args.get("name").and_then(|n| n.as_str()).unwrap_or("");
+        let reference = args.get("variablesReference").and_then(|r| r.as_i64());
+
+        let addr = match reference {
+            Some(REGISTERS) => None,
+            _ => dbg.info.labels.get(name.trim_start_matches('[').trim_end_matches(']')).cloned(),
+        };
+
+        Ok(match addr {
+            Some(addr) => Json::object(vec![
+                ("dataId", Json::String(format!("#{:0>4X}", addr))),
+                ("description", Json::String(format!("{} (#{:0>4X})", name, addr))),
+                ("accessTypes", Json::Array(vec![Json::string("write"), Json::string("read"), Json::string("readWrite")])),
+            ]),
+            None => Json::object(vec![
+                ("dataId", Json::Null),
+                ("description", Json::string("Only labelled memory can be watched")),
+            ]),
+        })
+    }
+
+    fn set_data_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+
+        let requested = args.get("breakpoints").and_then(|b| b.as_array()).cloned().unwrap_or_default();
+
+        let mut points: Vec<Watchpoint> = Vec::new();
+        let mut results: Vec<Json> = Vec::new();
+
+        for b in &requested {
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            let kind = match b.get("accessType").and_then(|a| a.as_str()) {
+                Some("read") => Kind::Read,
+                Some("readWrite") => Kind::Access,
+                _ => Kind::Write,
+            };
+
+            let spec = match (b.get("dataId").and_then(|d| d.as_str()), b.get("condition").and_then(|c| c.as_str())) {
+                (Some(loc), Some(c)) if !c.trim().is_empty() => format!("{} if {}", loc, c),
+                (Some(loc), _) => loc.to_string(),
+                (None, _) => String::new(),
+            };
+
+            let parsed = self.debugger().and_then(|dbg| Watchpoint::parse(kind, &spec, &dbg.info, &dbg.cpu));
+
+            results.push(match parsed {
+                Ok(w) => {
+                    points.push(w);
+                    Json::object(vec![("id", Json::from(id)), ("verified", Json::from(true))])
+                },
+                Err(e) => Json::object(vec![("id", Json::from(id)), ("verified", Json::from(false)), ("message", Json::String(e))]),
+            });
+        }
+
+        self.data = points;
+        self.sync();
+
+        Ok(Json::object(vec![("breakpoints", Json::Array(results))]))
+    }
+
+    // Debugger のブレークポイントを置き直す
+    fn sync(&mut self) {
+        if let Some(ref mut dbg) = self.dbg {
+            dbg.breakpoints = self.sources.iter().flat_map(|(_, v)| v.iter().cloned()).chain(self.data.iter().cloned()).collect();
+        }
+    }
+
+    fn stack_trace(&self) -> Result<Json, String> {
+
+        let dbg = self.debugger()?;
+
+        let frames = if dbg.halted { Vec::new() } else { dbg.backtrace() };
+
+        let frames = frames
+            .iter()
+            .enumerate()
+            .map(|(i, (name, pc))| {
+                let mut fields = vec![
+                    ("id", Json::from(i)),
+                    ("name", Json::String(format!("{} (#{:0>4X})", name, pc))),
+                    ("instructionPointerReference", Json::String(format!("#{:0>4X}", pc))),
+                ];
+                match dbg.info.find(*pc) {
+                    Some(e) => {
+                        fields.push(("source", source_json(&dbg.info, &e.file)));
+                        fields.push(("line", Json::from(e.line)));
+                        fields.push(("column", Json::from(1i64)));
+                    },
+                    None => {
+                        fields.push(("line", Json::from(0i64)));
+                        fields.push(("column", Json::from(0i64)));
+                    },
+                }
+                Json::object(fields)
+            })
+            .collect::<Vec<Json>>();
+
+        Ok(Json::object(vec![("totalFrames", Json::from(frames.len())), ("stackFrames", Json::Array(frames))]))
+    }
+
+    fn variables(&self, args: &Json) -> Result<Json, String> {
+
+        let dbg = self.debugger()?;
+        let cpu = &dbg.cpu;
+
+        let variable = |name: &str, value: String, evaluate: Option<String>, kind: Option<String>| {
+            let mut fields = vec![("name", Json::string(name)), ("value", Json::String(value)), ("variablesReference", Json::from(0i64))];
+            if let Some(e) = evaluate {
+                fields.push(("evaluateName", Json::String(e)));
+            }
+            if let Some(k) = kind {
+                fields.push(("type", Json::String(k)));
+            }
+            Json::object(fields)
+        };
+
+        let variables = match args.get("variablesReference").and_then(|r| r.as_i64()) {
+            Some(REGISTERS) => {
+                let mut v = (0..8).map(|i| variable(&format!("GR{}", i), word(cpu.gr[i]), Some(format!("GR{}", i)), None)).collect::<Vec<Json>>();
+                v.push(variable("SP", word(cpu.sp), Some("SP".to_string()), None));
+                v.push(variable("PR", word(cpu.pr), Some("PR".to_string()), None));
+                v.push(variable("FR", format!("OF={} SF={} ZF={}", cpu.of as u8, cpu.sf as u8, cpu.zf as u8), None, None));
+                v
+            },
+            Some(LABELS) => {
+                let mut labels = dbg.info.labels.iter().collect::<Vec<(&String,&u16)>>();
+                labels.sort_by_key(|(name, addr)| (**addr, name.to_string()));
+                labels
+                    .iter()
+                    .map(|(name, addr)| variable(name, word(cpu.read(**addr)), Some(format!("[{}]", name)), Some(format!("#{:0>4X}", addr))))
+                    .collect()
+            },
+            _ => Vec::new(),
+        };
+
+        Ok(Json::object(vec![("variables", Json::Array(variables))]))
+    }
+
+    fn set_variable(&mut self, args: &Json) -> Result<Json, String> {
+
+        let reference = args.get("variablesReference").and_then(|r| r.as_i64());
+        let name = args.get("name").and_then(|n| n.as_str()).unwrap_or("").to_string();
+        let value = args.get("value").and_then(|v| v.as_str()).unwrap_or("").to_string();
+
+        let dbg = self.dbg.as_mut().ok_or_else(|| "The program is not launched".to_string())?;
+        let v = Expr::parse(&value, &dbg.info.labels)?.eval(&dbg.cpu);
+
+        match (reference, name.as_str()) {
+            (Some(REGISTERS), "SP") => dbg.cpu.sp = v,
+            (Some(REGISTERS), "PR") => dbg.cpu.pr = v,
+            (Some(REGISTERS), r) if Register::parse(r).is_some() => {
+                dbg.cpu.gr[Register::parse(r).unwrap().number() as usize] = v;
+            },
+            (Some(LABELS), label) => match dbg.info.labels.get(label) {
+                Some(addr) => dbg.cpu.memory[*addr as usize] = v,
+                None => return Err(format!("Unknown label: `{}`", label)),
+            },
+            _ => return Err(format!("`{}` cannot be set", name)),
+        }
+
+        Ok(Json::object(vec![("value", Json::String(word(v)))]))
+    }
+
+    // ホバーではラベルの番地と内容を表示する
+    fn evaluate(&self, args: &Json) -> Result<Json, String> {
+
+        let dbg = self.debugger()?;
+        let expression = args.get("expression").and_then(|e| e.as_str()).unwrap_or("").trim();
+        let context = args.get("context").and_then(|c| c.as_str()).unwrap_or("");
+
+        let result = match dbg.info.labels.get(expression) {
+            Some(addr) if context == "hover" => format!("#{:0>4X}: {}", addr, word(dbg.cpu.read(*addr))),
+            _ => word(Expr::parse(expression, &dbg.info.labels)?.eval(&dbg.cpu)),
+        };
+
+        Ok(Json::object(vec![("result", Json::String(result)), ("variablesReference", Json::from(0i64))]))
+    }
+
+    // 標準サブルーチンのソース
+    fn source(&self, args: &Json) -> Result<Json, String> {
+
+        let dbg = self.debugger()?;
+        let reference = args.get("sourceReference").and_then(|r| r.as_i64()).unwrap_or(0);
+
+        let content = files(&dbg.info)
+            .get((reference - 1).max(0) as usize)
+            .and_then(|f| f.strip_prefix("stdlib:"))
+            .and_then(stdlib::source)
+            .ok_or_else(|| format!("No source for reference {}", reference))?;
+
+        Ok(Json::object(vec![("content", Json::string(content)), ("mimeType", Json::string("text/x-casl2"))]))
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("supportsConfigurationDoneRequest", Json::from(true)),
+        ("supportsConditionalBreakpoints", Json::from(true)),
+        ("supportsDataBreakpoints", Json::from(true)),
+        ("supportsEvaluateForHovers", Json::from(true)),
+        ("supportsSetVariable", Json::from(true)),
+        ("supportsStepBack", Json::from(true)),
+        ("supportsTerminateRequest", Json::from(true)),
+    ])
+}
+
+fn word(v: u16) -> String {
+    format!("#{:0>4X} {}", v, v as i16)
+}
+
+// 行番号表に出てくるファイル (最初に出てきた順)
+fn files(info: &DebugInfo) -> Vec<String> {
+    let mut files: Vec<String> = Vec::new();
+    for e in &info.lines {
+        if !files.contains(&e.file) {
+            files.push(e.file.to_string());
+        }
+    }
+    files
+}
+
+// エディタから渡されたパスを，行番号表のファイル名にする
+fn source_file(info: &DebugInfo, path: &str) -> Option<String> {
+
+    let canonical = fs::canonicalize(path).ok();
+
+    files(info).into_iter().find(|f| {
+        f == path || path.ends_with(&format!("/{}", f)) || (canonical.is_some() && fs::canonicalize(f).ok() == canonical)
+    })
+}
+
+// ファイルがなければ (標準サブルーチン) source 要求で中身を渡す
+fn source_json(info: &DebugInfo, file: &str) -> Json {
+
+    if Path::new(file).exists() {
+        let path = fs::canonicalize(file).map_or(file.to_string(), |p| p.to_string_lossy().to_string());
+        let name = Path::new(file).file_name().map_or(file.to_string(), |n| n.to_string_lossy().to_string());
+        return Json::object(vec![("name", Json::String(name)), ("path", Json::String(path))]);
+    }
+
+    let reference = files(info).iter().position(|f| f == file).map_or(0, |i| i + 1);
+
+    Json::object(vec![
+        ("name", Json::string(file)),
+        ("sourceReference", Json::from(reference)),
+        ("presentationHint", Json::string("deemphasize")),
+    ])
+}
+
+// 止まるまで実行する (戻る)
+fn execute(dbg: &mut Debugger<Buffer>, mode: Mode, interrupted: &mut dyn FnMut() -> bool) -> Stop {
+
+    let line = |dbg: &Debugger<Buffer>| dbg.info.find(dbg.cpu.pr).map(|e| (e.file.to_string(), e.line));
+
+    let start = line(dbg);
+    let depth = dbg.frames.len();
+    let mut first = true;
+
+    loop {
+
+        // 今いるブレークポイントでは止まらない
+        if !first {
+            if dbg.at_breakpoint() {
+                return Stop::Stopped("breakpoint", None);
+            }
+            if dbg.cpu.steps.is_multiple_of(POLL_INTERVAL) && interrupted() {
+                return Stop::Stopped("pause", None);
+            }
+        }
+        first = false;
+
+        if mode == Mode::StepBack || mode == Mode::ReverseContinue {
+            if dbg.undo().is_err() {
+                return Stop::Stopped("step", Some("Reached the start of history".to_string()));
+            }
+        } else {
+            match dbg.step_one() {
+                Err(e) => return Stop::Stopped("exception", Some(e)),
+                Ok(_) if dbg.halted => return Stop::Exited,
+                Ok(Some(s)) => return Stop::Stopped("data breakpoint", Some(s)),
+                Ok(None) => {},
+            }
+        }
+
+        // 行番号がなければ1命令ずつ
+        let moved = start.is_none() || line(dbg) != start;
+
+        let done = match mode {
+            Mode::Continue | Mode::ReverseContinue => false,
+            Mode::StepIn | Mode::StepBack => moved,
+            Mode::Next => moved && dbg.frames.len() <= depth,
+            Mode::StepOut => dbg.frames.len() < depth,
+        };
+
+        if done {
+            return Stop::Stopped("step", None);
+        }
+    }
+}
+
+// ソース (*.casl2) かオブジェクト (*.o) を読み込み，アーカイブと標準サブルーチンをリンクする
+pub fn load(paths: &[String], libraries: &[String], stdlib: bool) -> Result<(Vec<u16>, DebugInfo, u16), String> {
+
+    if paths.is_empty() {
+        return Err("No program to debug".to_string());
+    }
+
+    let mut objects: Vec<Object> = Vec::new();
+
+    for path in paths {
+
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+        if path.ends_with(".o") {
+            objects.push(Object::parse(&text).map_err(|e| format!("{}: {}", path, e))?);
+            continue;
+        }
+
+        match assemble(&text) {
+            Ok(objs) => {
+                for mut obj in objs {
+                    obj.file = path.to_string();
+                    objects.push(obj);
+                }
+            },
+            Err(errors) => return Err(errors.iter().map(|e| format!("{}:{}", path, e)).collect::<Vec<String>>().join("\n")),
+        }
+    }
+
+    let mut archives: Vec<Archive> = Vec::new();
+
+    for path in libraries {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        archives.push(Archive::parse(&text).map_err(|e| format!("{}: {}", path, e))?);
+    }
+
+    if stdlib {
+        archives.push(stdlib::archive());
+    }
+
+    resolve_members(&mut objects, &archives);
+
+    let code = link(&objects)?;
+    let entry = objects[0].defs.first().map_or(0, |(_, addr)| *addr);
+
+    Ok((code, DebugInfo::link(&objects), entry))
+}
+
+// Content-Length のヘッダに続く本文を1つ読む．入力が終わればNone
+pub fn read_message<R: BufRead>(r: &mut R) -> io::Result<Option<String>> {
+
+    let mut length: Option<usize> = None;
+
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0u8; length.unwrap()];
+    r.read_exact(&mut body)?;
+
+    Ok(Some(String::from_utf8_lossy(&body).to_string()))
+}
+
+pub fn write_message<W: Write>(w: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(w, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    w.flush()
+}
+
+// 標準入出力でやりとりする．要求は別のスレッドで読み，実行中は pause が来ていないか確かめる
+pub fn serve() -> io::Result<()> {
+
+    let (tx, rx) = mpsc::channel::<Json>();
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        while let Ok(Some(body)) = read_message(&mut input) {
+            if let Ok(m) = Json::parse(&body) {
+                if tx.send(m).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    let stdout = io::stdout();
+    let mut adapter = Adapter::new();
+    let mut backlog: VecDeque<Json> = VecDeque::new();
+
+    while !adapter.quit {
+
+        let request = match backlog.pop_front() {
+            Some(r) => r,
+            None => match rx.recv() {
+                Ok(r) => r,
+                Err(_) => break,
+            },
+        };
+
+        for m in adapter.handle(&request) {
+            write_message(&mut stdout.lock(), &m)?;
+        }
+
+        let messages = {
+            let mut interrupted = || {
+                let mut pause = false;
+                while let Ok(m) = rx.try_recv() {
+                    pause |= m.get("command").and_then(|c| c.as_str()) == Some("pause");
+                    backlog.push_back(m);
+                }
+                pause
+            };
+            adapter.run(&mut interrupted)
+        };
+
+        for m in messages {
+            write_message(&mut stdout.lock(), &m)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_dap() {
+
+    use debuginfo::LineEntry;
+    use std::io::Cursor;
+
+    let src = "\
+MAIN     START
+         LAD     GR1,2
+LOOP     CALL    DOUBLE
+         SUBA    GR1,=1
+         JNZ     LOOP
+         OUT     BUF,LEN
+         RET
+DOUBLE   ADDA    GR2,GR2
+         LAD     GR2,1,GR2
+         ST      GR2,ANS
+         RET
+ANS      DS      1
+BUF      DC      'ok'
+LEN      DC      2
+         END
+";
+
+    let mut objects = assemble(src).unwrap();
+    objects[0].file = "dbl.casl2".to_string();
+    let info = DebugInfo::link(&objects);
+    assert!(info.lines.contains(&LineEntry{addr: 0x15, file: "dbl.casl2".to_string(), line: 8}));
+
+    let cpu = Comet2::load_with_console(&link(&objects).unwrap(), 0, Buffer::new(""));
+
+    let mut adapter = Adapter::new();
+    let mut seq = 0;
+    let mut never = || false;
+
+    let mut request = |adapter: &mut Adapter, command: &str, args: &str| {
+        seq += 1;
+        let r = Json::parse(&format!(r#"{{"seq":{},"type":"request","command":"{}","arguments":{}}}"#, seq, command, args)).unwrap();
+        let mut messages = adapter.handle(&r);
+        messages.extend(adapter.run(&mut never));
+        messages
+    };
+
+    let events = |messages: &[Json]| messages
+        .iter()
+        .filter_map(|m| m.get("event").and_then(|e| e.as_str()).map(|e| e.to_string()))
+        .collect::<Vec<String>>();
+
+    let body = |messages: &[Json], key: &str| messages[0].get("body").and_then(|b| b.get(key)).cloned().unwrap();
+
+    let m = request(&mut adapter, "initialize", "{}");
+    assert_eq!(body(&m, "supportsStepBack"), Json::Bool(true));
+
+    // 起動していなければ確かめられない
+    let m = request(&mut adapter, "setBreakpoints", r#"{"source":{"path":"dbl.casl2"},"breakpoints":[{"line":9}]}"#);
+    assert_eq!(body(&m, "breakpoints").as_array().unwrap()[0].get("verified"), Some(&Json::Bool(false)));
+
+    adapter.dbg = Some(Debugger::new(cpu, info));
+    adapter.stop_on_entry = true;
+
+    let m = request(&mut adapter, "configurationDone", "{}");
+    assert_eq!(events(&m), vec!["stopped"]);
+    assert_eq!(m[1].get("body").and_then(|b| b.get("reason")), Some(&Json::string("entry")));
+
+    let m = request(&mut adapter, "setBreakpoints", r#"{"source":{"path":"/work/dbl.casl2"},"breakpoints":[{"line":9},{"line":20},{"line":11,"condition":"GR1 =="}]}"#);
+    let results = body(&m, "breakpoints");
+    let results = results.as_array().unwrap();
+    assert_eq!((results[0].get("verified"), results[0].get("line")), (Some(&Json::Bool(true)), Some(&Json::Number(9))));
+    assert_eq!(results[1].get("verified"), Some(&Json::Bool(false)));
+    assert_eq!(results[2].get("verified"), Some(&Json::Bool(false)));
+
+    let m = request(&mut adapter, "continue", "{}");
+    assert_eq!(events(&m), vec!["stopped"]);
+    assert_eq!(adapter.dbg.as_ref().unwrap().cpu.pr, 0x16);
+
+    let m = request(&mut adapter, "stackTrace", r#"{"threadId":1}"#);
+    let frames = body(&m, "stackFrames");
+    let frames = frames.as_array().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].get("name"), Some(&Json::string("DOUBLE (#0016)")));
+    assert_eq!((frames[1].get("name"), frames[1].get("line")), (Some(&Json::string("MAIN (#0002)")), Some(&Json::Number(3))));
+
+    let m = request(&mut adapter, "variables", r#"{"variablesReference":1}"#);
+    assert_eq!(body(&m, "variables").as_array().unwrap()[1].get("value"), Some(&Json::string("#0002 2")));
+
+    // ラベルの書き込みで止める
+    let m = request(&mut adapter, "dataBreakpointInfo", r#"{"variablesReference":2,"name":"ANS"}"#);
+    let id = body(&m, "dataId");
+    assert_eq!(id, Json::string("#001B"));
+
+    request(&mut adapter, "setBreakpoints", r#"{"source":{"path":"/work/dbl.casl2"},"breakpoints":[]}"#);
+    request(&mut adapter, "setDataBreakpoints", &format!(r#"{{"breakpoints":[{{"dataId":{},"accessType":"write"}}]}}"#, id));
+
+    let m = request(&mut adapter, "continue", "{}");
+    assert_eq!(m[1].get("body").and_then(|b| b.get("reason")), Some(&Json::string("data breakpoint")));
+
+    let m = request(&mut adapter, "evaluate", r#"{"expression":"ANS","context":"hover"}"#);
+    assert_eq!(body(&m, "result"), Json::string("#001B: #0001 1"));
+
+    // 1行戻って，次の行へ
+    request(&mut adapter, "stepBack", "{}");
+    assert_eq!(adapter.dbg.as_ref().unwrap().cpu.pr, 0x18);
+    request(&mut adapter, "setDataBreakpoints", r#"{"breakpoints":[]}"#);
+    request(&mut adapter, "next", "{}");
+    assert_eq!(adapter.dbg.as_ref().unwrap().cpu.pr, 0x1a);
+
+    request(&mut adapter, "stepOut", "{}");
+    assert_eq!(adapter.dbg.as_ref().unwrap().cpu.pr, 4);
+
+    let m = request(&mut adapter, "continue", "{}");
+    assert_eq!(events(&m), vec!["output", "exited", "terminated"]);
+    assert_eq!(m[1].get("body").and_then(|b| b.get("output")), Some(&Json::string("ok\n")));
+
+    request(&mut adapter, "disconnect", "{}");
+    assert!(adapter.quit);
+
+    let mut input = Cursor::new(b"Content-Length: 2\r\n\r\n{}\r\nContent-Length: 3\r\n\r\n[1]".to_vec());
+    assert_eq!(read_message(&mut input).unwrap(), Some("{}".to_string()));
+    assert_eq!(read_message(&mut input).unwrap(), Some("[1]".to_string()));
+    assert_eq!(read_message(&mut input).unwrap(), None);
+
+    let mut out: Vec<u8> = Vec::new();
+    write_message(&mut out, &Json::object(vec![("a", Json::from(1i64))])).unwrap();
+    assert_eq!(out, b"Content-Length: 7\r\n\r\n{\"a\":1}");
+}
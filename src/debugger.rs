@@ -0,0 +1,733 @@
+use std::collections::HashMap;
+
+use comet2::{Comet2,State};
+use console::Console;
+use debuginfo::DebugInfo;
+use disasm::{decode,format_operands};
+use expr::Expr;
+use history::{History,Snapshot,DEFAULT_LIMIT};
+use register::Register;
+use watch::{Kind,Watchpoint};
+
+pub const HELP: &str = "\
+break LOC       (b)  LOCにブレークポイントを置く．LOCを省くと一覧を表示する
+break if EXPR        式が真になったところで止める
+watch LOC            LOCに書き込んだら止める
+rwatch LOC           LOCを読み出したら止める
+awatch LOC           LOCを読み書きしたら止める
+delete [N]      (d)  N番目のブレークポイントを消す．Nを省くと全て消す
+print EXPR      (p)  式の値を表示する
+step [N]        (s)  N命令実行する
+next            (n)  1命令実行する．CALLは戻ってくるまで実行する
+continue        (c)  ブレークポイントか終了まで実行する
+reverse-step [N]     (rs) N命令戻る
+reverse-continue     (rc) ブレークポイントか，記録の最初まで戻る
+reverse-write LOC    (rw) LOCに最後に書き込んだ命令 (の実行前) まで戻る
+goto N               N命令実行した時点に移る
+history [N]          記録している命令数を表示する．Nで記録する上限を変える
+regs            (r)  レジスタとフラグを表示する
+backtrace       (bt) CALLで呼ばれているサブルーチンを内側から表示する
+x/NF LOC             LOCからN語を表示する．Fは x (16進), d (符号付き), u (符号なし), c (文字)
+list [LOC]      (l)  LOC (省くとPR) から命令を表示する
+quit            (q)  終了する
+
+LOC はラベル，ラベル+数，#16進数，10進数，GR0〜GR7 (その値の番地) で書く．
+デバッグ情報があれば ファイル:行 や :行 (最初のファイル) でソースの行も指定できる．
+break, watch, rwatch, awatch の LOC のあとに「if 式」を書くと，式が真のときだけ止まる．
+式には GR0〜GR7，SP，PR，OF，SF，ZF，ラベル，数，[番地] (メモリの内容) と
+|| && | ^ & == != < <= > >= + - ! ~ が使える (値は16ビットの符号なし整数)．
+戻れるのは記録している分だけで，IN, OUT の入出力は取り消せない．
+空行は直前のコマンドを繰り返す．";
+
+// CALLで積んだフレーム
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Frame {
+    // CALL命令の番地
+    pub site: u16,
+    // 呼び出し先
+    pub entry: u16,
+    // 戻り番地を積んだ番地
+    pub sp: u16,
+}
+
+pub struct Debugger<C: Console> {
+    pub cpu: Comet2<C>,
+    pub info: DebugInfo,
+    pub breakpoints: Vec<Watchpoint>,
+    pub history: History,
+    // 外側から順
+    pub frames: Vec<Frame>,
+    pub halted: bool,
+    pub quit: bool,
+    // 番地からラベル名を引く
+    names: HashMap<u16,String>,
+    last: String,
+}
+
+impl<C: Console> Debugger<C> {
+
+    pub fn new(cpu: Comet2<C>, info: DebugInfo) -> Debugger<C> {
+
+        let mut sorted: Vec<(&String,&u16)> = info.labels.iter().collect();
+        sorted.sort();
+
+        let mut names: HashMap<u16,String> = HashMap::new();
+        for (name, addr) in sorted {
+            names.entry(*addr).or_insert_with(|| name.to_string());
+        }
+
+        Debugger{
+            cpu,
+            info,
+            breakpoints: Vec::new(),
+            history: History::new(DEFAULT_LIMIT),
+            frames: Vec::new(),
+            halted: false,
+            quit: false,
+            names,
+            last: String::new(),
+        }
+    }
+
+    // コマンドを1行実行し，表示する内容を返す
+    pub fn execute(&mut self, line: &str) -> String {
+
+        let line = if line.trim().is_empty() {
+            self.last.to_string()
+        } else {
+            line.trim().to_string()
+        };
+
+        self.last = line.to_string();
+
+        let mut args = line.split_whitespace();
+        let command = args.next().unwrap_or("");
+        let args = args.collect::<Vec<&str>>();
+        let rest = line[command.len()..].trim();
+
+        let result = match command {
+            "" => Ok(String::new()),
+            "b" | "break" => self.command_break(rest),
+            "watch" => self.command_watch(Kind::Write, rest),
+            "rwatch" => self.command_watch(Kind::Read, rest),
+            "awatch" => self.command_watch(Kind::Access, rest),
+            "d" | "delete" => self.command_delete(&args),
+            "p" | "print" => self.command_print(rest),
+            "s" | "step" => self.command_step(&args),
+            "n" | "next" => self.command_next(),
+            "c" | "continue" => self.command_continue(),
+            "rs" | "reverse-step" => self.command_reverse_step(&args),
+            "rc" | "reverse-continue" => self.command_reverse_continue(),
+            "rw" | "reverse-write" => self.command_reverse_write(&args),
+            "goto" => self.command_goto(&args),
+            "history" => self.command_history(&args),
+            "r" | "regs" => Ok(self.registers()),
+            "bt" | "backtrace" => Ok(self.command_backtrace()),
+            "l" | "list" => self.command_list(&args),
+            "q" | "quit" => {
+                self.quit = true;
+                Ok(String::new())
+            },
+            "h" | "help" => Ok(HELP.to_string()),
+            _ if command.starts_with("x") => self.command_examine(&command[1..], &args),
+            _ => Err(format!("Unknown command `{}` (type `help`)", command)),
+        };
+
+        match result {
+            Ok(s) => s,
+            Err(e) => e,
+        }
+    }
+
+    // レジスタならその値，それ以外はデバッグ情報で番地にする
+    pub fn address(&self, s: &str) -> Result<u16, String> {
+        match Register::parse(s) {
+            Some(r) => Ok(self.cpu.gr[r.number() as usize]),
+            None => self.info.resolve(s),
+        }
+    }
+
+    fn location(&self, addr: u16) -> String {
+        match self.names.get(&addr) {
+            Some(name) => format!("#{:0>4X} <{}>", addr, name),
+            None => format!("#{:0>4X}", addr),
+        }
+    }
+
+    // 番地の命令を1行で表示する
+    pub fn instruction(&self, addr: u16) -> (String, u16) {
+
+        let words = [self.cpu.read(addr), self.cpu.read(addr.wrapping_add(1))];
+
+        match decode(&words) {
+            Some(d) => (format!("{:<16} {:<7} {}", self.location(addr) + ":", d.inst.mnemonic, format_operands(&d, &self.names)).trim_end().to_string(), d.words()),
+            None => (format!("{:<16} DC      #{:0>4X}", self.location(addr) + ":", words[0]), 1),
+        }
+    }
+
+    pub fn registers(&self) -> String {
+
+        let mut s = String::new();
+
+        for (i, v) in self.cpu.gr.iter().enumerate() {
+            s.push_str(&format!("GR{} #{:0>4X} {:>6} {:>5}", i, v, *v as i16, v));
+            s.push_str(if i % 2 == 0 { "    " } else { "\n" });
+        }
+
+        s.push_str(&format!("SP  #{:0>4X}    PR  {}\n", self.cpu.sp, self.location(self.cpu.pr)));
+        s.push_str(&format!("OF={} SF={} ZF={}", self.cpu.of as u8, self.cpu.sf as u8, self.cpu.zf as u8));
+
+        s
+    }
+
+    fn command_break(&mut self, rest: &str) -> Result<String, String> {
+
+        if rest.is_empty() {
+            if self.breakpoints.is_empty() {
+                return Ok("No breakpoints".to_string());
+            }
+            let lines = self.breakpoints
+                .iter()
+                .enumerate()
+                .map(|(i, w)| format!("{}: {}", i + 1, self.describe(w)))
+                .collect::<Vec<String>>();
+            return Ok(lines.join("\n"));
+        }
+
+        let w = match rest.strip_prefix("if ") {
+            Some(e) => Watchpoint::parse(Kind::When, e, &self.info, &self.cpu)?,
+            None => Watchpoint::parse(Kind::Break, &self.address_spec(rest)?, &self.info, &self.cpu)?,
+        };
+
+        Ok(self.add(w))
+    }
+
+    fn command_watch(&mut self, kind: Kind, rest: &str) -> Result<String, String> {
+
+        if rest.is_empty() {
+            return Err("Usage: watch LOC [if EXPR]".to_string());
+        }
+
+        let w = Watchpoint::parse(kind, &self.address_spec(rest)?, &self.info, &self.cpu)?;
+
+        Ok(self.add(w))
+    }
+
+    // LOC がレジスタならその値の番地に置き換える
+    fn address_spec(&self, rest: &str) -> Result<String, String> {
+        let (loc, cond) = match rest.find(" if ") {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        };
+        match Register::parse(loc) {
+            Some(_) => Ok(format!("#{:0>4X}{}", self.address(loc)?, cond)),
+            None => Ok(rest.to_string()),
+        }
+    }
+
+    // 同じものがあれば置かずにその番号を返す
+    fn add(&mut self, w: Watchpoint) -> String {
+
+        let s = self.describe(&w);
+        let kind = w.kind;
+
+        let n = match self.breakpoints.iter().position(|b| b.kind == w.kind && b.spec == w.spec) {
+            Some(i) => i + 1,
+            None => {
+                self.breakpoints.push(w);
+                self.breakpoints.len()
+            },
+        };
+
+        match kind {
+            Kind::Break => format!("Breakpoint {} at {}", n, s),
+            _ => format!("Watchpoint {}: {}", n, s),
+        }
+    }
+
+    fn describe(&self, w: &Watchpoint) -> String {
+
+        let cond = w.condition.as_ref().map_or(String::new(), |_| match w.spec.find(" if ") {
+            Some(i) => w.spec[i..].to_string(),
+            None => String::new(),
+        });
+
+        match w.kind {
+            Kind::Break => format!("{}{}", self.location(w.addr), cond),
+            Kind::When => format!("if {}", w.spec),
+            Kind::Write => format!("write {}{}", self.location(w.addr), cond),
+            Kind::Read => format!("read {}{}", self.location(w.addr), cond),
+            Kind::Access => format!("access {}{}", self.location(w.addr), cond),
+        }
+    }
+
+    fn command_print(&mut self, rest: &str) -> Result<String, String> {
+        let v = Expr::parse(rest, &self.info.labels)?.eval(&self.cpu);
+        Ok(format!("#{:0>4X} {} {}", v, v as i16, v))
+    }
+
+    pub fn at_breakpoint(&self) -> bool {
+        self.breakpoints.iter().any(|w| w.before(&self.cpu))
+    }
+
+    fn command_delete(&mut self, args: &[&str]) -> Result<String, String> {
+
+        match args.first() {
+            None => {
+                self.breakpoints.clear();
+                Ok("Deleted all breakpoints".to_string())
+            },
+            Some(n) => match n.parse::<usize>() {
+                Ok(n) if 0 < n && n <= self.breakpoints.len() => {
+                    let w = self.breakpoints.remove(n - 1);
+                    Ok(format!("Deleted breakpoint at {}", self.describe(&w)))
+                },
+                _ => Err(format!("No breakpoint number {}", n)),
+            },
+        }
+    }
+
+    // 1命令実行して記録し，フレームを積み降ろしする
+    fn advance(&mut self) -> Result<State, String> {
+
+        let before = Snapshot::of(&self.cpu);
+        let is_call = self.mnemonic(before.pr) == Some("CALL");
+
+        let state = self.cpu.step()?;
+
+        if state == State::Halted {
+            self.halted = true;
+        }
+
+        if is_call {
+            self.frames.push(Frame{site: before.pr, entry: self.cpu.pr, sp: self.cpu.sp});
+        }
+        let dropped = self.drop_frames();
+
+        self.history.record(before, &self.cpu, dropped);
+
+        Ok(state)
+    }
+
+    // スタックから降ろされたフレームを除いて返す (SPが0ならスタックは空)
+    fn drop_frames(&mut self) -> Vec<Frame> {
+        let top = if self.cpu.sp == 0 { 0x10000 } else { self.cpu.sp as u32 };
+        let kept = self.frames.iter().take_while(|f| f.sp as u32 >= top).count();
+        self.frames.split_off(kept)
+    }
+
+    fn mnemonic(&self, addr: u16) -> Option<&'static str> {
+        decode(&[self.cpu.read(addr), self.cpu.read(addr.wrapping_add(1))]).map(|d| d.inst.mnemonic)
+    }
+
+    // 1命令実行する．終了したかウォッチポイントで止まったら，表示する内容を返す
+    pub fn step_one(&mut self) -> Result<Option<String>, String> {
+
+        if self.halted {
+            return Err("The program has halted".to_string());
+        }
+
+        if self.advance()? == State::Halted {
+            return Ok(Some(self.current()));
+        }
+
+        // 条件の直前の値を更新するため，全てに知らせる
+        let mut hit: Option<String> = None;
+        for (i, w) in self.breakpoints.iter_mut().enumerate() {
+            if let Some(s) = w.after(&self.cpu) {
+                hit = hit.or(Some(s.replacen(':', &format!(" {}:", i + 1), 1)));
+            }
+        }
+
+        Ok(hit.map(|s| format!("{}\n{}", s, self.current())))
+    }
+
+    // 止まった場所を，デバッグ情報があればソースの位置とともに表示する
+    pub fn current(&self) -> String {
+        if self.halted {
+            return format!("Halted after {} steps", self.cpu.steps);
+        }
+
+        let inst = self.instruction(self.cpu.pr).0;
+
+        match self.info.location(self.cpu.pr) {
+            Some(loc) => format!("{:<40} ({})", inst, loc),
+            None => inst,
+        }
+    }
+
+    fn command_step(&mut self, args: &[&str]) -> Result<String, String> {
+
+        let n = match args.first() {
+            Some(n) => n.parse::<u64>().map_err(|_| format!("Invalid count: `{}`", n))?,
+            None => 1,
+        };
+
+        for _ in 0..n {
+            if let Some(s) = self.step_one()? {
+                return Ok(s);
+            }
+        }
+
+        Ok(self.current())
+    }
+
+    fn command_next(&mut self) -> Result<String, String> {
+
+        let pr = self.cpu.pr;
+        let sp = self.cpu.sp;
+
+        // CALLなら，次の命令に戻ってスタックが元に戻るまで進める
+        let is_call = decode(&[self.cpu.read(pr), 0]).is_some_and(|d| d.inst.mnemonic == "CALL");
+
+        if let Some(s) = self.step_one()? {
+            return Ok(s);
+        }
+
+        if !is_call {
+            return Ok(self.current());
+        }
+
+        let next = pr.wrapping_add(2);
+
+        while !(self.cpu.pr == next && self.cpu.sp == sp) {
+            if self.at_breakpoint() {
+                return Ok(format!("Breakpoint at {}", self.current()));
+            }
+            if let Some(s) = self.step_one()? {
+                return Ok(s);
+            }
+        }
+
+        Ok(self.current())
+    }
+
+    fn command_continue(&mut self) -> Result<String, String> {
+
+        // 今いるブレークポイントで止まらないよう，1命令は必ず進める
+        if let Some(s) = self.step_one()? {
+            return Ok(s);
+        }
+
+        loop {
+            if self.at_breakpoint() {
+                return Ok(format!("Breakpoint at {}", self.current()));
+            }
+            if let Some(s) = self.step_one()? {
+                return Ok(s);
+            }
+        }
+    }
+
+    // 1命令戻る．戻れなければエラー
+    //
+    // RETなどを取り消したときは，その命令で降ろしたフレームを履歴から積み直す
+    pub fn undo(&mut self) -> Result<(), String> {
+
+        let delta = match self.history.undo(&mut self.cpu) {
+            Some(d) => d,
+            None => return Err(format!("No more history (at step {})", self.cpu.steps)),
+        };
+
+        self.halted = false;
+
+        self.drop_frames();
+        self.frames.extend(delta.frames);
+
+        Ok(())
+    }
+
+    // 内側から順に，サブルーチン名と実行している番地
+    //
+    // 一番外側はPRを含むプログラムの名前にする
+    pub fn backtrace(&self) -> Vec<(String,u16)> {
+
+        let mut pcs = vec![self.cpu.pr];
+        pcs.extend(self.frames.iter().rev().map(|f| f.site));
+
+        let mut names = self.frames.iter().rev().map(|f| self.names.get(&f.entry).cloned().unwrap_or_else(|| format!("#{:0>4X}", f.entry))).collect::<Vec<String>>();
+
+        let pr = self.frames.first().map_or(self.cpu.pr, |f| f.site);
+
+        names.push(self.info.programs
+            .iter()
+            .find(|(_, start, end)| *start <= pr && pr < *end)
+            .map_or_else(|| "??".to_string(), |(name, _, _)| name.to_string()));
+
+        names.into_iter().zip(pcs).collect()
+    }
+
+    fn command_backtrace(&self) -> String {
+        self.backtrace()
+            .iter()
+            .enumerate()
+            .map(|(i, (name, pc))| match self.info.location(*pc) {
+                Some(loc) => format!("#{:<2} {:<8} at {} ({})", i, name, self.location(*pc), loc),
+                None => format!("#{:<2} {:<8} at {}", i, name, self.location(*pc)),
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn command_reverse_step(&mut self, args: &[&str]) -> Result<String, String> {
+
+        let n = match args.first() {
+            Some(n) => n.parse::<u64>().map_err(|_| format!("Invalid count: `{}`", n))?,
+            None => 1,
+        };
+
+        for _ in 0..n {
+            self.undo()?;
+        }
+
+        Ok(self.current())
+    }
+
+    fn command_reverse_continue(&mut self) -> Result<String, String> {
+
+        self.undo()?;
+
+        loop {
+            if self.at_breakpoint() {
+                return Ok(format!("Breakpoint at {}", self.current()));
+            }
+            if self.history.is_empty() {
+                return Ok(format!("Reached the start of history\n{}", self.current()));
+            }
+            self.undo()?;
+        }
+    }
+
+    fn command_reverse_write(&mut self, args: &[&str]) -> Result<String, String> {
+
+        let addr = match args.first() {
+            Some(s) => self.address(s)?,
+            None => return Err("Usage: reverse-write LOC".to_string()),
+        };
+
+        let n = match self.history.last_write(addr) {
+            Some(n) => n,
+            None => return Err(format!("No write to {} in history", self.location(addr))),
+        };
+
+        let new = self.cpu.read(addr);
+
+        for _ in 0..n {
+            self.undo()?;
+        }
+
+        Ok(format!("Write to {}: #{:0>4X} -> #{:0>4X}\n{}", self.location(addr), self.cpu.read(addr), new, self.current()))
+    }
+
+    // 前なら戻り，後なら (ブレークポイントは無視して) 実行する
+    fn command_goto(&mut self, args: &[&str]) -> Result<String, String> {
+
+        let n = match args.first() {
+            Some(n) => n.parse::<u64>().map_err(|_| format!("Invalid step: `{}`", n))?,
+            None => return Err("Usage: goto N".to_string()),
+        };
+
+        if n < self.cpu.steps {
+            match self.history.oldest() {
+                Some(oldest) if oldest <= n => {},
+                _ => return Err(format!("Step {} is not in history", n)),
+            }
+            while self.cpu.steps > n {
+                self.undo()?;
+            }
+        }
+
+        while self.cpu.steps < n && !self.halted {
+            self.advance()?;
+        }
+
+        Ok(self.current())
+    }
+
+    fn command_history(&mut self, args: &[&str]) -> Result<String, String> {
+
+        if let Some(n) = args.first() {
+            let n = n.parse::<usize>().map_err(|_| format!("Invalid limit: `{}`", n))?;
+            self.history.set_limit(n);
+        }
+
+        Ok(match self.history.oldest() {
+            Some(oldest) => format!("{} steps recorded from step {} (limit {})", self.history.len(), oldest, self.history.limit),
+            None => format!("No steps recorded (limit {})", self.history.limit),
+        })
+    }
+
+    fn command_list(&mut self, args: &[&str]) -> Result<String, String> {
+
+        let mut addr = match args.first() {
+            Some(s) => self.address(s)?,
+            None => self.cpu.pr,
+        };
+
+        let mut lines: Vec<String> = Vec::new();
+
+        for _ in 0..8 {
+            let (line, len) = self.instruction(addr);
+            let mark = if addr == self.cpu.pr { "=> " } else { "   " };
+            lines.push(format!("{}{}", mark, line));
+            addr = addr.wrapping_add(len);
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    // x/8x BUF のように，語数と表示形式を指定してメモリを表示する
+    fn command_examine(&mut self, spec: &str, args: &[&str]) -> Result<String, String> {
+
+        let spec = spec.strip_prefix('/').unwrap_or(spec);
+        let digits = spec.chars().take_while(|c| c.is_ascii_digit()).collect::<String>();
+
+        let count = if digits.is_empty() { 1 } else { digits.parse::<u16>().map_err(|_| format!("Invalid count: `{}`", digits))? };
+
+        let format = match &spec[digits.len()..] {
+            "" => 'x',
+            f if f.len() == 1 && "xduc".contains(f) => f.chars().next().unwrap(),
+            f => return Err(format!("Unknown format `{}`: use x, d, u or c", f)),
+        };
+
+        let addr = match args.first() {
+            Some(s) => self.address(s)?,
+            None => return Err("Usage: x/NF LOC".to_string()),
+        };
+
+        let mut lines: Vec<String> = Vec::new();
+
+        for row in 0..count.div_ceil(8) {
+
+            let start = addr.wrapping_add(row * 8);
+            let n = (count - row * 8).min(8);
+
+            let values = (0..n)
+                .map(|i| self.cpu.read(start.wrapping_add(i)))
+                .map(|v| match format {
+                    'd' => format!("{:>6}", v as i16),
+                    'u' => format!("{:>5}", v),
+                    // 表示できない値は16進にする
+                    'c' => match ::std::char::from_u32(v as u32) {
+                        Some(c) if c.is_ascii_graphic() || c == ' ' || (!c.is_ascii() && !c.is_control() && v < 0xe000) => format!("'{}'", c),
+                        _ => format!("#{:0>4X}", v),
+                    },
+                    _ => format!("{:0>4X}", v),
+                })
+                .collect::<Vec<String>>();
+
+            lines.push(format!("{:<16} {}", self.location(start) + ":", values.join(" ")));
+        }
+
+        Ok(lines.join("\n"))
+    }
+}
+
+#[test]
+fn test_debugger() {
+
+    use assembler::assemble;
+    use console::Buffer;
+    use linker::link;
+
+    let src = "\
+MAIN     START
+         LAD     GR1,3
+LOOP     CALL    DOUBLE
+         SUBA    GR1,=1
+         JNZ     LOOP
+         RET
+DOUBLE   ADDA    GR2,GR2
+         LAD     GR2,1,GR2
+         RET
+BUF      DC      'Hi',-2
+         END
+";
+
+    let objects = assemble(src).unwrap();
+    let cpu = Comet2::load_with_console(&link(&objects).unwrap(), 0, Buffer::new(""));
+    let mut dbg = Debugger::new(cpu, DebugInfo{lines: Vec::new(), ..DebugInfo::link(&objects)});
+
+    assert_eq!(dbg.address("LOOP+2"), Ok(4));
+    assert_eq!(dbg.address("#000A"), Ok(10));
+    assert!(dbg.address("NONE").is_err());
+
+    assert_eq!(dbg.execute("break DOUBLE"), "Breakpoint 1 at #0009 <DOUBLE>");
+    assert_eq!(dbg.execute("s"), "#0002 <LOOP>:    CALL    DOUBLE");
+
+    // nextはCALLの中のブレークポイントで止まる
+    assert_eq!(dbg.execute("n"), "Breakpoint at #0009 <DOUBLE>:  ADDA    GR2,GR2");
+    assert_eq!(dbg.execute("bt"), "#0  DOUBLE   at #0009 <DOUBLE>\n#1  MAIN     at #0002 <LOOP>");
+    assert_eq!(dbg.execute("s 3"), "#0004:           SUBA    GR1,#0010");
+    assert!(dbg.frames.is_empty());
+    assert_eq!(dbg.execute("rs"), "#000C:           RET");
+    assert_eq!(dbg.backtrace(), vec![("DOUBLE".to_string(), 12), ("MAIN".to_string(), 2)]);
+    assert_eq!(dbg.execute("delete 1"), "Deleted breakpoint at #0009 <DOUBLE>");
+    assert_eq!(dbg.execute("c"), "Halted after 20 steps");
+    assert_eq!(dbg.cpu.gr[2], 7);
+    assert!(dbg.execute("s").contains("halted"));
+
+    assert_eq!(dbg.execute("x/3c BUF"), "#000D <BUF>:     'H' 'i' #FFFE");
+    assert_eq!(dbg.execute("x/3d BUF"), "#000D <BUF>:         72    105     -2");
+    assert_eq!(dbg.execute("x/2 #0000"), "#0000 <MAIN>:    1210 0003");
+    assert!(dbg.execute("r").starts_with("GR0 #0000      0     0    GR1 #0000      0     0\n"));
+
+    // 条件とウォッチポイント
+    let cpu = Comet2::load_with_console(&link(&objects).unwrap(), 0, Buffer::new(""));
+    let mut dbg = Debugger::new(cpu, DebugInfo{lines: Vec::new(), ..DebugInfo::link(&objects)});
+    assert_eq!(dbg.execute("break if GR2 == 3"), "Watchpoint 1: if GR2 == 3");
+    assert_eq!(dbg.execute("c"), "Condition 1: GR2 == 3\n#000C:           RET");
+    assert_eq!(dbg.execute("watch #FFFF"), "Watchpoint 2: write #FFFF");
+    assert_eq!(dbg.execute("c"), "Watchpoint 2: #FFFF #0004 -> #0004\n#0009 <DOUBLE>:  ADDA    GR2,GR2");
+    assert_eq!(dbg.execute("d"), "Deleted all breakpoints");
+    assert_eq!(dbg.execute("b DOUBLE if GR2 == GR1 + 1"), "Breakpoint 1 at #0009 <DOUBLE> if GR2 == GR1 + 1");
+    assert_eq!(dbg.execute("c"), "Halted after 20 steps");
+    assert_eq!(dbg.execute("print [BUF] + 1"), "#0049 73 73");
+    assert!(dbg.execute("p GR1 +").contains("end of expression"));
+
+    // 戻る
+    let cpu = Comet2::load_with_console(&link(&objects).unwrap(), 0, Buffer::new(""));
+    let mut dbg = Debugger::new(cpu, DebugInfo{lines: Vec::new(), ..DebugInfo::link(&objects)});
+    assert_eq!(dbg.execute("c"), "Halted after 20 steps");
+    assert_eq!(dbg.execute("rs"), "#0008:           RET");
+    assert_eq!(dbg.execute("rw #FFFF"), "Write to #FFFF: #0004 -> #0004\n#0002 <LOOP>:    CALL    DOUBLE");
+    assert_eq!((dbg.cpu.steps, dbg.cpu.gr[1]), (13, 1));
+    dbg.execute("b DOUBLE");
+    dbg.execute("goto 18");
+    assert_eq!(dbg.execute("rc"), "Breakpoint at #0009 <DOUBLE>:  ADDA    GR2,GR2");
+    assert_eq!(dbg.cpu.gr[2], 3);
+    assert_eq!(dbg.execute("goto 3"), "#000A:           LAD     GR2,1,GR2");
+    assert_eq!(dbg.execute("goto 20"), "Halted after 20 steps");
+    assert_eq!(dbg.execute("history 5"), "5 steps recorded from step 15 (limit 5)");
+    assert_eq!(dbg.execute("goto 10"), "Step 10 is not in history");
+    assert_eq!(dbg.execute("rs 6"), "No more history (at step 15)");
+
+    // 指標レジスタで呼んだサブルーチンから戻ったのを取り消す
+    let src = "MAIN     START\n         LAD     GR3,SUB\n         CALL    0,GR3\n         RET\nSUB      RET\n         END\n";
+    let indexed = assemble(src).unwrap();
+    let cpu = Comet2::load_with_console(&link(&indexed).unwrap(), 0, Buffer::new(""));
+    let mut dbg = Debugger::new(cpu, DebugInfo{lines: Vec::new(), ..DebugInfo::link(&indexed)});
+    dbg.execute("s 3");
+    assert!(dbg.frames.is_empty());
+    dbg.execute("rs");
+    assert_eq!(dbg.frames, vec![Frame{site: 2, entry: 5, sp: 0xffff}]);
+    assert_eq!(dbg.backtrace(), vec![("SUB".to_string(), 5), ("MAIN".to_string(), 2)]);
+
+    // デバッグ情報があればソースの行も表示し，行でブレークポイントを置ける
+    let mut objects = objects;
+    objects[0].file = "double.casl2".to_string();
+
+    let cpu = Comet2::load_with_console(&link(&objects).unwrap(), 0, Buffer::new(""));
+    let mut dbg = Debugger::new(cpu, DebugInfo::link(&objects));
+    assert_eq!(dbg.execute("b double.casl2:7"), "Breakpoint 1 at #0009 <DOUBLE>");
+    assert_eq!(dbg.execute("b :6"), "Breakpoint 2 at #0008");
+    dbg.execute("d");
+    dbg.execute("s");
+    assert_eq!(dbg.execute("n"), "#0004:           SUBA    GR1,#0010       (double.casl2:4)");
+    assert_eq!(dbg.cpu.gr[2], 1);
+
+    // 空行は直前のコマンドを繰り返す
+    assert_eq!(dbg.execute(""), "#0006:           JNZ     LOOP            (double.casl2:5)");
+    dbg.execute("q");
+    assert!(dbg.quit);
+}
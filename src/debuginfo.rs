@@ -0,0 +1,235 @@
+use std::fmt;
+
+use linker::{bases,link_symbols};
+use object::Object;
+use token::SymbolTable;
+
+// 番地とソースの位置の対応
+#[derive(Debug,Clone,PartialEq)]
+pub struct LineEntry {
+    pub addr: u16,
+    pub file: String,
+    // 1始まり
+    pub line: usize,
+}
+
+// リンク後の番地で引けるデバッグ情報
+//
+// 実行ファイルの横に「実行ファイル名.dbg」として書き出せる
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct DebugInfo {
+    pub labels: SymbolTable,
+    // 番地の順
+    pub lines: Vec<LineEntry>,
+    // プログラム名とその範囲 (終わりは含まない)
+    pub programs: Vec<(String,u16,u16)>,
+}
+
+impl DebugInfo {
+
+    pub fn link(objects: &[Object]) -> DebugInfo {
+
+        let mut lines: Vec<LineEntry> = Vec::new();
+        let mut programs: Vec<(String,u16,u16)> = Vec::new();
+
+        // 65536語に収まらなければ何も載せない (link がエラーにする)
+        for (obj, base) in objects.iter().zip(bases(objects).unwrap_or_default()) {
+            for (addr, line) in &obj.lines {
+                if let Some(addr) = base.checked_add(*addr) {
+                    lines.push(LineEntry{addr, file: obj.file.to_string(), line: *line});
+                }
+            }
+            programs.push((obj.name.to_string(), base, base.saturating_add(obj.code.len() as u16)));
+        }
+
+        lines.sort_by_key(|e| e.addr);
+
+        DebugInfo{labels: link_symbols(objects), lines, programs}
+    }
+
+    // ファイル:行，ラベル，ラベル+数，#16進数，10進数を番地にする
+    pub fn resolve(&self, s: &str) -> Result<u16, String> {
+
+        if let Some(i) = s.rfind(':') {
+            let (file, line) = (&s[..i], &s[i+1..]);
+            let n = line.parse::<usize>().map_err(|_| format!("Invalid line number: `{}`", line))?;
+            return self.address_of(file, n).ok_or_else(|| format!("No code at line {}", s));
+        }
+
+        let (base, offset) = match s.find('+') {
+            Some(i) => (&s[..i], &s[i+1..]),
+            None => (s, "0"),
+        };
+
+        let offset = parse_number(offset).ok_or_else(|| format!("Invalid offset: `{}`", offset))?;
+
+        let base = match parse_number(base) {
+            Some(v) => v,
+            None => match self.labels.get(base) {
+                Some(v) => *v,
+                None => return Err(format!("Unknown label: `{}`", base)),
+            },
+        };
+
+        Ok(base.wrapping_add(offset))
+    }
+
+    // プログラム名から範囲を引く
+    pub fn program(&self, name: &str) -> Option<(u16,u16)> {
+        self.programs.iter().find(|(n, _, _)| n == name).map(|(_, start, end)| (*start, *end))
+    }
+
+    // 番地を含む行 (その番地以前で最も近いもの)
+    pub fn find(&self, addr: u16) -> Option<&LineEntry> {
+        match self.lines.binary_search_by_key(&addr, |e| e.addr) {
+            Ok(i) => Some(&self.lines[i]),
+            Err(0) => None,
+            Err(i) => Some(&self.lines[i - 1]),
+        }
+    }
+
+    // ファイルと行番号から番地を求める
+    //
+    // ファイル名を省くと最初のファイルとみなし，その行に命令がなければ後ろの行を使う
+    pub fn address_of(&self, file: &str, line: usize) -> Option<u16> {
+
+        let file = if file.is_empty() {
+            &self.lines.first()?.file
+        } else {
+            self.lines.iter().map(|e| &e.file).find(|f| *f == file || f.ends_with(&format!("/{}", file)))?
+        };
+
+        self.lines
+            .iter()
+            .filter(|e| e.file == *file && e.line >= line)
+            .min_by_key(|e| (e.line, e.addr))
+            .map(|e| e.addr)
+    }
+
+    // 「ファイル:行」か，ファイル名がなければ「行」
+    pub fn location(&self, addr: u16) -> Option<String> {
+        self.find(addr).map(|e| {
+            if e.file.is_empty() {
+                format!("line {}", e.line)
+            } else {
+                format!("{}:{}", e.file, e.line)
+            }
+        })
+    }
+
+    pub fn parse(s: &str) -> Result<DebugInfo, String> {
+
+        let mut lines = s.lines();
+
+        if lines.next().map(|l| l.trim()) != Some("CASL2DBG") {
+            return Err("Not a CASL2 debug information".to_string());
+        }
+
+        let mut info = DebugInfo::default();
+        let mut file = String::new();
+
+        for line in lines {
+
+            if let Some(f) = line.strip_prefix("FILE ") {
+                file = f.to_string();
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+
+            let word = |s: &str| u16::from_str_radix(s, 16).map_err(|_| format!("Invalid word: `{}`", s));
+
+            match fields.as_slice() {
+                ["SYM", name, addr] => {
+                    info.labels.insert(name.to_string(), word(addr)?);
+                },
+                ["PROG", name, start, end] => {
+                    info.programs.push((name.to_string(), word(start)?, word(end)?));
+                },
+                ["LINE", addr, n] => {
+                    let n = n.parse::<usize>().map_err(|_| format!("Invalid line number: `{}`", n))?;
+                    info.lines.push(LineEntry{addr: word(addr)?, file: file.to_string(), line: n});
+                },
+                [] => {},
+                _ => return Err(format!("Invalid debug record: `{}`", line)),
+            }
+        }
+
+        info.lines.sort_by_key(|e| e.addr);
+
+        Ok(info)
+    }
+}
+
+impl fmt::Display for DebugInfo {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        writeln!(f, "CASL2DBG")?;
+
+        let mut labels: Vec<(&String,&u16)> = self.labels.iter().collect();
+        labels.sort_by_key(|(name, addr)| (**addr, name.to_string()));
+
+        for (name, addr) in labels {
+            writeln!(f, "SYM {} {:0>4x}", name, addr)?;
+        }
+
+        for (name, start, end) in &self.programs {
+            writeln!(f, "PROG {} {:0>4x} {:0>4x}", name, start, end)?;
+        }
+
+        let mut file: Option<&str> = None;
+
+        for e in &self.lines {
+            if file != Some(&e.file) {
+                writeln!(f, "FILE {}", e.file)?;
+                file = Some(&e.file);
+            }
+            writeln!(f, "LINE {:0>4x} {}", e.addr, e.line)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse_number(s: &str) -> Option<u16> {
+    if let Some(h) = s.strip_prefix('#') {
+        u16::from_str_radix(h, 16).ok()
+    } else if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+        s.parse::<u16>().ok()
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_debug_info() {
+
+    let mut main = Object::new("MAIN");
+    main.code = vec![0x8000, 0x0000, 0x8100];
+    main.labels.push(("MAIN".to_string(), 0));
+    main.file = "src/main.casl2".to_string();
+    main.lines = vec![(0, 2), (2, 4)];
+
+    let mut sub = Object::new("SUB");
+    sub.code = vec![0x8100, 0x0000];
+    sub.labels.push(("SUB".to_string(), 0));
+    sub.file = "sub.casl2".to_string();
+    sub.lines = vec![(0, 3), (1, 5)];
+
+    let info = DebugInfo::link(&[main, sub]);
+
+    assert_eq!(info.labels["SUB"], 3);
+    assert_eq!(info.location(2), Some("src/main.casl2:4".to_string()));
+    assert_eq!(info.location(4), Some("sub.casl2:5".to_string()));
+    assert_eq!(info.find(5).unwrap().line, 5);
+    assert_eq!(info.address_of("", 3), Some(2));
+    assert_eq!(info.address_of("main.casl2", 1), Some(0));
+    assert_eq!(info.address_of("sub.casl2", 4), Some(4));
+    assert_eq!(info.address_of("sub.casl2", 6), None);
+    assert_eq!(info.resolve("SUB+1"), Ok(4));
+    assert_eq!(info.resolve("sub.casl2:5"), Ok(4));
+    assert_eq!(info.program("SUB"), Some((3, 5)));
+
+    assert_eq!(DebugInfo::parse(&info.to_string()), Ok(info));
+}
@@ -0,0 +1,456 @@
+use std::env;
+use std::io::{self,IsTerminal};
+
+use assembler::is_assembler;
+use ast::{Error,ErrorKind,Span};
+use constant::label_error;
+use json::Json;
+use lint::{Warning,RULES};
+use macros::is_macro;
+use opcode::{is_opcode,usage};
+use register::Register;
+use token::{tokenize,TokenType};
+
+// 診断の出し方 (--message-format)
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum MessageFormat {
+    // 行を添えて人が読む形
+    Human,
+    // 1行に1つの JSON
+    Json,
+    // SARIF 2.1.0 (全てのファイルの分を1つのログにする)
+    Sarif,
+}
+
+impl MessageFormat {
+    pub fn parse(s: &str) -> Option<MessageFormat> {
+        match s {
+            "human" => Some(MessageFormat::Human),
+            "json" => Some(MessageFormat::Json),
+            "sarif" => Some(MessageFormat::Sarif),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+        }
+    }
+}
+
+// 直し方の題と，書き換える位置と文字列
+pub type Suggestion = (String, Vec<(Span,String)>);
+
+// ソースの行を添えて表示するエラーや警告
+#[derive(Debug,Clone,PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // エラーの種類か lint の検査の名前
+    pub code: &'static str,
+    pub span: Span,
+    pub message: String,
+    pub notes: Vec<String>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+// 位置 (行と桁は1から，終わりの桁は含まない)
+fn span_json(span: Span) -> Json {
+    Json::object(vec![
+        ("line", Json::from(span.line + 1)),
+        ("column", Json::from(span.start + 1)),
+        ("end_column", Json::from(span.end.max(span.start) + 1)),
+    ])
+}
+
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// 標準出力が端末で，NO_COLOR が設定されていなければ色を付ける
+pub fn use_color() -> bool {
+    io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none()
+}
+
+// 命令やマクロ，アセンブラ命令の書き方
+fn forms(op: &str) -> Vec<String> {
+    match op {
+        "START" => vec!["START [entry]".to_string()],
+        "END" | "RPUSH" | "RPOP" => vec![op.to_string()],
+        "DS" => vec!["DS n".to_string()],
+        "DC" => vec!["DC constant[,constant]...".to_string()],
+        "IN" | "OUT" => vec![format!("{} buffer,length", op)],
+        _ => usage(op),
+    }
+}
+
+// 表示したときの幅 (全角文字は2桁)
+fn width(c: char) -> usize {
+    if (c as u32) < 0x1100 { 1 } else { 2 }
+}
+
+impl Diagnostic {
+
+    // 命令欄やオペランド欄のエラーには，その命令の書き方を添える
+    pub fn error(src: &str, e: &Error) -> Diagnostic {
+
+        let code = src.lines().nth(e.span.line).unwrap_or("");
+        let tokens = tokenize(e.span.line, code).unwrap_or_default();
+
+        // 注釈を除いた命令の終わり
+        let end = tokens.iter().filter(|t| t.kind != TokenType::Comment).map(|t| t.span.end).max().unwrap_or(0);
+
+        let notes = match tokens.iter().find(|t| t.kind == TokenType::Opcode) {
+            Some(t) if t.span.start <= e.span.start && e.span.start < end => {
+                let v = forms(&t.value).iter().map(|f| format!("`{}`", f)).collect::<Vec<String>>().join(" or ");
+                // メッセージに書いてあれば繰り返さない
+                if v.is_empty() || e.message.contains(&v) { Vec::new() } else { vec![format!("expected {}", v)] }
+            },
+            _ => Vec::new(),
+        };
+
+        Diagnostic{
+            severity: Severity::Error,
+            code: e.kind.code(),
+            span: e.span,
+            message: e.message.to_string(),
+            notes,
+            suggestions: quick_fix(src, e).into_iter().collect(),
+        }
+    }
+
+    pub fn warning(w: &Warning) -> Diagnostic {
+        Diagnostic{severity: Severity::Warning, code: w.rule, span: w.span, message: w.message.to_string(), notes: Vec::new(), suggestions: Vec::new()}
+    }
+
+    // --message-format json の1行
+    pub fn to_json(&self, path: &str) -> Json {
+        Json::object(vec![
+            ("file", Json::string(path)),
+            ("code", Json::string(self.code)),
+            ("severity", Json::string(self.severity.name())),
+            ("span", span_json(self.span)),
+            ("message", Json::string(&self.message)),
+            ("notes", Json::Array(self.notes.iter().map(|n| Json::string(n)).collect())),
+            ("suggestions", Json::Array(self.suggestions.iter().map(|(title, edits)| Json::object(vec![
+                ("message", Json::string(title)),
+                ("edits", Json::Array(edits.iter().map(|(span, text)| Json::object(vec![
+                    ("span", span_json(*span)),
+                    ("replacement", Json::string(text)),
+                ])).collect())),
+            ])).collect())),
+        ])
+    }
+
+    // error: メッセージ
+    //  --> ファイル:行:桁
+    //   |
+    // 3 |          JUMP    GR1
+    //   |                  ^^^
+    //   |
+    //   = note: expected `JUMP adr[,x]`
+    pub fn render(&self, path: &str, src: &str, color: bool) -> String {
+
+        let paint = |style: &str, s: &str| if color { format!("{}{}{}", style, s, RESET) } else { s.to_string() };
+
+        let number = (self.span.line + 1).to_string();
+        let pad = " ".repeat(number.len());
+        let bar = paint(BLUE, "|");
+
+        let code = src.lines().nth(self.span.line).unwrap_or("").trim_end_matches('\r');
+        let start = self.span.start.min(code.len());
+        let end = self.span.end.clamp(start, code.len());
+
+        // タブはそのまま残して桁をそろえる
+        let indent = code.get(..start).unwrap_or("").chars().map(|c| if c == '\t' { "\t".to_string() } else { " ".repeat(width(c)) }).collect::<String>();
+        let marks = code.get(start..end).unwrap_or("").chars().map(width).sum::<usize>().max(1);
+
+        let mut s = String::new();
+
+        s += &format!("{}{}\n", paint(self.severity.color(), self.severity.name()), paint(BOLD, &format!(": {}", self.message)));
+        s += &format!("{}{} {}:{}:{}\n", pad, paint(BLUE, "-->"), path, self.span.line + 1, self.span.start + 1);
+        s += &format!("{} {}\n", pad, bar);
+        s += &format!("{} {} {}\n", paint(BLUE, &number), bar, code);
+        s += &format!("{} {} {}{}\n", pad, bar, indent, paint(self.severity.color(), &"^".repeat(marks)));
+
+        if !self.notes.is_empty() {
+            s += &format!("{} {}\n", pad, bar);
+        }
+
+        for n in &self.notes {
+            s += &format!("{} {} {}\n", pad, paint(BLUE, "="), paint(BOLD, &format!("note: {}", n)));
+        }
+
+        s
+    }
+}
+
+// ファイルごとの診断を1つの SARIF 2.1.0 のログにする
+//
+// lint の検査とエラーの種類を規則として並べる
+pub fn sarif(diagnostics: &[(String,Diagnostic)]) -> Json {
+
+    let names = RULES
+        .iter()
+        .map(|(name, _, description)| (*name, *description))
+        .chain(ErrorKind::ALL.iter().map(|k| (k.code(), k.description())))
+        .collect::<Vec<(&str,&str)>>();
+
+    let rules = names.iter().map(|(name, description)| Json::object(vec![
+        ("id", Json::string(name)),
+        ("shortDescription", Json::object(vec![("text", Json::string(description))])),
+    ])).collect();
+
+    let results = diagnostics.iter().map(|(path, d)| {
+
+        let region = Json::object(vec![
+            ("startLine", Json::from(d.span.line + 1)),
+            ("startColumn", Json::from(d.span.start + 1)),
+            ("endColumn", Json::from(d.span.end.max(d.span.start) + 1)),
+        ]);
+
+        let mut fields = vec![
+            ("ruleId", Json::string(d.code)),
+            ("level", Json::string(d.severity.name())),
+            ("message", Json::object(vec![("text", Json::string(&d.message))])),
+            ("locations", Json::Array(vec![Json::object(vec![
+                ("physicalLocation", Json::object(vec![
+                    ("artifactLocation", Json::object(vec![("uri", Json::string(path))])),
+                    ("region", region),
+                ])),
+            ])])),
+        ];
+
+        if let Some(i) = names.iter().position(|(name, _)| *name == d.code) {
+            fields.insert(1, ("ruleIndex", Json::from(i)));
+        }
+
+        Json::object(fields)
+    }).collect();
+
+    Json::object(vec![
+        ("$schema", Json::string("https://json.schemastore.org/sarif-2.1.0.json")),
+        ("version", Json::string("2.1.0")),
+        ("runs", Json::Array(vec![Json::object(vec![
+            ("tool", Json::object(vec![
+                ("driver", Json::object(vec![
+                    ("name", Json::string("rust-casl2")),
+                    ("rules", Json::Array(rules)),
+                ])),
+            ])),
+            ("results", Json::Array(results)),
+        ])])),
+    ])
+}
+
+// エラーの直し方 (題と，書き換える位置と文字列)
+pub fn quick_fix(text: &str, e: &Error) -> Option<Suggestion> {
+
+    let code = text.lines().nth(e.span.line).unwrap_or("");
+    let tokens = tokenize(e.span.line, code).unwrap_or_default();
+
+    // エラーの指す字句
+    let word = code.get(e.span.start..e.span.end).unwrap_or("");
+
+    match e.kind {
+
+        ErrorKind::UnknownInstruction => {
+
+            let upper = word.to_uppercase();
+
+            if !(is_opcode(&upper) || is_assembler(&upper) || is_macro(&upper)) {
+                return None;
+            }
+
+            let mut edits = vec![(e.span, upper.to_string())];
+
+            // 小文字で書いたレジスタも一緒に直す
+            for t in tokens.iter().filter(|t| t.kind == TokenType::Operand && t.value != t.value.to_uppercase()) {
+                if Register::parse(&t.value.to_uppercase()).is_some() {
+                    edits.push((t.span, t.value.to_uppercase()));
+                }
+            }
+
+            Some((format!("Change to `{}`", upper), edits))
+        },
+
+        // GR0 を指標にしても修飾しないのと同じ
+        ErrorKind::InvalidIndex => {
+
+            let i = tokens.iter().position(|t| t.span == e.span)?;
+
+            if tokens[i].value != "GR0" {
+                return None;
+            }
+
+            let adr = tokens[..i].iter().rposition(|t| t.kind == TokenType::Operand)?;
+
+            Some(("Remove index register GR0".to_string(), vec![(Span::new(e.span.line, tokens[adr].span.end, e.span.end), String::new())]))
+        },
+
+        // 長すぎるだけのラベルは，定義と同じプログラムの中の参照を先頭8文字にする
+        ErrorKind::InvalidLabel if word.len() > 8 && word.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) => {
+
+            let short = word.chars().take(8).collect::<String>();
+
+            if label_error(&short).is_some() {
+                return None;
+            }
+
+            let (first, last) = program_lines(text, e.span.line);
+            let mut edits: Vec<(Span,String)> = Vec::new();
+            let mut defined = false;
+
+            for (n, code) in text.lines().enumerate().take(last + 1).skip(first) {
+                for t in tokenize(n, code).unwrap_or_default() {
+                    if t.kind == TokenType::Label && t.value == short {
+                        return None;
+                    }
+                    if (t.kind == TokenType::Label || t.kind == TokenType::Operand) && t.value == word {
+                        defined |= t.kind == TokenType::Label;
+                        edits.push((t.span, short.to_string()));
+                    }
+                }
+            }
+
+            if !defined {
+                return None;
+            }
+
+            Some((format!("Shorten to `{}`", short), edits))
+        },
+
+        // START と同じ桁に END を置く
+        ErrorKind::MissingEnd => {
+
+            let (first, _) = program_lines(text, e.span.line);
+            let start = text.lines().nth(first).unwrap_or("");
+            let column = tokenize(first, start)
+                .ok()
+                .and_then(|t| t.into_iter().find(|t| t.kind == TokenType::Opcode))
+                .map_or(9, |t| t.span.start);
+
+            let indent = if start[..column].contains('\t') { "\t".to_string() } else { " ".repeat(column) };
+
+            // 次のプログラムの START を指すものはその前に，ソースの終わりを指すものは最後に置く
+            let edit = if tokens.iter().any(|t| t.kind == TokenType::Opcode && t.span == e.span) {
+                (Span::new(e.span.line, 0, 0), format!("{}END\n", indent))
+            } else {
+                (e.span, format!("\n{}END", indent))
+            };
+
+            Some(("Add END".to_string(), vec![edit]))
+        },
+
+        _ => None,
+    }
+}
+
+// 行を含む START から END まで (エラーのある行も含める)
+fn program_lines(text: &str, line: usize) -> (usize, usize) {
+
+    let lines: Vec<&str> = text.lines().collect();
+
+    let opcode = |n: usize| {
+        tokenize(n, lines.get(n).cloned().unwrap_or(""))
+            .ok()
+            .and_then(|t| t.into_iter().find(|t| t.kind == TokenType::Opcode))
+            .map_or(String::new(), |t| t.value)
+    };
+
+    let first = (0..line + 1).rev().find(|n| opcode(*n) == "START").unwrap_or(0);
+    let last = (line..lines.len()).find(|n| opcode(*n) == "END").unwrap_or(lines.len().saturating_sub(1));
+
+    (first, last)
+}
+
+#[test]
+fn test_diagnostic() {
+
+    use assembler::assemble;
+
+    let src = "\
+MAIN     START
+\tLD\tGR1,GR2,GR3
+         JUMP    GR1
+         END
+";
+
+    let errors = assemble(src).unwrap_err();
+
+    assert_eq!(Diagnostic::error(src, &errors[0]).render("main.casl2", src, false), "\
+error: Operand `GR2` of LD is not adr
+ --> main.casl2:2:9
+  |
+2 | \tLD\tGR1,GR2,GR3
+  | \t  \t    ^^^
+  |
+  = note: expected `LD r,adr[,x]` or `LD r1,r2`
+");
+
+    assert_eq!(errors[1].message, "Invalid operands for JUMP: expected `JUMP adr[,x]`");
+    assert!(Diagnostic::error(src, &errors[1]).notes.is_empty());
+
+    // ラベルの誤りには書き方を添えない
+    let src = "\
+main     START
+         RET
+         END
+";
+    let d = Diagnostic::error(src, &assemble(src).unwrap_err()[0]);
+    assert!(d.notes.is_empty());
+    assert!(d.render("a", src, true).starts_with("\x1b[1;31merror\x1b[0m"));
+
+    // ソースの終わりを指すものにも添えない
+    let src = "MAIN     START\n         RET\n";
+    let d = Diagnostic::error(src, &assemble(src).unwrap_err()[0]);
+    assert!(d.notes.is_empty());
+    assert!(d.render("a", src, false).contains("2 |          RET\n  |             ^\n"));
+    assert_eq!(d.code, "missing-end");
+
+    let src = "MAIN     START\nL1\n         DC      12X\n         LD      GR1,A B\nA        DC      1\n         END\n";
+    let codes = assemble(src).unwrap_err().iter().map(|e| Diagnostic::error(src, e).code).collect::<Vec<_>>();
+    assert_eq!(codes, vec!["missing-instruction", "invalid-constant", "unexpected-token"]);
+
+    // JSON には種類と直し方も含める
+    let src = "MAIN     START\n         ld      GR1,A\n         END\n";
+    let d = Diagnostic::error(src, &assemble(src).unwrap_err()[0]);
+    assert_eq!(d.to_json("a").to_string(), concat!(
+        r#"{"file":"a","code":"unknown-instruction","severity":"error","span":{"line":2,"column":10,"end_column":12},"#,
+        r#""message":"Unknown instruction `ld`","notes":[],"#,
+        r#""suggestions":[{"message":"Change to `LD`","edits":[{"span":{"line":2,"column":10,"end_column":12},"replacement":"LD"}]}]}"#,
+    ));
+
+    let w = Warning{rule: "unused-label", span: Span::new(2, 0, 3), message: "Label `TOP` is never used".to_string()};
+    let log = sarif(&[("a".to_string(), Diagnostic::warning(&w)), ("b".to_string(), d)]);
+    let results = log.get("runs").and_then(|r| r.as_array()).unwrap()[0].get("results").and_then(|r| r.as_array()).unwrap().clone();
+
+    assert_eq!(log.get("version").and_then(|v| v.as_str()), Some("2.1.0"));
+    assert_eq!(results[0].get("ruleIndex").and_then(|i| i.as_i64()), Some(0));
+    assert_eq!(results[0].get("level").and_then(|l| l.as_str()), Some("warning"));
+    assert_eq!(results[1].get("ruleId").and_then(|l| l.as_str()), Some("unknown-instruction"));
+    assert_eq!(results[1].get("ruleIndex").and_then(|i| i.as_i64()), Some(8));
+
+    let rules = log.get("runs").and_then(|r| r.as_array()).unwrap()[0].get("tool").and_then(|t| t.get("driver")).and_then(|d| d.get("rules")).and_then(|r| r.as_array()).unwrap().clone();
+    assert_eq!(rules.len(), RULES.len() + ErrorKind::ALL.len());
+    assert_eq!(rules[8].get("id").and_then(|i| i.as_str()), Some("unknown-instruction"));
+    assert_eq!(rules[0].get("shortDescription").and_then(|d| d.get("text")).and_then(|t| t.as_str()), Some("Label that is defined but never referenced"));
+    assert!(results[1].to_string().contains(r#""region":{"startLine":2,"startColumn":10,"endColumn":12}"#));
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+
+use object::Object;
+use opcode::{lookup_code,Instruction,Format,Access};
+use register::Register;
+use token::SymbolTable;
+
+use self::Operands::*;
+
+// 機械語から読み取ったオペランド
+#[derive(Debug,PartialEq)]
+pub enum Operands {
+    // NOP, RET
+    Nothing,
+    // POP r
+    R(Register),
+    // LD r1,r2
+    RR(Register,Register),
+    // LD r,adr,x
+    RAdr(Register,u16,Option<Register>),
+    // JUMP adr,x
+    Adr(u16,Option<Register>),
+}
+
+#[derive(Debug,PartialEq)]
+pub struct Decoded {
+    pub inst: &'static Instruction,
+    pub operands: Operands,
+}
+
+impl Decoded {
+    pub fn words(&self) -> u16 {
+        self.inst.words()
+    }
+}
+
+// 先頭の語から命令を1つ読み取る
+//
+// 命令として解釈できない語や，2語目が足りない場合はNone
+pub fn decode(words: &[u16]) -> Option<Decoded> {
+
+    let w = *words.first()?;
+    let inst = lookup_code(w >> 8)?;
+    let r = (w >> 4) & 0xf;
+    let x = w & 0xf;
+
+    // 指標レジスタの0はGR0ではなく「指標なし」
+    let index = |x: u16| if x == 0 { Some(None) } else { Register::from_number(x).map(Some) };
+
+    let operands = match inst.format {
+        Format::NoOperand if r == 0 && x == 0 => Nothing,
+        Format::R if x == 0 => R(Register::from_number(r)?),
+        Format::R1R2 => RR(Register::from_number(r)?, Register::from_number(x)?),
+        Format::RAdrX => RAdr(Register::from_number(r)?, *words.get(1)?, index(x)?),
+        Format::AdrX if r == 0 => Adr(*words.get(1)?, index(x)?),
+        _ => return None,
+    };
+
+    Some(Decoded{inst, operands})
+}
+
+// オペランドの番地
+fn address(d: &Decoded) -> Option<u16> {
+    match d.operands {
+        RAdr(_, adr, _) | Adr(adr, _) => Some(adr),
+        _ => None,
+    }
+}
+
+// 分岐先と呼び出し先の番地
+fn branch_target(d: &Decoded) -> Option<u16> {
+    match d.operands {
+        Adr(adr, None) if d.inst.access == Access::Branch => Some(adr),
+        _ => None,
+    }
+}
+
+fn format_adr(adr: u16, x: Option<Register>, names: &HashMap<u16,String>) -> String {
+
+    // 「0,GR2」のように指標レジスタだけで番地を指定しているものはラベルにしない
+    if adr == 0 && x.is_some() {
+        return format_value(adr, x);
+    }
+
+    let mut s = match names.get(&adr) {
+        Some(name) => name.to_string(),
+        None => format!("#{:0>4X}", adr),
+    };
+
+    if let Some(x) = x {
+        s.push(',');
+        s.push_str(x.name());
+    }
+
+    s
+}
+
+// 実効番地を値として使う命令は，ラベルではなく数値で表示する
+fn format_value(adr: u16, x: Option<Register>) -> String {
+
+    let mut s = if adr < 0x8000 {
+        adr.to_string()
+    } else {
+        format!("#{:0>4X}", adr)
+    };
+
+    if let Some(x) = x {
+        s.push(',');
+        s.push_str(x.name());
+    }
+
+    s
+}
+
+pub fn format_operands(d: &Decoded, names: &HashMap<u16,String>) -> String {
+
+    let immediate = d.inst.access == Access::Value;
+
+    match d.operands {
+        Nothing => String::new(),
+        R(r) => r.to_string(),
+        RR(r1, r2) => format!("{},{}", r1, r2),
+        RAdr(r, adr, x) if immediate => format!("{},{}", r, format_value(adr, x)),
+        RAdr(r, adr, x) => format!("{},{}", r, format_adr(adr, x, names)),
+        Adr(adr, x) if immediate => format_value(adr, x),
+        Adr(adr, x) => format_adr(adr, x, names),
+    }
+}
+
+// ラベル，命令，オペランド，注釈 (「;」から) を決まった桁に並べる
+pub fn format_line(label: &str, op: &str, operands: &str, comment: &str) -> String {
+
+    let line = if comment.is_empty() {
+        format!("{:<8} {:<7} {}", label, op, operands)
+    } else {
+        format!("{:<8} {:<7} {:<16}{}", label, op, operands, comment)
+    };
+
+    line.trim_end().to_string()
+}
+
+// 機械語をCASL2のソースに戻す
+//
+// ラベル表があればその名前を使い，なければ分岐先と呼び出し先にL+番地のラベルを付ける
+pub fn disassemble(words: &[u16], labels: &SymbolTable) -> String {
+    disassemble_code(words, label_names(HashMap::new(), labels.iter()), &HashMap::new(), &[])
+}
+
+// リンクする前のオブジェクトをそのまま戻す
+//
+// 外部ラベルを参照する語はその名前で表示し，REF と RELOC の語には注釈で印を付ける
+pub fn disassemble_object(o: &Object) -> String {
+
+    let mut names = HashMap::new();
+    names.insert(0, o.name.to_string());
+
+    let names = label_names(names, o.labels.iter().chain(o.defs.iter()).map(|(name, addr)| (name, addr)));
+    let externals = o.refs.iter().map(|(name, addr)| (*addr, name.to_string())).collect();
+
+    disassemble_code(&o.code, names, &externals, &o.relocs)
+}
+
+// 同じ番地のラベルは名前順で最初のもの
+fn label_names<'a, I: Iterator<Item=(&'a String,&'a u16)>>(mut names: HashMap<u16,String>, labels: I) -> HashMap<u16,String> {
+
+    let mut sorted: Vec<(&String,&u16)> = labels.collect();
+    sorted.sort();
+
+    for (name, addr) in sorted {
+        names.entry(*addr).or_insert_with(|| name.to_string());
+    }
+
+    names
+}
+
+// 番地をラベルの名前で書いたオペランド (外部参照や，即値として使う再配置の番地)
+fn format_symbol(d: &Decoded, name: &str) -> String {
+
+    let adr = |x: Option<Register>| match x {
+        Some(x) => format!("{},{}", name, x.name()),
+        None => name.to_string(),
+    };
+
+    match d.operands {
+        RAdr(r, _, x) => format!("{},{}", r, adr(x)),
+        Adr(_, x) => adr(x),
+        _ => format_operands(d, &HashMap::new()),
+    }
+}
+
+// externals は外部ラベルを参照する語の位置とその名前，relocs は再配置する語の位置
+fn disassemble_code(words: &[u16], mut names: HashMap<u16,String>, externals: &HashMap<u16,String>, relocs: &[u16]) -> String {
+
+    // 1回目で命令の区切りを決めて分岐先を集める
+    let mut decoded: Vec<(u16, Option<Decoded>)> = Vec::new();
+    let mut pc: usize = 0;
+
+    while pc < words.len() {
+
+        // ラベルの付いた語をまたぐ命令や，先頭の語を再配置するものは，データとみなす
+        let fixed = externals.contains_key(&(pc as u16)) || relocs.contains(&(pc as u16));
+        let d = decode(&words[pc..]).filter(|d| !fixed && (d.words() == 1 || !names.contains_key(&(pc as u16 + 1))));
+
+        if let Some(target) = d.as_ref().filter(|_| !externals.contains_key(&(pc as u16 + 1))).and_then(branch_target) {
+            if (target as usize) < words.len() {
+                names.entry(target).or_insert_with(|| format!("L{:0>4X}", target));
+            }
+        }
+
+        let len = d.as_ref().map_or(1, |d| d.words() as usize);
+        decoded.push((pc as u16, d));
+        pc += len;
+    }
+
+    // 命令の途中を指すラベルは付けられないので番地のまま表示する
+    names.retain(|addr, _| decoded.iter().any(|(pc, _)| pc == addr));
+
+    let program = match names.get(&0) {
+        Some(name) => name.to_string(),
+        None => "PROG".to_string(),
+    };
+
+    let mut out = String::new();
+
+    out.push_str(&format_line(&program, "START", "", ""));
+    out.push('\n');
+
+    for (pc, d) in &decoded {
+
+        let label = match names.get(pc) {
+            Some(name) if *pc != 0 => name.as_str(),
+            _ => "",
+        };
+
+        let len = d.as_ref().map_or(1, |d| d.words());
+
+        // 再配置や外部参照の語の印
+        let marks = (*pc..*pc + len).filter_map(|i| match externals.get(&i) {
+            Some(name) => Some(format!(" (REF {})", name)),
+            None if relocs.contains(&i) => Some(" (RELOC)".to_string()),
+            None => None,
+        }).collect::<String>();
+
+        let line = match d {
+            Some(d) => {
+                let raw = words[*pc as usize..(*pc + d.words()) as usize]
+                    .iter()
+                    .map(|w| format!("{:0>4x}", w))
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                let symbol = match externals.get(&(*pc + 1)) {
+                    Some(name) => Some(name),
+                    None if relocs.contains(&(*pc + 1)) => address(d).and_then(|adr| names.get(&adr)),
+                    None => None,
+                };
+                let operands = match symbol {
+                    Some(name) => format_symbol(d, name),
+                    None => format_operands(d, &names),
+                };
+                format_line(label, d.inst.mnemonic, &operands, &format!("; {:0>4x}: {}{}", pc, raw, marks))
+            },
+            None => {
+                let w = words[*pc as usize];
+                let value = match externals.get(pc) {
+                    Some(name) => name.to_string(),
+                    None if relocs.contains(pc) => format_adr(w, None, &names),
+                    None => format!("#{:0>4X}", w),
+                };
+                format_line(label, "DC", &value, &format!("; {:0>4x}: {:0>4x}{}", pc, w, marks))
+            },
+        };
+
+        out.push_str(&line);
+        out.push('\n');
+    }
+
+    out.push_str(&format_line("", "END", "", ""));
+    out.push('\n');
+
+    out
+}
+
+#[test]
+fn test_disassemble() {
+
+    let d = decode(&[0x1010, 0x0005]).unwrap();
+    assert_eq!((d.inst.mnemonic, d.operands, d.inst.words()), ("LD", RAdr(Register::GR1, 5, None), 2));
+    let d = decode(&[0x1421]).unwrap();
+    assert_eq!((d.inst.mnemonic, d.operands, d.inst.words()), ("LD", RR(Register::GR2, Register::GR1), 1));
+    assert_eq!(decode(&[0x7120]).unwrap().operands, R(Register::GR2));
+    assert_eq!(decode(&[0x6400]), None);
+    assert_eq!(decode(&[0x000a]), None);
+
+    // MAIN: LD GR1,=10 / LOOP: SUBA GR1,=1 / JNZ LOOP / CALL #0100 / LAD GR0,2 / RET
+    let words = [0x1010, 0x000b, 0x2110, 0x000c, 0x6200, 0x0002, 0x8000, 0x0100, 0x1200, 0x0002, 0x8100, 0x000a, 0x0001];
+
+    let src = disassemble(&words, &SymbolTable::new());
+    let lines = src.lines().map(|l| l.split(';').next().unwrap().trim_end()).collect::<Vec<&str>>();
+    assert_eq!(lines, vec![
+        "PROG     START",
+        "         LD      GR1,#000B",
+        "L0002    SUBA    GR1,#000C",
+        "         JNZ     L0002",
+        "         CALL    #0100",
+        "         LAD     GR0,2",
+        "         RET",
+        "         DC      #000A",
+        "         DC      #0001",
+        "         END",
+    ]);
+
+    let mut labels = SymbolTable::new();
+    labels.insert("MAIN".to_string(), 0);
+    labels.insert("LOOP".to_string(), 2);
+    labels.insert("TEN".to_string(), 11);
+
+    let src = disassemble(&words, &labels);
+    assert!(src.starts_with("MAIN     START"));
+    assert!(src.contains("LOOP     SUBA    GR1,#000C"));
+    assert!(src.contains("JNZ     LOOP"));
+    assert!(src.contains("LD      GR1,TEN"));
+    assert!(src.contains("LAD     GR0,2 "));
+
+    // MAIN: LAD GR1,DATA / CALL SUB / RET / DATA: DC SUB
+    let mut o = Object::new("MAIN");
+    o.code = vec![0x1210, 0x0005, 0x8000, 0x0000, 0x8100, 0x0000];
+    o.defs.push(("MAIN".to_string(), 0));
+    o.refs = vec![("SUB".to_string(), 3), ("SUB".to_string(), 5)];
+    o.relocs.push(1);
+    o.labels.push(("DATA".to_string(), 5));
+
+    assert_eq!(disassemble_object(&o), "\
+MAIN     START
+         LAD     GR1,DATA        ; 0000: 1210 0005 (RELOC)
+         CALL    SUB             ; 0002: 8000 0000 (REF SUB)
+         RET                     ; 0004: 8100
+DATA     DC      SUB             ; 0005: 0000 (REF SUB)
+         END
+");
+}
@@ -0,0 +1,264 @@
+use std::fmt;
+
+use comet2::Comet2;
+use console::Console;
+use debuginfo::parse_number;
+use register::Register;
+use token::SymbolTable;
+
+// レジスタ，フラグ，メモリ，ラベルを使う式
+//
+// 値は全て16ビットの符号なし整数で，演算は桁あふれを無視する．
+// 比較と論理演算は真なら1，偽なら0 になる
+#[derive(Debug,Clone,PartialEq)]
+pub enum Expr {
+    // 数とラベル (その番地)
+    Number(u16),
+    Register(Register),
+    Sp,
+    Pr,
+    // OF, SF, ZF の順の番号
+    Flag(usize),
+    // [式] : その番地の内容
+    Memory(Box<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+// 優先順位の低いものから
+const BINARY: [&[&str]; 7] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!=", "<=", ">=", "<", ">"],
+    &["+", "-"],
+];
+
+const SYMBOLS: [&str; 19] = [
+    "||", "&&", "==", "!=", "<=", ">=",
+    "|", "^", "&", "<", ">", "+", "-", "!", "~", "(", ")", "[", "]",
+];
+
+const FLAGS: [&str; 3] = ["OF", "SF", "ZF"];
+
+fn tokenize(s: &str) -> Result<Vec<String>, String> {
+
+    let mut tokens: Vec<String> = Vec::new();
+    let mut rest = s.trim_start();
+
+    while !rest.is_empty() {
+
+        let len = if let Some(sym) = SYMBOLS.iter().find(|sym| rest.starts_with(**sym)) {
+            sym.len()
+        } else {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '#')).unwrap_or(rest.len());
+            if len == 0 {
+                return Err(format!("Unexpected `{}` in expression", rest.chars().next().unwrap()));
+            }
+            len
+        };
+
+        tokens.push(rest[..len].to_string());
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<String>,
+    pos: usize,
+    labels: &'a SymbolTable,
+}
+
+impl<'a> Parser<'a> {
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|s| s.as_str())
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), String> {
+        if self.peek() == Some(s) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("Expected `{}` in expression", s))
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+
+        if level == BINARY.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+
+        while let Some(op) = self.peek().and_then(|t| BINARY[level].iter().find(|op| **op == t)) {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some("!") => { self.pos += 1; Ok(Expr::Unary("!", Box::new(self.unary()?))) },
+            Some("-") => { self.pos += 1; Ok(Expr::Unary("-", Box::new(self.unary()?))) },
+            Some("~") => { self.pos += 1; Ok(Expr::Unary("~", Box::new(self.unary()?))) },
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+
+        let token = match self.peek() {
+            Some(t) => t.to_string(),
+            None => return Err("Unexpected end of expression".to_string()),
+        };
+
+        self.pos += 1;
+
+        match token.as_str() {
+            "(" => {
+                let e = self.binary(0)?;
+                self.expect(")")?;
+                Ok(e)
+            },
+            "[" => {
+                let e = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(e)))
+            },
+            "SP" => Ok(Expr::Sp),
+            "PR" => Ok(Expr::Pr),
+            t => {
+                if let Some(i) = FLAGS.iter().position(|f| *f == t) {
+                    Ok(Expr::Flag(i))
+                } else if let Some(r) = Register::parse(t) {
+                    Ok(Expr::Register(r))
+                } else if let Some(v) = parse_number(t) {
+                    Ok(Expr::Number(v))
+                } else if let Some(v) = self.labels.get(t) {
+                    Ok(Expr::Number(*v))
+                } else if t.chars().next().is_some_and(|c| c.is_ascii_uppercase()) {
+                    Err(format!("Unknown label: `{}`", t))
+                } else {
+                    Err(format!("Unexpected `{}` in expression", t))
+                }
+            },
+        }
+    }
+}
+
+impl Expr {
+
+    // ラベルはここで番地に置き換える
+    pub fn parse(s: &str, labels: &SymbolTable) -> Result<Expr, String> {
+
+        let mut parser = Parser{tokens: tokenize(s)?, pos: 0, labels};
+
+        let e = parser.binary(0)?;
+
+        match parser.peek() {
+            None => Ok(e),
+            Some(t) => Err(format!("Unexpected `{}` in expression", t)),
+        }
+    }
+
+    pub fn eval<C: Console>(&self, cpu: &Comet2<C>) -> u16 {
+        match *self {
+            Expr::Number(v) => v,
+            Expr::Register(r) => cpu.gr[r.number() as usize],
+            Expr::Sp => cpu.sp,
+            Expr::Pr => cpu.pr,
+            Expr::Flag(i) => [cpu.of, cpu.sf, cpu.zf][i] as u16,
+            Expr::Memory(ref e) => cpu.read(e.eval(cpu)),
+            Expr::Unary(op, ref e) => {
+                let v = e.eval(cpu);
+                match op {
+                    "!" => (v == 0) as u16,
+                    "-" => v.wrapping_neg(),
+                    _ => !v,
+                }
+            },
+            Expr::Binary(op, ref a, ref b) => {
+                let a = a.eval(cpu);
+                // && と || は左だけで決まれば右を評価しない
+                match op {
+                    "&&" => return (a != 0 && b.eval(cpu) != 0) as u16,
+                    "||" => return (a != 0 || b.eval(cpu) != 0) as u16,
+                    _ => {},
+                }
+                let b = b.eval(cpu);
+                match op {
+                    "|" => a | b,
+                    "^" => a ^ b,
+                    "&" => a & b,
+                    "==" => (a == b) as u16,
+                    "!=" => (a != b) as u16,
+                    "<=" => (a <= b) as u16,
+                    ">=" => (a >= b) as u16,
+                    "<" => (a < b) as u16,
+                    ">" => (a > b) as u16,
+                    "+" => a.wrapping_add(b),
+                    _ => a.wrapping_sub(b),
+                }
+            },
+        }
+    }
+
+    pub fn holds<C: Console>(&self, cpu: &Comet2<C>) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Number(v) => write!(f, "#{:0>4X}", v),
+            Expr::Register(r) => write!(f, "{}", r),
+            Expr::Sp => write!(f, "SP"),
+            Expr::Pr => write!(f, "PR"),
+            Expr::Flag(i) => write!(f, "{}", FLAGS[i]),
+            Expr::Memory(ref e) => write!(f, "[{}]", e),
+            Expr::Unary(op, ref e) => write!(f, "{}{}", op, e),
+            Expr::Binary(op, ref a, ref b) => write!(f, "({} {} {})", a, op, b),
+        }
+    }
+}
+
+#[test]
+fn test_expr() {
+
+    use console::Buffer;
+
+    let mut labels = SymbolTable::new();
+    labels.insert("CNT".to_string(), 0x10);
+
+    let mut cpu = Comet2::with_console(Buffer::new(""));
+    cpu.gr[1] = 0;
+    cpu.gr[2] = 0x0f;
+    cpu.zf = true;
+    cpu.write(0x10, 3);
+    cpu.write(0x11, 0xffff);
+
+    let eval = |s: &str| Expr::parse(s, &labels).map(|e| e.eval(&cpu));
+
+    assert_eq!(eval("GR1 == 0 && ZF"), Ok(1));
+    assert_eq!(eval("[CNT] + 2 * 1"), Err("Unexpected `*` in expression".to_string()));
+    assert_eq!(eval("[CNT] >= 3 || [NONE]"), Err("Unknown label: `NONE`".to_string()));
+    assert_eq!(eval("[CNT+1] == -1"), Ok(1));
+    assert_eq!(eval("GR2 & #3 | 4"), Ok(7));
+    assert_eq!(eval("!(OF || SF) && ~GR1 == #FFFF"), Ok(1));
+    assert_eq!(eval("GR1 - 1 > GR2"), Ok(1));
+    assert!(eval("(GR1").is_err());
+    assert!(eval("GR1 =").is_err());
+
+    assert_eq!(Expr::parse("[CNT] != 0 && ZF", &labels).unwrap().to_string(), "(([#0010] != #0000) && ZF)");
+}
@@ -0,0 +1,95 @@
+use ast::Error;
+use disasm::format_line;
+use token::{tokenize,Token,TokenType};
+
+// ソースを決まった桁にそろえる
+//
+// ラベル，命令，オペランド，注釈の順に並べ直し，オペランドはカンマだけで区切る．
+// 注釈と文字定数は書かれたとおりに残す．字句に分けられない行があればエラー
+pub fn format(src: &str) -> Result<String, Vec<Error>> {
+
+    let mut out = String::new();
+    let mut errors: Vec<Error> = Vec::new();
+
+    for (n, code) in src.lines().enumerate() {
+        match tokenize(n, code) {
+            Ok(tokens) => {
+                out.push_str(&format_tokens(code, &tokens));
+                out.push('\n');
+            },
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(out)
+    } else {
+        Err(errors)
+    }
+}
+
+fn format_tokens(code: &str, tokens: &[Token]) -> String {
+
+    let label = value(tokens, TokenType::Label);
+    let opcode = value(tokens, TokenType::Opcode);
+    let comment = value(tokens, TokenType::Comment);
+
+    let operands = tokens
+        .iter()
+        .filter(|t| t.kind == TokenType::Operand)
+        .map(|t| t.value.as_str())
+        .collect::<Vec<&str>>()
+        .join(",");
+
+    // 注釈だけの行は行頭か命令の桁に置く
+    if label.is_empty() && opcode.is_empty() {
+        return if comment.is_empty() || code.starts_with(';') {
+            comment.to_string()
+        } else {
+            format!("{:<8} {}", "", comment)
+        };
+    }
+
+    format_line(label, opcode, &operands, comment)
+}
+
+fn value(tokens: &[Token], kind: TokenType) -> &str {
+    tokens.iter().find(|t| t.kind == kind).map_or("", |t| &t.value)
+}
+
+#[test]
+fn test_format() {
+
+    let src = "\
+; 足し算
+MAIN\tSTART
+\tLD GR1, A ;  A を読む
+LONGLABEL1 OUT MSG,LEN
+   ; 字下げした注釈
+\tDC  'a, b;c' , #0001;x\t y
+   RET   \r
+  
+A DC 1
+        END
+";
+
+    let formatted = format(src).unwrap();
+
+    assert_eq!(formatted, "\
+; 足し算
+MAIN     START
+         LD      GR1,A           ;  A を読む
+LONGLABEL1 OUT     MSG,LEN
+         ; 字下げした注釈
+         DC      'a, b;c',#0001  ;x\t y
+         RET
+
+A        DC      1
+         END
+");
+
+    assert_eq!(format(&formatted).unwrap(), formatted);
+
+    let errors = format("         LD      GR1,'abc\n").unwrap_err();
+    assert_eq!(errors[0].span.line, 0);
+}
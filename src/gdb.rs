@@ -0,0 +1,512 @@
+use std::io::{self,Read,Write};
+use std::net::TcpStream;
+
+use comet2::{Comet2,State,MEMORY_SIZE};
+use console::Console;
+use watch::{Kind,Watchpoint};
+
+// GR0〜GR7, SP, PR, FR (ZF, SF, OF の順に下位ビットから)
+//
+// メモリの番地は1語 (16ビット) 単位で，レジスタもメモリも語を上位バイトから16進で送る
+pub const TARGET_XML: &str = include_str!("../gdb/comet2.xml");
+
+const REGISTERS: usize = 11;
+
+// 止まった理由のシグナル番号
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// 割り込み (Ctrl-C) を確かめる間隔
+const POLL_INTERVAL: u64 = 4096;
+
+// GDBのリモートシリアルプロトコルでCOMET2を操作する
+pub struct Stub<C: Console> {
+    pub cpu: Comet2<C>,
+    // 語の番地
+    pub breakpoints: Vec<u16>,
+    // GDBが指定したバイト番地と，その範囲にかかる語ごとのウォッチポイント
+    pub watchpoints: Vec<(u32,Watchpoint)>,
+    pub halted: bool,
+    pub quit: bool,
+}
+
+impl<C: Console> Stub<C> {
+
+    pub fn new(cpu: Comet2<C>) -> Stub<C> {
+        Stub{cpu, breakpoints: Vec::new(), watchpoints: Vec::new(), halted: false, quit: false}
+    }
+
+    fn register(&self, n: usize) -> Option<u16> {
+        match n {
+            0..=7 => Some(self.cpu.gr[n]),
+            8 => Some(self.cpu.sp),
+            9 => Some(self.cpu.pr),
+            10 => Some(self.cpu.zf as u16 | (self.cpu.sf as u16) << 1 | (self.cpu.of as u16) << 2),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, v: u16) -> bool {
+        match n {
+            0..=7 => self.cpu.gr[n] = v,
+            8 => self.cpu.sp = v,
+            9 => self.cpu.pr = v,
+            10 => {
+                self.cpu.zf = v & 1 != 0;
+                self.cpu.sf = v & 2 != 0;
+                self.cpu.of = v & 4 != 0;
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    // GDBの番地はバイト単位．1語を上位バイトが先 (ビッグエンディアン) の2バイトとみなす
+    fn read_byte(&self, addr: u32) -> u8 {
+        let word = self.cpu.read((addr / 2) as u16);
+        if addr.is_multiple_of(2) { (word >> 8) as u8 } else { word as u8 }
+    }
+
+    fn write_byte(&mut self, addr: u32, v: u8) {
+        let word = self.cpu.read((addr / 2) as u16);
+        let word = if addr.is_multiple_of(2) { (word & 0x00ff) | (v as u16) << 8 } else { (word & 0xff00) | v as u16 };
+        self.cpu.write((addr / 2) as u16, word);
+    }
+
+    // パケットの中身を処理して応答を返す．None なら応答しない
+    //
+    // interrupted は実行中に呼ばれ，真を返すとそこで止める
+    pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+
+        let (command, args) = packet.split_at(packet.char_indices().nth(1).map_or(packet.len(), |(i, _)| i));
+
+        let reply = match command {
+
+            "?" => self.status(),
+
+            "g" => (0..REGISTERS).map(|n| format!("{:0>4x}", self.register(n).unwrap())).collect(),
+
+            "G" => {
+                let values = parse_words(args);
+                match values {
+                    Some(ref v) if v.len() == REGISTERS => {
+                        for (n, v) in v.iter().enumerate() {
+                            self.set_register(n, *v);
+                        }
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| self.register(n)) {
+                Some(v) => format!("{:0>4x}", v),
+                None => "E01".to_string(),
+            },
+
+            "P" => {
+                let ok = args.split_once('=').and_then(|(n, v)| {
+                    let n = usize::from_str_radix(n, 16).ok()?;
+                    let v = u16::from_str_radix(v, 16).ok()?;
+                    Some(self.set_register(n, v))
+                });
+                if ok == Some(true) { "OK".to_string() } else { "E01".to_string() }
+            },
+
+            "m" => match parse_range(args) {
+                Some((addr, len)) => (addr..addr + len).map(|b| format!("{:0>2x}", self.read_byte(b))).collect(),
+                None => "E01".to_string(),
+            },
+
+            "M" => {
+                let ok = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let bytes = parse_bytes(data)?;
+                    if bytes.len() != len as usize {
+                        return None;
+                    }
+                    for (i, v) in bytes.iter().enumerate() {
+                        self.write_byte(addr + i as u32, *v);
+                    }
+                    Some(())
+                });
+                if ok.is_some() { "OK".to_string() } else { "E01".to_string() }
+            },
+
+            "c" | "s" => {
+                if let Ok(addr) = u16::from_str_radix(args, 16) {
+                    self.cpu.pr = addr;
+                }
+                self.resume(command == "s", interrupted)
+            },
+
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+
+            "k" => {
+                self.quit = true;
+                return None;
+            },
+
+            "D" => {
+                self.quit = true;
+                "OK".to_string()
+            },
+
+            "H" => "OK".to_string(),
+
+            _ => self.query(packet),
+        };
+
+        Some(reply)
+    }
+
+    fn query(&self, packet: &str) -> String {
+
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(rest) {
+                Some((offset, len)) => {
+                    let (offset, len) = (offset as usize, len as usize);
+                    if offset >= TARGET_XML.len() {
+                        "l".to_string()
+                    } else if offset + len >= TARGET_XML.len() {
+                        format!("l{}", &TARGET_XML[offset..])
+                    } else {
+                        format!("m{}", &TARGET_XML[offset..offset + len])
+                    }
+                },
+                None => "E01".to_string(),
+            };
+        }
+
+        match packet {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            // 知らないものには空の応答を返す
+            _ => "",
+        }.to_string()
+    }
+
+    fn status(&self) -> String {
+        if self.halted {
+            "W00".to_string()
+        } else {
+            format!("S{:0>2x}", SIGTRAP)
+        }
+    }
+
+    // Z0,Z1 : ブレークポイント，Z2 : 書き込み，Z3 : 読み出し，Z4 : 読み書き
+    //
+    // ブレークポイントの番地は PR と同じ語の番地，ウォッチポイントは m/M と同じバイト番地と長さ
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+
+        let fields = args.split(',').collect::<Vec<&str>>();
+
+        let kind = match fields[0] {
+            "0" | "1" => Kind::Break,
+            "2" => Kind::Write,
+            "3" => Kind::Read,
+            "4" => Kind::Access,
+            _ => return String::new(),
+        };
+
+        if kind == Kind::Break {
+            let addr = match fields.get(1).and_then(|a| u16::from_str_radix(a, 16).ok()) {
+                Some(a) => a,
+                None => return "E01".to_string(),
+            };
+            self.breakpoints.retain(|a| *a != addr);
+            if insert {
+                self.breakpoints.push(addr);
+            }
+            return "OK".to_string();
+        }
+
+        let (start, len) = match fields.get(1).zip(fields.get(2)).and_then(|(a, l)| parse_range(&format!("{},{}", a, l))) {
+            Some((a, l)) => (a, l.max(1)),
+            None => return "E01".to_string(),
+        };
+
+        self.watchpoints.retain(|(s, w)| !(w.kind == kind && *s == start));
+        if insert {
+            for word in start / 2..=(start + len - 1) / 2 {
+                self.watchpoints.push((start, Watchpoint::at(kind, word as u16)));
+            }
+        }
+
+        "OK".to_string()
+    }
+
+    // 実行して止まった理由を返す
+    fn resume(&mut self, step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+
+        if self.halted {
+            return "W00".to_string();
+        }
+
+        let mut first = true;
+
+        loop {
+
+            // 今いるブレークポイントでは止まらない
+            if !first && self.breakpoints.contains(&self.cpu.pr) {
+                return format!("S{:0>2x}", SIGTRAP);
+            }
+            first = false;
+
+            match self.cpu.step() {
+                Ok(State::Running) => {},
+                Ok(State::Halted) => {
+                    self.halted = true;
+                    return "W00".to_string();
+                },
+                Err(_) => return format!("S{:0>2x}", SIGILL),
+            }
+
+            for (start, w) in &mut self.watchpoints {
+                if w.after(&self.cpu).is_some() {
+                    let name = match w.kind {
+                        Kind::Read => "rwatch",
+                        Kind::Access => "awatch",
+                        _ => "watch",
+                    };
+                    // 語のうち，指定された範囲に入る最初のバイト
+                    return format!("T{:0>2x}{}:{:x};", SIGTRAP, name, (w.addr as u32 * 2).max(*start));
+                }
+            }
+
+            if step {
+                return format!("S{:0>2x}", SIGTRAP);
+            }
+
+            if self.cpu.steps.is_multiple_of(POLL_INTERVAL) && interrupted() {
+                return format!("S{:0>2x}", SIGINT);
+            }
+        }
+    }
+}
+
+// 「番地,長さ」(バイト単位)．メモリの 0x20000 バイトを超える範囲は受け付けない
+fn parse_range(s: &str) -> Option<(u32,u32)> {
+    let (addr, len) = s.split_once(',')?;
+    let (addr, len) = (u32::from_str_radix(addr, 16).ok()?, u32::from_str_radix(len, 16).ok()?);
+    if addr.checked_add(len)? > 2 * MEMORY_SIZE as u32 {
+        return None;
+    }
+    Some((addr, len))
+}
+
+// 2桁ずつの16進
+fn parse_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len() / 2).map(|i| u8::from_str_radix(&s[i*2..i*2+2], 16).ok()).collect()
+}
+
+// 4桁ずつの16進
+fn parse_words(s: &str) -> Option<Vec<u16>> {
+    if !s.len().is_multiple_of(4) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len() / 4).map(|i| u16::from_str_radix(&s[i*4..i*4+4], 16).ok()).collect()
+}
+
+pub fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+// $中身#チェックサム．$ # } * は } のあとに0x20との排他的論理和で送る
+pub fn frame(data: &str) -> String {
+
+    let mut escaped = String::new();
+
+    for c in data.chars() {
+        match c {
+            '$' | '#' | '}' | '*' => {
+                escaped.push('}');
+                escaped.push((c as u8 ^ 0x20) as char);
+            },
+            c => escaped.push(c),
+        }
+    }
+
+    format!("${}#{:0>2x}", escaped, checksum(&escaped))
+}
+
+// パケットを1つ読む．接続が閉じたらNone
+//
+// パケットの外の +, - や割り込みは読み飛ばす．ack が真ならチェックサムを確かめて + か - を返す
+pub fn read_packet<S: Read + Write>(stream: &mut S, ack: bool) -> io::Result<Option<String>> {
+
+    let mut byte = [0u8];
+
+    loop {
+
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut data: Vec<u8> = Vec::new();
+
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum)?;
+
+        let data = String::from_utf8_lossy(&data).to_string();
+
+        if !ack {
+            return Ok(Some(data));
+        }
+
+        let expected = ::std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+
+        stream.write_all(b"-")?;
+    }
+}
+
+// 接続が閉じるか，k や D を受け取るまで応答する
+pub fn serve<C: Console>(stub: &mut Stub<C>, stream: &mut TcpStream) -> io::Result<()> {
+
+    let mut ack = true;
+
+    while !stub.quit {
+
+        let packet = match read_packet(stream, ack)? {
+            Some(p) => p,
+            None => break,
+        };
+
+        let reply = {
+            let poll: &TcpStream = stream;
+            let mut interrupted = || interrupt_requested(poll);
+            stub.handle(&packet, &mut interrupted)
+        };
+
+        if let Some(r) = reply {
+            stream.write_all(frame(&r).as_bytes())?;
+            stream.flush()?;
+        }
+
+        if packet == "QStartNoAckMode" {
+            ack = false;
+        }
+    }
+
+    Ok(())
+}
+
+// 実行中に届いた割り込み (0x03) があるか
+fn interrupt_requested(mut stream: &TcpStream) -> bool {
+
+    let mut byte = [0u8];
+
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+
+    let result = stream.read(&mut byte);
+    let _ = stream.set_nonblocking(false);
+
+    matches!(result, Ok(1)) && byte[0] == 0x03
+}
+
+#[test]
+fn test_gdb_stub() {
+
+    use console::Buffer;
+    use std::io::Cursor;
+
+    // LAD GR1,2 / ST GR1,#0010 / SUBA GR1,=1 (#000A) / JNZ #0002 / RET / 1
+    let code = [0x1210, 0x0002, 0x1110, 0x0010, 0x2010, 0x000a, 0x6200, 0x0002, 0x8100, 0x0000, 0x0001];
+
+    let mut stub = Stub::new(Comet2::load_with_console(&code, 0, Buffer::new("")));
+    let mut never = || false;
+    let mut send = |stub: &mut Stub<Buffer>, p: &str| stub.handle(p, &mut never).unwrap_or_default();
+
+    assert!(send(&mut stub, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+    assert_eq!(send(&mut stub, "?"), "S05");
+    assert_eq!(send(&mut stub, "m0,4"), "12100002");
+    assert_eq!(send(&mut stub, "m3,2"), "0211");
+    assert_eq!(send(&mut stub, "m1fffe,4"), "E01");
+    assert_eq!(send(&mut stub, "s"), "S05");
+    assert_eq!(send(&mut stub, "p1"), "0002");
+    assert_eq!(send(&mut stub, "p9"), "0002");
+    assert_eq!(send(&mut stub, "g"), "0000000200000000000000000000000000000002".to_string() + "0000");
+
+    // 書き込みのウォッチポイントは m と同じバイト番地 (#0010 番地の語はバイト番地 0x20)
+    assert_eq!(send(&mut stub, "Z2,20,2"), "OK");
+    assert_eq!(send(&mut stub, "c"), "T05watch:20;");
+    assert_eq!(send(&mut stub, "m20,2"), "0002");
+    assert_eq!(send(&mut stub, "z2,20,2"), "OK");
+    assert!(stub.watchpoints.is_empty());
+    assert_eq!(send(&mut stub, "Z2,1f,3"), "OK");
+    assert_eq!(stub.watchpoints.iter().map(|(_, w)| w.addr).collect::<Vec<u16>>(), vec![0xf, 0x10]);
+    assert_eq!(send(&mut stub, "z2,1f,3"), "OK");
+
+    // FRの書き換え
+    assert_eq!(send(&mut stub, "Pa=4"), "OK");
+    assert!(stub.cpu.of && !stub.cpu.zf);
+
+    assert_eq!(send(&mut stub, "Z0,6,1"), "OK");
+    assert_eq!(send(&mut stub, "c"), "S05");
+    assert_eq!(stub.cpu.pr, 6);
+    assert_eq!(send(&mut stub, "M20,4:abcd0102"), "OK");
+    assert_eq!(stub.cpu.read(0x10), 0xabcd);
+    assert_eq!(stub.cpu.read(0x11), 0x0102);
+    assert_eq!(send(&mut stub, "M23,1:ff"), "OK");
+    assert_eq!(stub.cpu.read(0x11), 0x01ff);
+    assert_eq!(send(&mut stub, "z0,6,1"), "OK");
+    assert_eq!(send(&mut stub, "c"), "W00");
+    assert_eq!(send(&mut stub, "?"), "W00");
+
+    let xml = send(&mut stub, "qXfer:features:read:target.xml:0,fff");
+    assert!(xml.starts_with("l<?xml") && xml.contains("name=\"FR\""));
+    assert_eq!(send(&mut stub, "vMustReplyEmpty"), "");
+
+    assert_eq!(frame("OK"), "$OK#9a");
+    assert_eq!(frame("a#b"), "$a}\x03b#43");
+
+    // チェックサムが違えば - を返して次を待つ
+    struct Pipe(Cursor<Vec<u8>>, Vec<u8>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.1.write(buf) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    let mut pipe = Pipe(Cursor::new(b"+$g#00$g#67".to_vec()), Vec::new());
+    assert_eq!(read_packet(&mut pipe, true).unwrap(), Some("g".to_string()));
+    assert_eq!(pipe.1, b"-+");
+    assert_eq!(read_packet(&mut pipe, true).unwrap(), None);
+}
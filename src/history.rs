@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+
+use comet2::Comet2;
+use console::Console;
+use debugger::Frame;
+
+// レジスタ，フラグと実行した命令の数
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Snapshot {
+    pub gr: [u16; 8],
+    pub sp: u16,
+    pub pr: u16,
+    pub of: bool,
+    pub sf: bool,
+    pub zf: bool,
+    pub steps: u64,
+}
+
+impl Snapshot {
+
+    pub fn of<C: Console>(cpu: &Comet2<C>) -> Snapshot {
+        Snapshot{gr: cpu.gr, sp: cpu.sp, pr: cpu.pr, of: cpu.of, sf: cpu.sf, zf: cpu.zf, steps: cpu.steps}
+    }
+
+    pub fn restore<C: Console>(&self, cpu: &mut Comet2<C>) {
+        cpu.gr = self.gr;
+        cpu.sp = self.sp;
+        cpu.pr = self.pr;
+        cpu.of = self.of;
+        cpu.sf = self.sf;
+        cpu.zf = self.zf;
+        cpu.steps = self.steps;
+    }
+}
+
+// 1命令で変わったもの
+#[derive(Debug,Clone,PartialEq)]
+pub struct Delta {
+    // 実行する前
+    pub before: Snapshot,
+    // 書き込んだ番地と書き込む前の値
+    pub writes: Vec<(u16,u16)>,
+    // この命令で降ろした CALL のフレーム (外側から順)
+    pub frames: Vec<Frame>,
+}
+
+// 戻れるように，実行した命令ごとの変化を新しいものから limit 個までためておく
+//
+// 入出力 (IN, OUT) は取り消せない
+#[derive(Debug,Clone)]
+pub struct History {
+    deltas: VecDeque<Delta>,
+    pub limit: usize,
+}
+
+pub const DEFAULT_LIMIT: usize = 100000;
+
+impl History {
+
+    pub fn new(limit: usize) -> History {
+        History{deltas: VecDeque::new(), limit}
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    // 戻れる最も古いステップ数
+    pub fn oldest(&self) -> Option<u64> {
+        self.deltas.front().map(|d| d.before.steps)
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.deltas.len() > limit {
+            self.deltas.pop_front();
+        }
+    }
+
+    // before は実行する前の状態．cpu は1命令実行した後，frames はその命令で降ろしたフレーム
+    pub fn record<C: Console>(&mut self, before: Snapshot, cpu: &Comet2<C>, frames: Vec<Frame>) {
+
+        if self.limit == 0 {
+            return;
+        }
+
+        if self.deltas.len() == self.limit {
+            self.deltas.pop_front();
+        }
+
+        self.deltas.push_back(Delta{before, writes: cpu.writes.clone(), frames});
+    }
+
+    // 最後の1命令を取り消す
+    pub fn undo<C: Console>(&mut self, cpu: &mut Comet2<C>) -> Option<Delta> {
+
+        let delta = self.deltas.pop_back()?;
+
+        for (addr, old) in delta.writes.iter().rev() {
+            cpu.memory[*addr as usize] = *old;
+        }
+
+        delta.before.restore(cpu);
+
+        Some(delta)
+    }
+
+    // addr に最後に書き込んだのは何命令前か (1なら直前の命令)
+    pub fn last_write(&self, addr: u16) -> Option<usize> {
+        self.deltas
+            .iter()
+            .rev()
+            .position(|d| d.writes.iter().any(|(a, _)| *a == addr))
+            .map(|i| i + 1)
+    }
+}
+
+#[test]
+fn test_history() {
+
+    use console::Buffer;
+
+    // LAD GR1,5 / ST GR1,#0010 / PUSH 0,GR1 / RET / RET
+    let code = [0x1210, 0x0005, 0x1110, 0x0010, 0x7001, 0x0000, 0x8100];
+
+    let mut cpu = Comet2::load_with_console(&code, 0, Buffer::new(""));
+    let mut history = History::new(2);
+
+    for _ in 0..3 {
+        let before = Snapshot::of(&cpu);
+        cpu.step().unwrap();
+        history.record(before, &cpu, Vec::new());
+    }
+
+    assert_eq!((history.len(), history.oldest()), (2, Some(1)));
+    assert_eq!(history.last_write(0x10), Some(2));
+    assert_eq!(history.last_write(0x11), None);
+
+    let delta = history.undo(&mut cpu).unwrap();
+    assert_eq!(delta.writes, vec![(0xffff, 0)]);
+    assert_eq!((cpu.sp, cpu.pr, cpu.steps, cpu.read(0xffff)), (0, 4, 2, 0));
+
+    history.undo(&mut cpu).unwrap();
+    assert_eq!((cpu.gr[1], cpu.pr, cpu.read(0x10)), (5, 2, 0));
+    assert!(history.undo(&mut cpu).is_none());
+
+    history.set_limit(0);
+    history.record(Snapshot::of(&cpu), &cpu, Vec::new());
+    assert!(history.is_empty());
+}
@@ -0,0 +1,374 @@
+use std::fmt;
+
+// トレースやエディタとのやりとりに使うJSONの値
+//
+// 数は整数だけを扱う
+#[derive(Debug,Clone,PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    // キーの順を保つ
+    Object(Vec<(String,Json)>),
+}
+
+impl Json {
+
+    pub fn object(fields: Vec<(&str,Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    pub fn string(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    // オブジェクトのキーの値
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref v) => v.iter().find(|(k, _)| k == key).map(|(_, x)| x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match *self {
+            Json::Array(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    // 小数や指数のある数は整数の部分だけを使う
+    pub fn parse(s: &str) -> Result<Json, String> {
+
+        let mut p = Parser{chars: s.chars().collect(), pos: 0};
+
+        let v = p.value()?;
+        p.skip_whitespace();
+
+        if p.pos < p.chars.len() {
+            return Err(format!("Unexpected `{}` at {}", p.chars[p.pos], p.pos));
+        }
+
+        Ok(v)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.peek() {
+            Some(x) if x == c => {
+                self.pos += 1;
+                Ok(())
+            },
+            Some(x) => Err(format!("Expected `{}` but found `{}` at {}", c, x, self.pos)),
+            None => Err(format!("Expected `{}` but reached the end", c)),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, v: Json) -> Result<Json, String> {
+        let end = self.pos + word.len();
+        if end <= self.chars.len() && self.chars[self.pos..end].iter().collect::<String>() == word {
+            self.pos = end;
+            Ok(v)
+        } else {
+            Err(format!("Invalid value at {}", self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('n') => self.keyword("null", Json::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(format!("Unexpected `{}` at {}", c, self.pos)),
+            None => Err("Unexpected end of JSON".to_string()),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+
+        self.expect('{')?;
+
+        let mut fields: Vec<(String,Json)> = Vec::new();
+
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+
+        loop {
+            if self.peek() != Some('"') {
+                return Err(format!("Expected a key at {}", self.pos));
+            }
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                _ => break,
+            }
+        }
+
+        self.expect('}')?;
+
+        Ok(Json::Object(fields))
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+
+        self.expect('[')?;
+
+        let mut values: Vec<Json> = Vec::new();
+
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Json::Array(values));
+        }
+
+        loop {
+            values.push(self.value()?);
+
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                _ => break,
+            }
+        }
+
+        self.expect(']')?;
+
+        Ok(Json::Array(values))
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+
+        let start = self.pos;
+
+        if self.chars[self.pos] == '-' {
+            self.pos += 1;
+        }
+
+        while self.pos < self.chars.len() && self.chars[self.pos].is_ascii_digit() {
+            self.pos += 1;
+        }
+
+        let n = self.chars[start..self.pos].iter().collect::<String>();
+        let n = n.parse::<i64>().map_err(|_| format!("Invalid number at {}", start))?;
+
+        // 小数部と指数部は読み飛ばす
+        while self.pos < self.chars.len() && "0123456789.eE+-".contains(self.chars[self.pos]) {
+            self.pos += 1;
+        }
+
+        Ok(Json::Number(n))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+
+        self.expect('"')?;
+
+        let mut s = String::new();
+
+        loop {
+            let c = match self.chars.get(self.pos) {
+                Some(c) => *c,
+                None => return Err("Unterminated string".to_string()),
+            };
+            self.pos += 1;
+
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let e = self.chars.get(self.pos).cloned().ok_or("Unterminated string")?;
+                    self.pos += 1;
+                    match e {
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'u' => s.push(self.unicode()?),
+                        c => s.push(c),
+                    }
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    // \uXXXX (サロゲートペアも読む)．対になっていないサロゲートはエラー
+    fn unicode(&mut self) -> Result<char, String> {
+
+        let high = self.hex4()?;
+
+        if (0xd800..0xdc00).contains(&high) {
+            if !self.chars[self.pos..].starts_with(&['\\', 'u']) {
+                return Err(format!("Unpaired surrogate: `\\u{:04x}`", high));
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xdc00..=0xdfff).contains(&low) {
+                return Err(format!("Invalid surrogate pair: `\\u{:04x}\\u{:04x}`", high, low));
+            }
+            let c = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+            return ::std::char::from_u32(c).ok_or(format!("Invalid unicode escape: `{:x}`", c));
+        }
+
+        ::std::char::from_u32(high).ok_or(format!("Unpaired surrogate: `\\u{:04x}`", high))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        if self.pos + 4 > self.chars.len() {
+            return Err("Invalid unicode escape".to_string());
+        }
+        let s = self.chars[self.pos..self.pos + 4].iter().collect::<String>();
+        self.pos += 4;
+        u32::from_str_radix(&s, 16).map_err(|_| format!("Invalid unicode escape: `{}`", s))
+    }
+}
+
+impl From<u16> for Json {
+    fn from(v: u16) -> Json {
+        Json::Number(v as i64)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(v: i64) -> Json {
+        Json::Number(v)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(v: usize) -> Json {
+        Json::Number(v as i64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(v: bool) -> Json {
+        Json::Bool(v)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+
+    write!(f, "\"")?;
+
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:0>4x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+
+    write!(f, "\"")
+}
+
+// 改行を含まない1行のJSONにする
+impl fmt::Display for Json {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref v) => {
+                write!(f, "[")?;
+                for (i, x) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", x)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(ref v) => {
+                write!(f, "{{")?;
+                for (i, (k, x)) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, k)?;
+                    write!(f, ":{}", x)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+#[test]
+fn test_json() {
+    let v = Json::object(vec![
+        ("pr", Json::from(10u16)),
+        ("inst", Json::string("DC 'a\"b'\n")),
+        ("writes", Json::Array(vec![Json::Null, Json::from(true)])),
+    ]);
+    assert_eq!(v.to_string(), r#"{"pr":10,"inst":"DC 'a\"b'\n","writes":[null,true]}"#);
+    assert_eq!(Json::parse(&v.to_string()), Ok(v));
+
+    let v = Json::parse(r#" {"seq": 1, "args": {"lines": [3, -4, 1.5e2], "path": "\u00e9\ud83d\ude00\/"}, "ok": false} "#).unwrap();
+    assert_eq!(v.get("seq").and_then(|x| x.as_i64()), Some(1));
+    assert_eq!(v.get("args").and_then(|a| a.get("lines")), Some(&Json::Array(vec![Json::from(3i64), Json::from(-4i64), Json::from(1i64)])));
+    assert_eq!(v.get("args").and_then(|a| a.get("path")).and_then(|x| x.as_str()), Some("é😀/"));
+    assert_eq!(v.get("ok").and_then(|x| x.as_bool()), Some(false));
+
+    assert!(Json::parse("{\"a\" 1}").is_err());
+    assert!(Json::parse("[1,]").is_err());
+    assert!(Json::parse("\"abc").is_err());
+    assert!(Json::parse(r#""\ud800\u0041""#).is_err());
+    assert!(Json::parse(r#""\ud800""#).is_err());
+    assert!(Json::parse(r#""\ud800A""#).is_err());
+    assert!(Json::parse(r#""\udc00""#).is_err());
+}
@@ -4,5 +4,32 @@ pub mod literal;
 pub mod constant;
 pub mod register;
 pub mod token;
+pub mod ast;
+pub mod parser;
 pub mod opcode;
 pub mod assembler;
+pub mod object;
+pub mod archive;
+pub mod linker;
+pub mod stdlib;
+pub mod disasm;
+pub mod format;
+pub mod diagnostic;
+pub mod comet2;
+pub mod console;
+pub mod debugger;
+pub mod debuginfo;
+pub mod expr;
+pub mod watch;
+pub mod history;
+pub mod gdb;
+pub mod dap;
+pub mod lsp;
+pub mod lint;
+pub mod cfg;
+pub mod stack;
+pub mod clobber;
+pub mod json;
+pub mod trace;
+pub mod table;
+pub mod profile;
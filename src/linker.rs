@@ -0,0 +1,184 @@
+use archive::Archive;
+use comet2::MEMORY_SIZE;
+use object::Object;
+use token::SymbolTable;
+
+// 未解決のラベルを定義しているメンバだけをアーカイブから取り出す
+//
+// 取り出したメンバがさらに別のメンバを参照することがあるので，
+// 新しく取り出すものがなくなるまで繰り返す
+pub fn resolve_members(objects: &mut Vec<Object>, archives: &[Archive]) {
+
+    loop {
+
+        let mut added = false;
+
+        for archive in archives {
+            for member in &archive.members {
+
+                if objects.iter().any(|o| o.name == member.name) {
+                    continue;
+                }
+
+                let needed = objects
+                    .iter()
+                    .flat_map(|o| o.refs.iter())
+                    .any(|(name, _)| member.defines(name) && !objects.iter().any(|o| o.defines(name)));
+
+                if needed {
+                    objects.push(member.clone());
+                    added = true;
+                }
+            }
+        }
+
+        if !added {
+            break;
+        }
+    }
+}
+
+// 先頭から順に並べたときの各オブジェクトの先頭番地
+//
+// 終わりの番地 (0x10000 まで) を比べるため u32 で数える
+pub fn bases(objects: &[Object]) -> Result<Vec<u16>, String> {
+
+    let mut bases: Vec<u16> = Vec::new();
+    let mut base: u32 = 0;
+
+    for obj in objects {
+        let end = base + obj.code.len() as u32;
+        // メモリがいっぱいになった後には空のオブジェクトも置けない (先頭番地がない)
+        if end > MEMORY_SIZE as u32 || base == MEMORY_SIZE as u32 {
+            return Err("program exceeds 65536 words".to_string());
+        }
+        bases.push(base as u16);
+        base = end;
+    }
+
+    Ok(bases)
+}
+
+// オブジェクトを先頭から順に並べ，番地を解決した機械語を返す
+pub fn link(objects: &[Object]) -> Result<Vec<u16>, String> {
+
+    let mut globals = SymbolTable::new();
+    let bases = bases(objects)?;
+
+    for (obj, base) in objects.iter().zip(&bases) {
+        for (name, addr) in &obj.defs {
+            if globals.contains_key(name) {
+                return Err(format!("Duplicate symbol: `{}`", name));
+            }
+            globals.insert(name.to_string(), base.checked_add(*addr).ok_or("program exceeds 65536 words")?);
+        }
+    }
+
+    let mut memory: Vec<u16> = Vec::new();
+
+    for (obj, base) in objects.iter().zip(bases) {
+
+        let mut code = obj.code.clone();
+        let out_of_code = |addr: u16| format!("Relocation at {:0>4x} is out of the code of `{}`", addr, obj.name);
+
+        for addr in &obj.relocs {
+            let word = code.get_mut(*addr as usize).ok_or_else(|| out_of_code(*addr))?;
+            *word = word.wrapping_add(base);
+        }
+
+        for (name, addr) in &obj.refs {
+            let v = globals.get(name).ok_or_else(|| format!("Undefined symbol: `{}` referenced from `{}`", name, obj.name))?;
+            let word = code.get_mut(*addr as usize).ok_or_else(|| out_of_code(*addr))?;
+            *word = word.wrapping_add(*v);
+        }
+
+        memory.append(&mut code);
+    }
+
+    Ok(memory)
+}
+
+// リンク後の番地でラベル表を作る
+//
+// 外部に公開しているラベルを優先し，プログラム間で重複する局所ラベルは先に現れたものを使う．
+// 65536語に収まらないとき (link がエラーになるとき) は空になる
+pub fn link_symbols(objects: &[Object]) -> SymbolTable {
+
+    let mut labels = SymbolTable::new();
+    let bases = bases(objects).unwrap_or_default();
+
+    for (obj, base) in objects.iter().zip(&bases) {
+        for (name, addr) in &obj.defs {
+            if let Some(v) = base.checked_add(*addr) {
+                labels.insert(name.to_string(), v);
+            }
+        }
+    }
+
+    for (obj, base) in objects.iter().zip(&bases) {
+        for (name, addr) in &obj.labels {
+            if let Some(v) = base.checked_add(*addr) {
+                labels.entry(name.to_string()).or_insert(v);
+            }
+        }
+    }
+
+    labels
+}
+
+#[test]
+fn test_link() {
+
+    // MAIN: CALL MULT / JUMP MAIN
+    let mut main = Object::new("MAIN");
+    main.code = vec![0x8000, 0x0000, 0x6400, 0x0000];
+    main.defs.push(("MAIN".to_string(), 0));
+    main.refs.push(("MULT".to_string(), 1));
+    main.relocs.push(3);
+
+    // MULT: CALL ADD / RET
+    let mut mult = Object::new("MULT");
+    mult.code = vec![0x8000, 0x0000, 0x8100];
+    mult.defs.push(("MULT".to_string(), 0));
+    mult.refs.push(("ADD".to_string(), 1));
+
+    let mut add = Object::new("ADD");
+    add.code = vec![0x8100];
+    add.defs.push(("ADD".to_string(), 0));
+    add.labels.push(("ADD".to_string(), 0));
+
+    let mut unused = Object::new("DIV");
+    unused.code = vec![0x8100];
+    unused.defs.push(("DIV".to_string(), 0));
+
+    let archive = Archive{members: vec![add, unused, mult]};
+
+    let mut objects = vec![main.clone()];
+    assert!(link(&objects).is_err());
+
+    resolve_members(&mut objects, &[archive]);
+    let names = objects.iter().map(|o| o.name.as_str()).collect::<Vec<&str>>();
+    assert_eq!(names, vec!["MAIN", "MULT", "ADD"]);
+
+    assert_eq!(link(&objects), Ok(vec![0x8000, 0x0004, 0x6400, 0x0000, 0x8000, 0x0007, 0x8100, 0x8100]));
+    assert_eq!(link_symbols(&objects)["ADD"], 7);
+
+    assert!(link(&[main.clone(), main]).is_err());
+
+    // ちょうど65536語なら収まる
+    let mut half = Object::new("HALF");
+    half.code = vec![0; 32768];
+    assert_eq!(link(&[half.clone(), half.clone()]).map(|m| m.len()), Ok(65536));
+    assert!(link(&[half.clone(), half.clone(), Object::new("EMPTY")]).is_err());
+
+    // DS 40000 を2つ並べると収まらない
+    let mut big = Object::new("BIG");
+    big.code = vec![0; 40000];
+    assert_eq!(link(&[big.clone(), big.clone()]), Err("program exceeds 65536 words".to_string()));
+    assert!(link_symbols(&[big.clone(), big]).is_empty());
+
+    let mut broken = Object::new("BROKEN");
+    broken.code = vec![0x8100];
+    broken.relocs.push(0x10);
+    assert!(link(&[broken]).is_err());
+}
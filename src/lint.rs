@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use ast::{Error,Line,Macro,Program,Span,Statement};
+use cfg;
+use clobber;
+use opcode::Access;
+use parser::parse_programs;
+use stack;
+use token::{tokenize,TokenType};
+
+// 検査の名前と説明 (使い方に出す日本語と，SARIF に書く英語)
+pub const RULES: [(&str, &str, &str); 7] = [
+    ("unused-label", "定義したがどこからも参照していないラベル", "Label that is defined but never referenced"),
+    ("unreachable", "RET や JUMP の直後にあり，ラベルもないので実行されない命令", "Unlabeled instruction right after RET or JUMP that is never executed"),
+    ("fall-through", "命令から続けて実行されてしまう DS/DC や END，データで始まるプログラム", "DS/DC or END reached by falling through from an instruction, or a program starting with data"),
+    ("data-in-code", "命令と命令の間に置いた DS/DC", "DS/DC placed between instructions"),
+    ("stack-balance", "サブルーチンの入口から RET までで PUSH と POP の数が合わない", "PUSH and POP counts differ between a subroutine entry and RET"),
+    ("store-to-code", "命令のラベルへの ST や IN", "ST or IN to the label of an instruction"),
+    ("register-contract", "注釈の @in，@out，@preserves と実際に読み書きするレジスタが合わない", "Registers read or written do not match the @in, @out and @preserves comments"),
+];
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Warning {
+    pub rule: &'static str,
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {} [{}]", self.span.line + 1, self.span.start + 1, self.message, self.rule)
+    }
+}
+
+// エラーにはならないが間違いらしいところを探す
+//
+// 注釈に「lint: allow 名前,...」と書くとその行で，注釈だけの行なら次の行で，
+// 「lint: allow-file 名前,...」と書くとファイル全体でその検査をしない
+pub fn lint(src: &str) -> Result<Vec<Warning>, Vec<Error>> {
+
+    let (programs, errors) = parse_programs(src);
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut warnings: Vec<Warning> = Vec::new();
+
+    for p in &programs {
+        unused_labels(p, &mut warnings);
+        layout(p, &mut warnings);
+        store_to_code(p, &mut warnings);
+    }
+
+    if let Ok(graph) = cfg::build(src) {
+        for p in stack::analyze(&graph).problems {
+            warn(&mut warnings, "stack-balance", p.span, p.message);
+        }
+        for (span, message) in clobber::violations(src, &clobber::analyze(&graph)) {
+            warn(&mut warnings, "register-contract", span, message);
+        }
+    }
+
+    let (lines, file) = suppressions(src);
+
+    warnings.retain(|w| {
+        let allowed = |rules: &Vec<String>| rules.iter().any(|r| r == w.rule);
+        !allowed(&file) && !lines.get(&w.span.line).is_some_and(allowed)
+    });
+
+    // 同じ行の同じ検査で同じメッセージのものは1つにする
+    warnings.sort_by(|a, b| (a.span.line, a.span.start, &a.message).cmp(&(b.span.line, b.span.start, &b.message)));
+    warnings.dedup_by(|a, b| a.rule == b.rule && a.span.line == b.span.line && a.message == b.message);
+
+    Ok(warnings)
+}
+
+// 行ごとと，ファイル全体で止めた検査
+fn suppressions(src: &str) -> (HashMap<usize,Vec<String>>, Vec<String>) {
+
+    let mut lines: HashMap<usize,Vec<String>> = HashMap::new();
+    let mut file: Vec<String> = Vec::new();
+    let mut pending: Vec<String> = Vec::new();
+
+    for (n, code) in src.lines().enumerate() {
+
+        let tokens = tokenize(n, code).unwrap_or_default();
+        let comment = tokens.iter().find(|t| t.kind == TokenType::Comment);
+        let statement = tokens.iter().any(|t| t.kind != TokenType::Comment);
+
+        let mut allowed: Vec<String> = Vec::new();
+
+        if let Some(rest) = comment.and_then(|t| t.value.find("lint:").map(|i| &t.value[i+5..])) {
+
+            let mut words = rest.split(|c: char| c.is_whitespace() || c == ',').filter(|w| !w.is_empty());
+            let rules = |words: &mut dyn Iterator<Item=&str>| words.map(|w| w.to_string()).collect::<Vec<String>>();
+
+            match words.next() {
+                Some("allow") => allowed = rules(&mut words),
+                Some("allow-file") => file.extend(rules(&mut words)),
+                _ => {},
+            }
+        }
+
+        if statement {
+            allowed.append(&mut pending);
+            lines.insert(n, allowed);
+        } else {
+            pending.append(&mut allowed);
+        }
+    }
+
+    (lines, file)
+}
+
+fn is_code(line: &Line) -> bool {
+    matches!(line.statement, Statement::Instruction(_, _) | Statement::Macro(_, _))
+}
+
+fn is_data(line: &Line) -> bool {
+    matches!(line.statement, Statement::Ds(_) | Statement::Dc(_))
+}
+
+// 次の行へ進まない命令
+fn is_terminal(line: &Line) -> bool {
+    match line.statement {
+        Statement::Instruction(inst, _) => inst.mnemonic == "RET" || inst.mnemonic == "JUMP",
+        _ => false,
+    }
+}
+
+fn warn(warnings: &mut Vec<Warning>, rule: &'static str, span: Span, message: String) {
+    warnings.push(Warning{rule, span, message});
+}
+
+fn unused_labels(p: &Program, warnings: &mut Vec<Warning>) {
+
+    let refs = p.references();
+
+    // プログラム名は他のプログラムから呼ばれる
+    for l in p.lines.iter().skip(1).filter_map(|l| l.label.as_ref()) {
+        if !refs.iter().any(|(name, _)| *name == l.node) {
+            warn(warnings, "unused-label", l.span, format!("Label `{}` is never used", l.node));
+        }
+    }
+}
+
+// 命令とデータの並び方
+fn layout(p: &Program, warnings: &mut Vec<Warning>) {
+
+    let lines = &p.lines;
+
+    for i in 1..lines.len() {
+
+        let (prev, line) = (&lines[i-1], &lines[i]);
+
+        if is_code(prev) && is_terminal(prev) && is_code(line) && line.label.is_none() {
+            warn(warnings, "unreachable", line.span, format!("Unreachable instruction after {}", prev.opcode.node));
+        }
+
+        let falls = match prev.statement {
+            Statement::Start(None) => is_data(line),
+            _ => is_code(prev) && !is_terminal(prev) && (is_data(line) || line.statement == Statement::End),
+        };
+
+        if falls {
+            let message = match (&prev.statement, &line.statement) {
+                (&Statement::Start(_), _) => format!("`{}` starts with data", p.name),
+                (_, &Statement::End) => format!("Execution falls off the end of `{}` after {}", p.name, prev.opcode.node),
+                _ => format!("Execution falls through from {} into {}", prev.opcode.node, line.opcode.node),
+            };
+            warn(warnings, "fall-through", line.span, message);
+            continue;
+        }
+
+        // 命令の後ろから始まり，さらに後ろに命令があるデータ
+        if is_data(line) && is_code(prev) && lines[i..].iter().any(is_code) {
+            warn(warnings, "data-in-code", line.span, format!("{} in the middle of code", line.opcode.node));
+        }
+    }
+}
+
+// 同じプログラムの中のラベルの行
+fn label_lines(p: &Program) -> HashMap<&str,usize> {
+    p.lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, l)| l.label.as_ref().map(|label| (label.node.as_str(), i)))
+        .collect()
+}
+
+fn store_to_code(p: &Program, warnings: &mut Vec<Warning>) {
+
+    let labels = label_lines(p);
+
+    for line in &p.lines {
+
+        let targets = match line.statement {
+            Statement::Instruction(inst, _) if inst.access == Access::Write && line.statement.x().is_none() => line.statement.adr().into_iter().collect(),
+            Statement::Macro(Macro::In, ref v) => v.iter().collect(),
+            _ => Vec::new(),
+        };
+
+        for o in targets {
+            if let Some(label) = o.node.label() {
+                if labels.get(label).is_some_and(|i| is_code(&p.lines[*i])) {
+                    warn(warnings, "store-to-code", o.span, format!("{} writes into the instruction at `{}`", line.opcode.node, label));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_lint() {
+
+    let src = "\
+MAIN     START
+TOP      LD      GR1,A
+         ST      GR1,TOP
+         CALL    SUB
+         RET
+         LD      GR2,A
+A        DC      1
+UNUSED   DS      1
+         END
+SUB      START
+         PUSH    0,GR1
+LOOP     LD      GR1,B
+         JZE     SKIP
+         POP     GR1
+SKIP     RET
+B        DC      0
+         OUT     B,B
+         END
+";
+
+    let rules = |warnings: Vec<Warning>| warnings.iter().map(|w| (w.span.line, w.rule)).collect::<Vec<(usize, &str)>>();
+
+    assert_eq!(rules(lint(src).unwrap()), vec![
+        (2, "store-to-code"),
+        (5, "unreachable"),
+        (6, "fall-through"),
+        (7, "unused-label"),
+        (11, "unused-label"),
+        (14, "stack-balance"),
+        (14, "stack-balance"),
+        (15, "data-in-code"),
+        (17, "fall-through"),
+    ]);
+
+    // JZE から来ると1語残ったまま RET する
+    let w = lint(src).unwrap();
+    assert_eq!(w[5].to_string(), "15:1: 1 word(s) left on the stack at RET of `SUB` [stack-balance]");
+    assert_eq!(w[6].to_string(), "15:1: Stack depth differs between paths reaching here in `SUB` [stack-balance]");
+
+    // 行ごと，ファイル全体で止める
+    let src = src
+        .replace("         RET\n         LD", "         RET\n; lint: allow unreachable\n         LD")
+        .replace("UNUSED   DS      1", "UNUSED   DS      1       ; lint: allow unused-label,fall-through")
+        .replace("B        DC      0", "B        DC      0       ; lint: allow-file stack-balance data-in-code");
+
+    assert_eq!(rules(lint(&src).unwrap()), vec![
+        (2, "store-to-code"),
+        (7, "fall-through"),
+        (12, "unused-label"),
+        (18, "fall-through"),
+    ]);
+
+    assert!(lint("MAIN     START\n").is_err());
+
+    // 同梱のサブルーチンには警告が出ない
+    for (name, _, src) in ::stdlib::ROUTINES.iter() {
+        assert_eq!(lint(src).unwrap(), Vec::new(), "{}", name);
+    }
+}
@@ -0,0 +1,970 @@
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+
+use assembler::{assemble,assemble_program};
+use ast::{Program,Span};
+use constant::label_error;
+use dap::{read_message,write_message};
+use diagnostic::quick_fix;
+use json::Json;
+use opcode::INSTRUCTIONS;
+use parser::parse_programs;
+use register::Register;
+use stdlib::ROUTINES;
+use token::{tokenize,TokenType};
+
+// 命令表にない命令 (名前, オペランド, 説明)
+const DIRECTIVES: [(&str, &str, &str); 8] = [
+    ("START", "[入口名]", "プログラムの先頭: ラベルがプログラム名になり，入口名から実行を始める"),
+    ("END", "", "プログラムの終わり"),
+    ("DS", "語数", "領域の確保: 指定した語数の0を置く"),
+    ("DC", "定数[,定数]...", "定数の定義: 10進定数，16進定数，文字定数，アドレス定数を置く"),
+    ("IN", "入力領域,入力文字長", "入力: 1行読み込み，文字を入力領域に，文字数を入力文字長に入れる (マクロ)"),
+    ("OUT", "出力領域,出力文字長", "出力: 出力領域から出力文字長の文字を1行として書き出す (マクロ)"),
+    ("RPUSH", "", "GR1〜GR7 の内容を順にスタックに積む (マクロ)"),
+    ("RPOP", "", "GR7〜GR1 の順にスタックから戻す (マクロ)"),
+];
+
+// CompletionItemKind
+const KEYWORD: i64 = 14;
+const VARIABLE: i64 = 6;
+const REFERENCE: i64 = 18;
+const FUNCTION: i64 = 3;
+
+// 開いているソースと，ワークスペースにあるソース
+#[derive(Debug,Clone)]
+struct Document {
+    uri: String,
+    text: String,
+    open: bool,
+}
+
+// どの文書のどこか
+#[derive(Debug,Clone,Copy,PartialEq)]
+struct Location {
+    doc: usize,
+    span: Span,
+}
+
+// Language Server Protocol でソースの診断，定義への移動，参照，ホバー，補完，修正と名前の変更を提供する
+//
+// ラベルはそれを定義したプログラム (START から END まで) の中で有効で，
+// 定義されていないものは他のプログラムの名前 (STARTのラベル) を指す
+#[derive(Default)]
+pub struct Server {
+    documents: Vec<Document>,
+    pub quit: bool,
+}
+
+impl Server {
+
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    // メッセージを1つ処理し，応答と通知を返す
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+
+        let method = message.get("method").and_then(|m| m.as_str()).unwrap_or("");
+        let empty = Json::Object(Vec::new());
+        let params = message.get("params").unwrap_or(&empty);
+
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            // 通知
+            None => return self.notification(method, params),
+        };
+
+        let result = match method {
+            "initialize" => Ok(self.initialize(params)),
+            "shutdown" => Ok(Json::Null),
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/references" => Ok(self.references(params)),
+            "textDocument/completion" => Ok(self.completion(params)),
+            "textDocument/codeAction" => Ok(self.code_action(params)),
+            "textDocument/prepareRename" => Ok(self.prepare_rename(params)),
+            "textDocument/rename" => self.rename(params),
+            _ => Err((-32601, format!("Unknown method `{}`", method))),
+        };
+
+        let reply = match result {
+            Ok(r) => ("result", r),
+            Err((code, message)) => ("error", Json::object(vec![("code", Json::from(code)), ("message", Json::String(message))])),
+        };
+
+        vec![Json::object(vec![("jsonrpc", Json::string("2.0")), ("id", id), reply])]
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+
+        let document = params.get("textDocument").cloned().unwrap_or(Json::Null);
+        let uri = document.get("uri").and_then(|u| u.as_str()).unwrap_or("").to_string();
+
+        match method {
+            "textDocument/didOpen" => {
+                let text = document.get("text").and_then(|t| t.as_str()).unwrap_or("").to_string();
+                self.update(&uri, text, true);
+            },
+            // 全体を送ってもらう
+            "textDocument/didChange" => {
+                let changes = params.get("contentChanges").and_then(|c| c.as_array()).cloned().unwrap_or_default();
+                match changes.last().and_then(|c| c.get("text")).and_then(|t| t.as_str()) {
+                    Some(text) => self.update(&uri, text.to_string(), true),
+                    None => return Vec::new(),
+                }
+            },
+            // ディスクの内容に戻す
+            "textDocument/didClose" => {
+                let text = uri_to_path(&uri).and_then(|p| fs::read_to_string(p).ok());
+                match text {
+                    Some(text) => self.update(&uri, text, false),
+                    None => self.documents.retain(|d| !same_uri(&d.uri, &uri)),
+                }
+                let mut messages = self.diagnostics();
+                messages.push(publish(&uri, Vec::new()));
+                return messages;
+            },
+            "exit" => {
+                self.quit = true;
+                return Vec::new();
+            },
+            _ => return Vec::new(),
+        }
+
+        self.diagnostics()
+    }
+
+    fn initialize(&mut self, params: &Json) -> Json {
+
+        let root = params
+            .get("workspaceFolders")
+            .and_then(|f| f.as_array())
+            .and_then(|f| f.first())
+            .and_then(|f| f.get("uri"))
+            .or_else(|| params.get("rootUri"))
+            .and_then(|u| u.as_str())
+            .and_then(uri_to_path);
+
+        if let Some(root) = root {
+            let mut paths: Vec<PathBuf> = Vec::new();
+            find_sources(&root, &mut paths);
+            for path in paths {
+                if let Ok(text) = fs::read_to_string(&path) {
+                    self.update(&path_to_uri(&path), text, false);
+                }
+            }
+        }
+
+        Json::object(vec![
+            ("capabilities", Json::object(vec![
+                ("textDocumentSync", Json::object(vec![("openClose", Json::from(true)), ("change", Json::from(1i64))])),
+                ("hoverProvider", Json::from(true)),
+                ("definitionProvider", Json::from(true)),
+                ("referencesProvider", Json::from(true)),
+                ("completionProvider", Json::object(vec![("triggerCharacters", Json::Array(vec![Json::string(",")]))])),
+                ("codeActionProvider", Json::object(vec![("codeActionKinds", Json::Array(vec![Json::string("quickfix")]))])),
+                ("renameProvider", Json::object(vec![("prepareProvider", Json::from(true))])),
+            ])),
+            ("serverInfo", Json::object(vec![("name", Json::string("rust-casl2"))])),
+        ])
+    }
+
+    fn update(&mut self, uri: &str, text: String, open: bool) {
+        match self.documents.iter_mut().find(|d| same_uri(&d.uri, uri)) {
+            Some(d) => {
+                d.uri = uri.to_string();
+                d.text = text;
+                d.open = open;
+            },
+            None => self.documents.push(Document{uri: uri.to_string(), text, open}),
+        }
+    }
+
+    fn document(&self, params: &Json) -> Option<usize> {
+        let uri = params.get("textDocument").and_then(|d| d.get("uri")).and_then(|u| u.as_str())?;
+        self.documents.iter().position(|d| same_uri(&d.uri, uri))
+    }
+
+    // 文書の番号と，行とその行でのバイト位置
+    fn position(&self, params: &Json) -> Option<(usize, usize, usize)> {
+        let doc = self.document(params)?;
+        let position = params.get("position")?;
+        let line = position.get("line")?.as_i64()? as usize;
+        let character = position.get("character")?.as_i64()? as usize;
+        let text = self.documents[doc].text.lines().nth(line).unwrap_or("");
+        Some((doc, line, byte_column(text, character)))
+    }
+
+    fn parse_all(&self) -> Vec<Vec<Program>> {
+        self.documents.iter().map(|d| parse_programs(&d.text).0).collect()
+    }
+
+    // アセンブラのエラーと，どこにも定義されていないラベルの警告を開いている文書ごとに送る
+    fn diagnostics(&self) -> Vec<Json> {
+
+        let parsed = self.parse_all();
+        let mut messages: Vec<Json> = Vec::new();
+
+        for (doc, d) in self.documents.iter().enumerate() {
+
+            if !d.open {
+                continue;
+            }
+
+            let mut diagnostics: Vec<Json> = Vec::new();
+
+            if let Err(errors) = assemble(&d.text) {
+                for e in errors {
+                    diagnostics.push(diagnostic(&d.text, e.span, 1, &e.message));
+                }
+            }
+
+            for (program, p) in parsed[doc].iter().enumerate() {
+                for (name, span) in p.references() {
+                    if resolve(&parsed, doc, program, &name).is_none() && !ROUTINES.iter().any(|(n, _, _)| *n == name) {
+                        diagnostics.push(diagnostic(&d.text, span, 2, &format!("Undefined label `{}`", name)));
+                    }
+                }
+            }
+
+            messages.push(publish(&d.uri, diagnostics));
+        }
+
+        messages
+    }
+
+    fn location(&self, l: Location) -> Json {
+        let text = &self.documents[l.doc].text;
+        Json::object(vec![("uri", Json::string(&self.documents[l.doc].uri)), ("range", range(text, l.span))])
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+
+        let parsed = self.parse_all();
+
+        match self.position(params).and_then(|(doc, line, col)| symbol_at(&parsed, doc, line, col)) {
+            Some((_, _, target)) => target.map_or(Json::Null, |l| self.location(l)),
+            None => Json::Null,
+        }
+    }
+
+    fn references(&self, params: &Json) -> Json {
+
+        let parsed = self.parse_all();
+
+        let target = match self.position(params).and_then(|(doc, line, col)| symbol_at(&parsed, doc, line, col)) {
+            Some((_, _, Some(target))) => target,
+            _ => return Json::Array(Vec::new()),
+        };
+
+        let declaration = params.get("context").and_then(|c| c.get("includeDeclaration")).and_then(|b| b.as_bool()).unwrap_or(true);
+
+        let mut locations: Vec<Location> = Vec::new();
+
+        if declaration {
+            locations.push(target);
+        }
+
+        for (doc, programs) in parsed.iter().enumerate() {
+            for (program, p) in programs.iter().enumerate() {
+                for (name, span) in p.references() {
+                    if resolve(&parsed, doc, program, &name) == Some(target) {
+                        locations.push(Location{doc, span});
+                    }
+                }
+            }
+        }
+
+        Json::Array(locations.into_iter().map(|l| self.location(l)).collect())
+    }
+
+    // 命令なら説明，ラベルなら番地
+    fn hover(&self, params: &Json) -> Json {
+
+        let (doc, line, col) = match self.position(params) {
+            Some(p) => p,
+            None => return Json::Null,
+        };
+
+        let text = self.documents[doc].text.lines().nth(line).unwrap_or("");
+
+        let token = match tokenize(line, text) {
+            Ok(tokens) => tokens.into_iter().find(|t| t.span.start <= col && col <= t.span.end && t.kind != TokenType::Comment),
+            Err(_) => None,
+        };
+
+        let token = match token {
+            Some(t) => t,
+            None => return Json::Null,
+        };
+
+        let contents = if token.kind == TokenType::Opcode {
+            describe_mnemonic(&token.value)
+        } else {
+            let parsed = self.parse_all();
+            match symbol_at(&parsed, doc, line, col) {
+                Some((name, _, Some(target))) => Some(self.describe_label(&parsed, &name, target)),
+                Some((name, _, None)) => ROUTINES.iter().find(|(n, _, _)| *n == name).map(|(n, summary, _)| format!("`{}`: 標準サブルーチン ({})", n, summary)),
+                None => Register::parse(&token.value).map(|r| format!("汎用レジスタ {}", r)),
+            }
+        };
+
+        match contents {
+            Some(s) => Json::object(vec![
+                ("contents", Json::object(vec![("kind", Json::string("markdown")), ("value", Json::String(s))])),
+                ("range", range(text, token.span)),
+            ]),
+            None => Json::Null,
+        }
+    }
+
+    // ラベルの番地 (プログラムの先頭から) と，どのプログラムのものか
+    fn describe_label(&self, parsed: &[Vec<Program>], name: &str, target: Location) -> String {
+
+        let program = parsed[target.doc].iter().find(|p| contains(p, target.span.line));
+
+        let program = match program {
+            Some(p) => p,
+            None => return format!("`{}`", name),
+        };
+
+        let addr = assemble_program(program, &mut Vec::new())
+            .labels
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, a)| *a);
+
+        let file = uri_to_path(&self.documents[target.doc].uri)
+            .and_then(|p| p.file_name().map(|f| f.to_string_lossy().to_string()))
+            .unwrap_or_else(|| self.documents[target.doc].uri.to_string());
+
+        let line = parsed[target.doc].iter().flat_map(|p| p.lines.iter()).find(|l| l.number() == target.span.line);
+
+        let mut s = match addr {
+            Some(_) if program.name == name => format!("`{}`: プログラム ({}:{})", name, file, target.span.line + 1),
+            Some(a) => format!("`{}`: {} + #{:0>4X} ({}:{})", name, program.name, a, file, target.span.line + 1),
+            None => format!("`{}`", name),
+        };
+
+        if let Some(l) = line {
+            s.push_str(&format!("\n\n```casl2\n{}\n```", self.documents[target.doc].text.lines().nth(l.number()).unwrap_or("").trim_end()));
+        }
+
+        s
+    }
+
+    // 命令欄ならニーモニック，オペランド欄ならレジスタとラベル
+    fn completion(&self, params: &Json) -> Json {
+
+        let (doc, line, col) = match self.position(params) {
+            Some(p) => p,
+            None => return Json::Array(Vec::new()),
+        };
+
+        let text = self.documents[doc].text.lines().nth(line).unwrap_or("");
+        let before = &text[..col.min(text.len())];
+
+        let tokens = match tokenize(line, before) {
+            Ok(t) => t,
+            Err(_) => return Json::Array(Vec::new()),
+        };
+
+        let item = |label: &str, kind: i64, detail: String, documentation: &str| {
+            Json::object(vec![
+                ("label", Json::string(label)),
+                ("kind", Json::from(kind)),
+                ("detail", Json::String(detail)),
+                ("documentation", Json::string(documentation)),
+            ])
+        };
+
+        let last = tokens.last().map(|t| t.kind);
+        let in_opcode = match last {
+            None => before.starts_with([' ', '\t']),
+            Some(TokenType::Label) => before.ends_with([' ', '\t']),
+            Some(TokenType::Opcode) => !before.ends_with([' ', '\t']),
+            _ => false,
+        };
+
+        let mut items: Vec<Json> = Vec::new();
+
+        if in_opcode {
+            let mut seen: Vec<&str> = Vec::new();
+            for i in INSTRUCTIONS.iter() {
+                if !seen.contains(&i.mnemonic) {
+                    seen.push(i.mnemonic);
+                    items.push(item(i.mnemonic, KEYWORD, syntax(i.mnemonic), i.description));
+                }
+            }
+            for (name, operands, description) in DIRECTIVES.iter() {
+                items.push(item(name, KEYWORD, format!("{} {}", name, operands).trim_end().to_string(), description));
+            }
+            return Json::Array(items);
+        }
+
+        if last == Some(TokenType::Comment) || last.is_none() || (last == Some(TokenType::Label) && !before.ends_with([' ', '\t'])) {
+            return Json::Array(items);
+        }
+
+        for i in 0..8 {
+            items.push(item(&format!("GR{}", i), VARIABLE, "汎用レジスタ".to_string(), ""));
+        }
+
+        let parsed = self.parse_all();
+
+        if let Some(p) = parsed[doc].iter().find(|p| contains(p, line)) {
+            for (name, addr) in assemble_program(p, &mut Vec::new()).labels {
+                if name != p.name {
+                    items.push(item(&name, REFERENCE, format!("{} + #{:0>4X}", p.name, addr), ""));
+                }
+            }
+        }
+
+        let mut programs: Vec<String> = Vec::new();
+        for p in parsed.iter().flat_map(|v| v.iter()) {
+            if !p.name.is_empty() && !programs.contains(&p.name) {
+                programs.push(p.name.to_string());
+                items.push(item(&p.name, FUNCTION, "プログラム".to_string(), ""));
+            }
+        }
+
+        for (name, summary, _) in ROUTINES.iter() {
+            if !programs.iter().any(|p| p == name) {
+                items.push(item(name, FUNCTION, "標準サブルーチン".to_string(), summary));
+            }
+        }
+
+        Json::Array(items)
+    }
+
+    // 範囲にあるエラーのうち直し方の決まっているもの
+    fn code_action(&self, params: &Json) -> Json {
+
+        let doc = match self.document(params) {
+            Some(doc) => doc,
+            None => return Json::Array(Vec::new()),
+        };
+
+        let line = |key: &str| params.get("range").and_then(|r| r.get(key)).and_then(|p| p.get("line")).and_then(|l| l.as_i64()).map(|l| l as usize);
+
+        let (first, last) = match (line("start"), line("end")) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Json::Array(Vec::new()),
+        };
+
+        let text = &self.documents[doc].text;
+        let errors = assemble(text).err().unwrap_or_default();
+        let mut actions: Vec<Json> = Vec::new();
+
+        for e in errors.iter().filter(|e| first <= e.span.line && e.span.line <= last) {
+            if let Some((title, edits)) = quick_fix(text, e) {
+                actions.push(Json::object(vec![
+                    ("title", Json::String(title)),
+                    ("kind", Json::string("quickfix")),
+                    ("diagnostics", Json::Array(vec![diagnostic(text, e.span, 1, &e.message)])),
+                    ("isPreferred", Json::from(true)),
+                    ("edit", self.workspace_edit(edits.into_iter().map(|(span, s)| (doc, span, s)).collect())),
+                ]));
+            }
+        }
+
+        Json::Array(actions)
+    }
+
+    // 名前を変えられるのは定義のあるラベルだけ
+    fn prepare_rename(&self, params: &Json) -> Json {
+
+        let parsed = self.parse_all();
+
+        match self.position(params).and_then(|(doc, line, col)| symbol_at(&parsed, doc, line, col).map(|s| (doc, s))) {
+            Some((doc, (name, span, Some(_)))) => Json::object(vec![
+                ("range", range(&self.documents[doc].text, span)),
+                ("placeholder", Json::String(name)),
+            ]),
+            _ => Json::Null,
+        }
+    }
+
+    // ラベルの定義とそれを指す参照を，すべての文書で書き換える
+    //
+    // 注釈と文字定数は構文木のラベルにならないので変わらない
+    fn rename(&self, params: &Json) -> Result<Json, (i64, String)> {
+
+        let parsed = self.parse_all();
+        let new = params.get("newName").and_then(|n| n.as_str()).unwrap_or("");
+
+        let (name, target) = match self.position(params).and_then(|(doc, line, col)| symbol_at(&parsed, doc, line, col)) {
+            Some((name, _, Some(target))) => (name, target),
+            _ => return Err((-32803, "No label to rename".to_string())),
+        };
+
+        if let Some(e) = label_error(new) {
+            return Err((-32602, format!("{}: `{}`", e, new)));
+        }
+
+        if new == name {
+            return Ok(self.workspace_edit(Vec::new()));
+        }
+
+        // エラーのある行は構文木に入らないので，どちらかの名前を書いた文書にエラーがあれば変えない
+        for d in &self.documents {
+            if parse_programs(&d.text).1.is_empty() {
+                continue;
+            }
+            let mentions = d.text.lines().enumerate().any(|(n, code)| tokenize(n, code).map_or(true, |tokens| {
+                tokens.iter().any(|t| (t.kind == TokenType::Label || t.kind == TokenType::Operand) && (t.value == name || t.value == new))
+            }));
+            if mentions {
+                return Err((-32803, format!("Fix the errors in `{}` before renaming `{}`", d.uri, name)));
+            }
+        }
+
+        let mut spans: Vec<(usize, Span)> = vec![(target.doc, target.span)];
+
+        for (doc, programs) in parsed.iter().enumerate() {
+            for (program, p) in programs.iter().enumerate() {
+                for (n, span) in p.references() {
+                    if n == name && resolve(&parsed, doc, program, &n) == Some(target) {
+                        spans.push((doc, span));
+                    }
+                }
+            }
+        }
+
+        // プログラム名なら他のプログラムからも参照される
+        let global = parsed[target.doc].iter().any(|p| p.lines[0].label.as_ref().map(|l| l.span) == Some(target.span));
+
+        // 新しい名前が既にあるラベルと重なったり，他の参照がこちらを指すようになったりしないか
+        for (doc, programs) in parsed.iter().enumerate() {
+            for p in programs {
+
+                let touched = spans.iter().any(|(d, span)| *d == doc && contains(p, span.line));
+                let defines = p.lines.iter().filter_map(|l| l.label.as_ref()).any(|l| l.node == new);
+                let refers = p.references().iter().any(|(n, _)| n == new);
+
+                let conflict = if global {
+                    p.name == new || (touched && defines) || (refers && !defines)
+                } else {
+                    touched && (defines || refers)
+                };
+
+                if conflict {
+                    return Err((-32803, format!("`{}` is already used in `{}`", new, p.name)));
+                }
+            }
+        }
+
+        Ok(self.workspace_edit(spans.into_iter().map(|(doc, span)| (doc, span, new.to_string())).collect()))
+    }
+
+    // 文書ごとにまとめた WorkspaceEdit
+    fn workspace_edit(&self, edits: Vec<(usize, Span, String)>) -> Json {
+
+        let mut changes: Vec<(String,Json)> = Vec::new();
+
+        for (doc, span, s) in edits {
+
+            let d = &self.documents[doc];
+            let edit = Json::object(vec![("range", range(&d.text, span)), ("newText", Json::String(s))]);
+
+            match changes.iter_mut().find(|(uri, _)| *uri == d.uri) {
+                Some((_, Json::Array(v))) => v.push(edit),
+                _ => changes.push((d.uri.to_string(), Json::Array(vec![edit]))),
+            }
+        }
+
+        Json::object(vec![("changes", Json::Object(changes))])
+    }
+}
+
+fn contains(p: &Program, line: usize) -> bool {
+    match (p.lines.first(), p.lines.last()) {
+        (Some(first), Some(last)) => first.number() <= line && line <= last.number(),
+        _ => false,
+    }
+}
+
+// doc の program で使われた name の定義
+//
+// そのプログラムになければ，同じ文書から順に他のプログラムの名前を探す
+fn resolve(parsed: &[Vec<Program>], doc: usize, program: usize, name: &str) -> Option<Location> {
+
+    let p = &parsed[doc][program];
+
+    if let Some(l) = p.lines.iter().filter_map(|l| l.label.as_ref()).find(|l| l.node == name) {
+        return Some(Location{doc, span: l.span});
+    }
+
+    let order = ::std::iter::once(doc).chain((0..parsed.len()).filter(|d| *d != doc));
+
+    for d in order {
+        for q in &parsed[d] {
+            if q.name == name {
+                if let Some(ref l) = q.lines[0].label {
+                    return Some(Location{doc: d, span: l.span});
+                }
+            }
+        }
+    }
+
+    None
+}
+
+// 位置にあるラベル (定義か参照) の名前，位置と，その定義
+fn symbol_at(parsed: &[Vec<Program>], doc: usize, line: usize, col: usize) -> Option<(String, Span, Option<Location>)> {
+
+    let (program, p) = parsed[doc].iter().enumerate().find(|(_, p)| contains(p, line))?;
+
+    let at = |span: &Span| span.line == line && span.start <= col && col <= span.end;
+
+    let defs = p.lines.iter().filter_map(|l| l.label.as_ref()).map(|l| (l.node.to_string(), l.span));
+
+    let (name, span) = defs.chain(p.references()).find(|(_, span)| at(span))?;
+
+    let target = resolve(parsed, doc, program, &name);
+
+    Some((name, span, target))
+}
+
+fn syntax(mnemonic: &str) -> String {
+    INSTRUCTIONS
+        .iter()
+        .filter(|i| i.mnemonic == mnemonic)
+        .map(|i| format!("{} {}", mnemonic, i.format.syntax()).trim_end().to_string())
+        .collect::<Vec<String>>()
+        .join(" / ")
+}
+
+// 形式ごとの説明，語数，フラグ
+fn describe_mnemonic(mnemonic: &str) -> Option<String> {
+
+    let lines = INSTRUCTIONS
+        .iter()
+        .filter(|i| i.mnemonic == mnemonic)
+        .map(|i| format!("`{}` {} ({}語, FR: {})", format!("{} {}", mnemonic, i.format.syntax()).trim_end(), i.description, i.words(), i.flags.name()))
+        .collect::<Vec<String>>();
+
+    if !lines.is_empty() {
+        return Some(lines.join("\n\n"));
+    }
+
+    DIRECTIVES
+        .iter()
+        .find(|(name, _, _)| *name == mnemonic)
+        .map(|(name, operands, description)| format!("`{}` {}", format!("{} {}", name, operands).trim_end(), description))
+}
+
+// バイト位置をUTF-16の位置にする (LSPの既定)
+fn utf16_column(line: &str, byte: usize) -> usize {
+    line[..byte.min(line.len())].encode_utf16().count()
+}
+
+fn byte_column(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn range(text: &str, span: Span) -> Json {
+
+    let line = text.lines().nth(span.line).unwrap_or("");
+
+    let position = |byte: usize| Json::object(vec![
+        ("line", Json::from(span.line)),
+        ("character", Json::from(utf16_column(line, byte))),
+    ]);
+
+    Json::object(vec![("start", position(span.start)), ("end", position(span.end))])
+}
+
+fn diagnostic(text: &str, span: Span, severity: i64, message: &str) -> Json {
+    Json::object(vec![
+        ("range", range(text, span)),
+        ("severity", Json::from(severity)),
+        ("source", Json::string("casl2")),
+        ("message", Json::string(message)),
+    ])
+}
+
+fn publish(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("method", Json::string("textDocument/publishDiagnostics")),
+        ("params", Json::object(vec![("uri", Json::string(uri)), ("diagnostics", Json::Array(diagnostics))])),
+    ])
+}
+
+// file:///a/b%20c.casl2 → /a/b c.casl2
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded: Vec<u8> = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if path.get(i+1..i+3).and_then(|h| u8::from_str_radix(h, 16).ok()).is_some() => {
+                decoded.push(u8::from_str_radix(&path[i+1..i+3], 16).unwrap());
+                i += 3;
+            },
+            b => {
+                decoded.push(b);
+                i += 1;
+            },
+        }
+    }
+
+    Some(PathBuf::from(String::from_utf8_lossy(&decoded).to_string()))
+}
+
+fn path_to_uri(path: &Path) -> String {
+
+    let mut uri = "file://".to_string();
+
+    for b in path.to_string_lossy().bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:0>2X}", b));
+        }
+    }
+
+    uri
+}
+
+// エンコードの違う同じファイルのURIも同じとみなす
+fn same_uri(a: &str, b: &str) -> bool {
+    a == b || (uri_to_path(a).is_some() && uri_to_path(a) == uri_to_path(b))
+}
+
+// ワークスペースの *.casl2 (隠しディレクトリと target は除く)
+fn find_sources(dir: &Path, paths: &mut Vec<PathBuf>) {
+
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    let mut entries = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect::<Vec<PathBuf>>();
+    entries.sort();
+
+    for path in entries {
+        let name = path.file_name().map_or(String::new(), |n| n.to_string_lossy().to_string());
+        if path.is_dir() {
+            if !name.starts_with('.') && name != "target" {
+                find_sources(&path, paths);
+            }
+        } else if name.ends_with(".casl2") {
+            paths.push(path);
+        }
+    }
+}
+
+// 標準入出力でやりとりする
+pub fn serve() -> io::Result<()> {
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut server = Server::new();
+
+    while !server.quit {
+
+        let body = match read_message(&mut input)? {
+            Some(b) => b,
+            None => break,
+        };
+
+        let message = match Json::parse(&body) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+
+        for m in server.handle(&message) {
+            write_message(&mut stdout.lock(), &m)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_lsp() {
+
+    let main = "\
+MAIN     START
+         LAD     GR1,3
+LOOP     CALL    SUB
+         SUBA    GR1,=1
+         JNZ     LOOP
+         CALL    MULU
+         RET
+         END
+SUB      START
+LOOP     LAD     GR2,1,GR2
+         JUMP    NONE
+         END
+";
+
+    let mut server = Server::new();
+
+    let open = Json::parse(&format!(r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///w/main%2B.casl2","languageId":"casl2","version":1,"text":{}}}}}}}"#, Json::string(main))).unwrap();
+
+    let messages = server.handle(&open);
+    let diagnostics = messages[0].get("params").and_then(|p| p.get("diagnostics")).and_then(|d| d.as_array()).cloned().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].get("message"), Some(&Json::string("Undefined label `NONE`")));
+    assert_eq!(diagnostics[0].get("range").and_then(|r| r.get("start")), Some(&Json::object(vec![("line", Json::from(10usize)), ("character", Json::from(17usize))])));
+
+    let mut id = 0;
+    let mut request = |server: &mut Server, method: &str, line: usize, character: usize| {
+        id += 1;
+        let r = Json::parse(&format!(r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{{"textDocument":{{"uri":"file:///w/main+.casl2"}},"position":{{"line":{},"character":{}}},"context":{{"includeDeclaration":true}}}}}}"#, id, method, line, character)).unwrap();
+        server.handle(&r)[0].get("result").cloned().unwrap()
+    };
+
+    let start = |v: &Json| v.get("range").and_then(|r| r.get("start")).and_then(|s| s.get("line")).and_then(|l| l.as_i64()).unwrap();
+
+    // LOOP はプログラムごとに別のもの
+    let def = request(&mut server, "textDocument/definition", 4, 19);
+    assert_eq!(start(&def), 2);
+    assert_eq!(def.get("uri"), Some(&Json::string("file:///w/main%2B.casl2")));
+
+    let def = request(&mut server, "textDocument/definition", 2, 18);
+    assert_eq!(start(&def), 8);
+
+    let refs = request(&mut server, "textDocument/references", 2, 1);
+    assert_eq!(refs.as_array().unwrap().iter().map(start).collect::<Vec<i64>>(), vec![2, 4]);
+
+    let hover = request(&mut server, "textDocument/hover", 1, 10);
+    let text = hover.get("contents").and_then(|c| c.get("value")).and_then(|v| v.as_str()).unwrap().to_string();
+    assert!(text.starts_with("`LAD r,adr[,x]` ロードアドレス"));
+
+    let hover = request(&mut server, "textDocument/hover", 4, 20);
+    let text = hover.get("contents").and_then(|c| c.get("value")).and_then(|v| v.as_str()).unwrap().to_string();
+    assert!(text.starts_with("`LOOP`: MAIN + #0002 (main+.casl2:3)"));
+
+    let hover = request(&mut server, "textDocument/hover", 5, 19);
+    assert!(hover.get("contents").and_then(|c| c.get("value")).and_then(|v| v.as_str()).unwrap().contains("符号なし乗算"));
+
+    let labels = |v: &Json| v.as_array().unwrap().iter().filter_map(|i| i.get("label").and_then(|l| l.as_str()).map(|l| l.to_string())).collect::<Vec<String>>();
+
+    let items = labels(&request(&mut server, "textDocument/completion", 1, 11));
+    assert!(items.contains(&"LAD".to_string()) && items.contains(&"RPUSH".to_string()));
+
+    let items = labels(&request(&mut server, "textDocument/completion", 4, 17));
+    assert!(items.contains(&"GR7".to_string()) && items.contains(&"LOOP".to_string()) && items.contains(&"SUB".to_string()));
+    assert!(!items.contains(&"LAD".to_string()));
+
+    assert_eq!(byte_column("'あ𝄞x'", 4), 8);
+    assert_eq!(utf16_column("'あ𝄞x'", 8), 4);
+    assert_eq!(path_to_uri(Path::new("/a b/c.casl2")), "file:///a%20b/c.casl2");
+    assert_eq!(uri_to_path("file:///a%20b/c.casl2"), Some(PathBuf::from("/a b/c.casl2")));
+
+    let fix = "\
+FIX      START
+         ld      gr1,DATA
+         LD      GR2,DATA,GR0
+         LD      GR3,LONGLABEL1
+         RET
+DATA     DC      2
+LONGLABEL1 DC    1
+";
+
+    let rename = "\
+REN      START
+LOOP     LD      GR1,MSG         ; LOOP
+         JUMP    LOOP
+MSG      DC      'LOOP',LOOP
+         END
+";
+
+    for (uri, text) in [("file:///w/fix.casl2", fix), ("file:///w/ren.casl2", rename)].iter() {
+        server.handle(&Json::parse(&format!(r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"{}","text":{}}}}}}}"#, uri, Json::string(text))).unwrap());
+    }
+
+    let send = |server: &mut Server, method: &str, params: String| {
+        let r = Json::parse(&format!(r#"{{"jsonrpc":"2.0","id":100,"method":"{}","params":{}}}"#, method, params)).unwrap();
+        server.handle(&r).remove(0)
+    };
+
+    // 編集を適用した結果 (ASCIIのみ)
+    let apply = |text: &str, edit: &Json, uri: &str| {
+        let mut lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+        let mut edits = edit.get("changes").and_then(|c| c.get(uri)).and_then(|e| e.as_array()).cloned().unwrap();
+        let at = |e: &Json, key: &str| {
+            let p = e.get("range").and_then(|r| r.get(key)).unwrap();
+            (p.get("line").and_then(|l| l.as_i64()).unwrap() as usize, p.get("character").and_then(|c| c.as_i64()).unwrap() as usize)
+        };
+        edits.sort_by_key(|e| at(e, "start"));
+        for e in edits.iter().rev() {
+            let ((line, start), (_, end)) = (at(e, "start"), at(e, "end"));
+            lines[line].replace_range(start..end, e.get("newText").and_then(|t| t.as_str()).unwrap());
+        }
+        lines.join("\n") + "\n"
+    };
+
+    let actions = send(&mut server, "textDocument/codeAction", r#"{"textDocument":{"uri":"file:///w/fix.casl2"},"range":{"start":{"line":0,"character":0},"end":{"line":6,"character":0}},"context":{"diagnostics":[]}}"#.to_string());
+    let actions = actions.get("result").and_then(|r| r.as_array()).cloned().unwrap();
+    let titles = actions.iter().map(|a| a.get("title").and_then(|t| t.as_str()).unwrap().to_string()).collect::<Vec<String>>();
+    assert_eq!(titles, vec!["Change to `LD`", "Remove index register GR0", "Shorten to `LONGLABE`", "Shorten to `LONGLABE`", "Add END"]);
+
+    // 位置がずれないよう後ろの修正から適用する
+    let fixed = actions.iter().rev().fold(fix.to_string(), |text, a| {
+        if a.get("title") == Some(&Json::string("Shorten to `LONGLABE`")) && text.contains("LONGLABE ") {
+            return text;
+        }
+        apply(&text, a.get("edit").unwrap(), "file:///w/fix.casl2")
+    });
+    assert_eq!(fixed, "\
+FIX      START
+         LD      GR1,DATA
+         LD      GR2,DATA
+         LD      GR3,LONGLABE
+         RET
+DATA     DC      2
+LONGLABE DC    1
+         END
+");
+    assert!(assemble(&fixed).is_ok());
+
+    let position = |uri: &str, line: usize, character: usize| format!(r#"{{"textDocument":{{"uri":"{}"}},"position":{{"line":{},"character":{}}}"#, uri, line, character);
+
+    let prepare = send(&mut server, "textDocument/prepareRename", position("file:///w/main%2B.casl2", 5, 19) + "}");
+    assert_eq!(prepare.get("result"), Some(&Json::Null));
+
+    // 注釈と文字定数の LOOP は変えない
+    let r = send(&mut server, "textDocument/rename", position("file:///w/ren.casl2", 2, 19) + r#","newName":"NEXT"}"#);
+    assert_eq!(apply(rename, r.get("result").unwrap(), "file:///w/ren.casl2"), rename.replace("LOOP     LD", "NEXT     LD").replace("JUMP    LOOP", "JUMP    NEXT").replace(",LOOP", ",NEXT"));
+
+    // プログラム名は他の文書の参照も変える
+    let r = send(&mut server, "textDocument/rename", position("file:///w/main%2B.casl2", 8, 0) + r#","newName":"SUB2"}"#);
+    let edits = r.get("result").and_then(|r| r.get("changes")).and_then(|c| c.get("file:///w/main%2B.casl2")).and_then(|e| e.as_array()).cloned().unwrap();
+    assert_eq!(edits.iter().map(start).collect::<Vec<i64>>(), vec![8, 2]);
+
+    for (params, message) in [
+        (position("file:///w/ren.casl2", 1, 0) + r#","newName":"MSG"}"#, "`MSG` is already used in `REN`"),
+        (position("file:///w/main%2B.casl2", 8, 0) + r#","newName":"REN"}"#, "`REN` is already used in `REN`"),
+        (position("file:///w/ren.casl2", 1, 0) + r#","newName":"gr1"}"#, "1st letter of label name is uppercase: `gr1`"),
+    ].iter() {
+        let r = send(&mut server, "textDocument/rename", params.to_string());
+        assert_eq!(r.get("error").and_then(|e| e.get("message")), Some(&Json::string(message)));
+    }
+
+    // エラーで読めない行の参照を取りこぼさない
+    let bad = "BAD      START\n         JUMP    LOOP\n         LD      GR1,LOOP,GR0\n         END\n";
+    server.handle(&Json::parse(&format!(r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///w/bad.casl2","text":{}}}}}}}"#, Json::string(bad))).unwrap());
+    let r = send(&mut server, "textDocument/rename", position("file:///w/ren.casl2", 2, 19) + r#","newName":"NEXT"}"#);
+    assert_eq!(r.get("error").and_then(|e| e.get("message")), Some(&Json::string("Fix the errors in `file:///w/bad.casl2` before renaming `LOOP`")));
+
+    server.handle(&Json::parse(r#"{"jsonrpc":"2.0","method":"exit"}"#).unwrap());
+    assert!(server.quit);
+}
@@ -1,6 +1,3 @@
 pub fn is_macro(s: &str) -> bool {
-    match s {
-        "IN" | "OUT" | "RPUSH" | "RPOP" => true,
-        _ => false
-    }
+    matches!(s, "IN" | "OUT" | "RPUSH" | "RPOP")
 }
@@ -0,0 +1,200 @@
+use std::fmt;
+use std::str::Lines;
+use std::iter::Peekable;
+
+// 再配置可能なオブジェクト
+//
+// 番地は全てプログラムの先頭を0とした相対番地で持つ
+#[derive(Debug,Clone,PartialEq)]
+pub struct Object {
+    pub name: String,
+    pub code: Vec<u16>,
+    // 外部に公開するラベルとその番地 (STARTのラベル)
+    pub defs: Vec<(String,u16)>,
+    // 外部ラベルを参照している語の位置
+    pub refs: Vec<(String,u16)>,
+    // 自プログラム内の番地を持つ語の位置
+    pub relocs: Vec<u16>,
+    // プログラム内の全てのラベル (逆アセンブルやデバッグ用で，リンクには使わない)
+    pub labels: Vec<(String,u16)>,
+    // ソースのファイル名と，番地ごとの行番号 (1始まり)．デバッグ情報がなければ空
+    pub file: String,
+    pub lines: Vec<(u16,usize)>,
+}
+
+impl Object {
+
+    pub fn new(name: &str) -> Object {
+        Object{
+            name: name.to_string(),
+            code: Vec::new(),
+            defs: Vec::new(),
+            refs: Vec::new(),
+            relocs: Vec::new(),
+            labels: Vec::new(),
+            file: String::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    // デバッグ情報を取り除く
+    pub fn strip(&mut self) {
+        self.file.clear();
+        self.lines.clear();
+    }
+
+    pub fn defines(&self, symbol: &str) -> bool {
+        self.defs.iter().any(|(name, _)| name == symbol)
+    }
+
+    // リンクや行番号で使う位置がコードの中にあるか
+    fn check_offsets(&self) -> Result<(), String> {
+
+        let offsets = self.defs.iter().map(|(_, a)| ("DEF", *a))
+            .chain(self.refs.iter().map(|(_, a)| ("REF", *a)))
+            .chain(self.relocs.iter().map(|a| ("RELOC", *a)))
+            .chain(self.lines.iter().map(|(a, _)| ("LINE", *a)));
+
+        for (record, addr) in offsets {
+            // 空のプログラムの入口 (0) だけは許す
+            if record == "DEF" && addr == 0 && self.code.is_empty() {
+                continue;
+            }
+            if addr as usize >= self.code.len() {
+                return Err(format!("{} {:0>4x} is out of the code of `{}` ({} words)", record, addr, self.name, self.code.len()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn parse(s: &str) -> Result<Object, String> {
+
+        let mut lines = s.lines().peekable();
+        let obj = Object::read(&mut lines)?;
+
+        if lines.any(|l| !l.trim().is_empty()) {
+            return Err("Trailing data after object".to_string());
+        }
+
+        Ok(obj)
+    }
+
+    // アーカイブからも読めるように，行のイテレータから1つ分だけ読み進める
+    pub fn read(lines: &mut Peekable<Lines>) -> Result<Object, String> {
+
+        let name = match lines.next().map(|l| l.split_whitespace().collect::<Vec<&str>>()) {
+            Some(ref v) if v.len() == 2 && v[0] == "CASL2OBJ" => v[1].to_string(),
+            _ => return Err("Not a CASL2 object".to_string()),
+        };
+
+        let mut obj = Object::new(&name);
+
+        while let Some(line) = lines.next() {
+
+            // ファイル名には空白を含められるので行末までとる
+            if let Some(file) = line.strip_prefix("FILE ") {
+                obj.file = file.to_string();
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+
+            match fields.as_slice() {
+                ["DEF", name, addr] => obj.defs.push((name.to_string(), parse_word(addr)?)),
+                ["REF", name, addr] => obj.refs.push((name.to_string(), parse_word(addr)?)),
+                ["RELOC", addr] => obj.relocs.push(parse_word(addr)?),
+                ["SYM", name, addr] => obj.labels.push((name.to_string(), parse_word(addr)?)),
+                ["LINE", addr, n] => {
+                    let n = n.parse::<usize>().map_err(|_| format!("Invalid line number: `{}`", n))?;
+                    obj.lines.push((parse_word(addr)?, n));
+                },
+                ["CODE", len] => {
+                    let len = len.parse::<usize>().map_err(|_| format!("Invalid code length: `{}`", len))?;
+                    for _ in 0..len {
+                        match lines.next() {
+                            Some(v) => obj.code.push(parse_word(v.trim())?),
+                            None => return Err(format!("Object `{}` is truncated", name)),
+                        }
+                    }
+                    obj.check_offsets()?;
+                    return Ok(obj);
+                },
+                _ => return Err(format!("Invalid object record: `{}`", line)),
+            }
+        }
+
+        Err(format!("Object `{}` has no code", name))
+    }
+}
+
+impl fmt::Display for Object {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        writeln!(f, "CASL2OBJ {}", self.name)?;
+
+        for (name, addr) in &self.defs {
+            writeln!(f, "DEF {} {:0>4x}", name, addr)?;
+        }
+
+        for (name, addr) in &self.refs {
+            writeln!(f, "REF {} {:0>4x}", name, addr)?;
+        }
+
+        for addr in &self.relocs {
+            writeln!(f, "RELOC {:0>4x}", addr)?;
+        }
+
+        for (name, addr) in &self.labels {
+            writeln!(f, "SYM {} {:0>4x}", name, addr)?;
+        }
+
+        if !self.file.is_empty() {
+            writeln!(f, "FILE {}", self.file)?;
+        }
+
+        for (addr, n) in &self.lines {
+            writeln!(f, "LINE {:0>4x} {}", addr, n)?;
+        }
+
+        writeln!(f, "CODE {}", self.code.len())?;
+
+        for v in &self.code {
+            writeln!(f, "{:0>4x}", v)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_word(s: &str) -> Result<u16, String> {
+    u16::from_str_radix(s, 16).map_err(|_| format!("Invalid word: `{}`", s))
+}
+
+#[test]
+fn test_object_round_trip() {
+
+    let mut obj = Object::new("MAIN");
+    obj.code = vec![0x8000, 0x0000, 0x1210, 0x0004, 0x8100];
+    obj.defs.push(("MAIN".to_string(), 0));
+    obj.refs.push(("MULT".to_string(), 1));
+    obj.relocs.push(3);
+    obj.labels.push(("MAIN".to_string(), 0));
+
+    let text = obj.to_string();
+    assert_eq!(Object::parse(&text), Ok(obj.clone()));
+
+    obj.file = "my prog.casl2".to_string();
+    obj.lines = vec![(0, 2), (2, 3), (4, 5)];
+
+    let text = obj.to_string();
+    assert!(text.contains("FILE my prog.casl2\nLINE 0000 2\n"));
+    assert_eq!(Object::parse(&text), Ok(obj));
+    assert!(Object::parse("0003\n1010\n").is_err());
+
+    // コードの外を指す位置は読み込まない
+    assert_eq!(Object::parse("CASL2OBJ MAIN\nRELOC 0010\nCODE 1\n8100\n"), Err("RELOC 0010 is out of the code of `MAIN` (1 words)".to_string()));
+    assert!(Object::parse("CASL2OBJ MAIN\nREF SUB 0001\nCODE 1\n8100\n").is_err());
+    assert!(Object::parse("CASL2OBJ MAIN\nDEF MAIN 0000\nCODE 0\n").is_ok());
+}
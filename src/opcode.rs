@@ -1,74 +1,217 @@
-/*
-pub enum Opcode {
-    NOP,    
-    LD,
-    LAD,
-    ST,
-    ADDA,
-    ADDL,
-    SUBA,
-    SUBL,
-    AND,
-    OR,
-    XOR,
-    CPA,
-    CPL,
-    SLA,
-    SRA,
-    SLL,
-    SRL,
-    JMI,
-    JNZ,    
-    JZE,
-    JUMP,
-    JPL,
-    JOV,
-    PUSH,
-    POP,
-    CALL,
-    RET,
-    SVC,
-}
-*/
-pub fn is_opcode(s: &str) -> bool {
-    match s {
-        "LD" | "ST" | "LAD" | "ADDA" | "SUBA" | "ADDL" | "SUBL" |
-        "CPA" | "CPL" | "SLL" | "SRL" | "SLA" | "SRA" | "CALL" | "RET" |
-        "PUSH" | "POP" | "JMI" | "JZE" | "JNZ" | "JPL" | "JUMP" | "JOV" => true,
-        _ => false,
+use self::Format::*;
+use self::Flags::*;
+use self::Access::*;
+
+// オペランドの形式
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Format {
+    // NOP, RET
+    NoOperand,
+    // POP r
+    R,
+    // LD r1,r2
+    R1R2,
+    // LD r,adr,x
+    RAdrX,
+    // JUMP adr,x
+    AdrX,
+}
+
+impl Format {
+
+    pub fn words(&self) -> u16 {
+        match *self {
+            NoOperand | R | R1R2 => 1,
+            RAdrX | AdrX => 2,
+        }
+    }
+
+    pub fn syntax(&self) -> &'static str {
+        match *self {
+            NoOperand => "",
+            R => "r",
+            R1R2 => "r1,r2",
+            RAdrX => "r,adr[,x]",
+            AdrX => "adr[,x]",
+        }
+    }
+}
+
+// フラグレジスタの変化
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Flags {
+    Unchanged,
+    // OF, SF, ZF を設定する
+    All,
+    // OF を0にして SF, ZF を設定する
+    ClearOF,
+}
+
+impl Flags {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Unchanged => "-",
+            All => "OF,SF,ZF",
+            ClearOF => "SF,ZF (OF=0)",
+        }
     }
 }
 
+// 実効番地の使い方
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Access {
+    // 実効番地を使わない
+    Nothing,
+    // 実効番地の内容を読む
+    Read,
+    // 実効番地に書き込む
+    Write,
+    // 実効番地そのものを値として使う
+    Value,
+    // 実効番地へ分岐する
+    Branch,
+}
+
+#[derive(Debug,PartialEq)]
+pub struct Instruction {
+    pub mnemonic: &'static str,
+    pub code: u16,
+    pub format: Format,
+    pub flags: Flags,
+    pub access: Access,
+    pub description: &'static str,
+}
+
+impl Instruction {
+    pub fn words(&self) -> u16 {
+        self.format.words()
+    }
+}
+
+macro_rules! inst {
+    ($mnemonic:expr, $code:expr, $format:expr, $flags:expr, $access:expr, $description:expr) => {
+        Instruction{
+            mnemonic: $mnemonic,
+            code: $code,
+            format: $format,
+            flags: $flags,
+            access: $access,
+            description: $description,
+        }
+    };
+}
+
+// COMET2の命令表
+//
+// 同じニーモニックでも形式によって命令コードが違うものは別の行にする
+pub const INSTRUCTIONS: [Instruction; 38] = [
+    inst!("NOP", 0x00, NoOperand, Unchanged, Nothing, "ノーオペレーション: 何もしない"),
+    inst!("LD", 0x10, RAdrX, ClearOF, Read, "ロード: r ← (実効番地)"),
+    inst!("ST", 0x11, RAdrX, Unchanged, Write, "ストア: 実効番地 ← (r)"),
+    inst!("LAD", 0x12, RAdrX, Unchanged, Value, "ロードアドレス: r ← 実効番地"),
+    inst!("LD", 0x14, R1R2, ClearOF, Nothing, "ロード: r1 ← (r2)"),
+    inst!("ADDA", 0x20, RAdrX, All, Read, "算術加算: r ← (r) + (実効番地)"),
+    inst!("SUBA", 0x21, RAdrX, All, Read, "算術減算: r ← (r) - (実効番地)"),
+    inst!("ADDL", 0x22, RAdrX, All, Read, "論理加算: r ← (r) +L (実効番地)"),
+    inst!("SUBL", 0x23, RAdrX, All, Read, "論理減算: r ← (r) -L (実効番地)"),
+    inst!("ADDA", 0x24, R1R2, All, Nothing, "算術加算: r1 ← (r1) + (r2)"),
+    inst!("SUBA", 0x25, R1R2, All, Nothing, "算術減算: r1 ← (r1) - (r2)"),
+    inst!("ADDL", 0x26, R1R2, All, Nothing, "論理加算: r1 ← (r1) +L (r2)"),
+    inst!("SUBL", 0x27, R1R2, All, Nothing, "論理減算: r1 ← (r1) -L (r2)"),
+    inst!("AND", 0x30, RAdrX, ClearOF, Read, "論理積: r ← (r) AND (実効番地)"),
+    inst!("OR", 0x31, RAdrX, ClearOF, Read, "論理和: r ← (r) OR (実効番地)"),
+    inst!("XOR", 0x32, RAdrX, ClearOF, Read, "排他的論理和: r ← (r) XOR (実効番地)"),
+    inst!("AND", 0x34, R1R2, ClearOF, Nothing, "論理積: r1 ← (r1) AND (r2)"),
+    inst!("OR", 0x35, R1R2, ClearOF, Nothing, "論理和: r1 ← (r1) OR (r2)"),
+    inst!("XOR", 0x36, R1R2, ClearOF, Nothing, "排他的論理和: r1 ← (r1) XOR (r2)"),
+    inst!("CPA", 0x40, RAdrX, ClearOF, Read, "算術比較: (r) と (実効番地) を符号付きで比較する"),
+    inst!("CPL", 0x41, RAdrX, ClearOF, Read, "論理比較: (r) と (実効番地) を符号なしで比較する"),
+    inst!("CPA", 0x44, R1R2, ClearOF, Nothing, "算術比較: (r1) と (r2) を符号付きで比較する"),
+    inst!("CPL", 0x45, R1R2, ClearOF, Nothing, "論理比較: (r1) と (r2) を符号なしで比較する"),
+    inst!("SLA", 0x50, RAdrX, All, Value, "算術左シフト: 符号を除き (r) を実効番地のビット数だけ左へ"),
+    inst!("SRA", 0x51, RAdrX, All, Value, "算術右シフト: 符号を除き (r) を実効番地のビット数だけ右へ"),
+    inst!("SLL", 0x52, RAdrX, All, Value, "論理左シフト: (r) を実効番地のビット数だけ左へ"),
+    inst!("SRL", 0x53, RAdrX, All, Value, "論理右シフト: (r) を実効番地のビット数だけ右へ"),
+    inst!("JMI", 0x61, AdrX, Unchanged, Branch, "負分岐: SF = 1 なら実効番地へ分岐する"),
+    inst!("JNZ", 0x62, AdrX, Unchanged, Branch, "非零分岐: ZF = 0 なら実効番地へ分岐する"),
+    inst!("JZE", 0x63, AdrX, Unchanged, Branch, "零分岐: ZF = 1 なら実効番地へ分岐する"),
+    inst!("JUMP", 0x64, AdrX, Unchanged, Branch, "無条件分岐: 実効番地へ分岐する"),
+    inst!("JPL", 0x65, AdrX, Unchanged, Branch, "正分岐: SF = 0 かつ ZF = 0 なら実効番地へ分岐する"),
+    inst!("JOV", 0x66, AdrX, Unchanged, Branch, "オーバフロー分岐: OF = 1 なら実効番地へ分岐する"),
+    inst!("PUSH", 0x70, AdrX, Unchanged, Value, "プッシュ: SP ← (SP) - 1, (SP) ← 実効番地"),
+    inst!("POP", 0x71, R, Unchanged, Nothing, "ポップ: r ← ((SP)), SP ← (SP) + 1"),
+    inst!("CALL", 0x80, AdrX, Unchanged, Branch, "コール: SP ← (SP) - 1, (SP) ← (PR), PR ← 実効番地"),
+    inst!("RET", 0x81, NoOperand, Unchanged, Nothing, "リターン: PR ← ((SP)), SP ← (SP) + 1"),
+    inst!("SVC", 0xf0, AdrX, Unchanged, Value, "スーパバイザコール: 実効番地で指定した機能を呼び出す"),
+];
+
+pub fn is_opcode(s: &str) -> bool {
+    INSTRUCTIONS.iter().any(|i| i.mnemonic == s)
+}
+
+// ニーモニックとオペランドの形式から命令を探す
+pub fn lookup(mnemonic: &str, format: Format) -> Option<&'static Instruction> {
+    INSTRUCTIONS.iter().find(|i| i.mnemonic == mnemonic && i.format == format)
+}
+
+// 命令コード (1語目の上位8ビット) から命令を探す
+pub fn lookup_code(code: u16) -> Option<&'static Instruction> {
+    INSTRUCTIONS.iter().find(|i| i.code == code)
+}
+
+// ニーモニックが取りうるオペランドの形式
+pub fn formats(mnemonic: &str) -> Vec<Format> {
+    INSTRUCTIONS
+        .iter()
+        .filter(|i| i.mnemonic == mnemonic)
+        .map(|i| i.format)
+        .collect()
+}
+
+// 「LD r1,r2」のような書き方 (ニーモニックが取りうる形式ごと)
+pub fn usage(mnemonic: &str) -> Vec<String> {
+    formats(mnemonic)
+        .iter()
+        .map(|f| format!("{} {}", mnemonic, f.syntax()).trim_end().to_string())
+        .collect()
+}
+
 pub fn get_opcode(s: &str) -> u16 {
-    
-    match s {
-        "NOP" => 0x00,
-        "LD" => 0x10,
-        "ST" => 0x11,
-        "LAD" => 0x12,
-        "ADDA" => 0x20,
-        "SUBA" => 0x21,
-        "ADDL" => 0x22,
-        "SUBL" => 0x23,
-        "AND" => 0x30,
-        "OR" => 0x31,
-        "XOR" => 0x32,
-        "CPA" => 0x40,
-        "CPL" => 0x41,
-        "SLA" => 0x50,
-        "SRA" => 0x51,
-        "SLL" => 0x52,
-        "SRL" => 0x53,
-        "JMI" => 0x61,
-        "JNZ" => 0x62,
-        "JZE" => 0x63,
-        "JUMP" => 0x64,        
-        "JPL" => 0x65,
-        "JOV" => 0x66,
-        "PUSH" => 0x70,
-        "POP" => 0x71,        
-        "CALL" => 0x80,
-        "RET" => 0x81,
-        _ => 0xff
+    match INSTRUCTIONS.iter().find(|i| i.mnemonic == s) {
+        Some(i) => i.code,
+        None => 0xff,
+    }
+}
+
+// 命令表をMarkdownの表にする
+pub fn reference() -> String {
+
+    let mut s = String::new();
+
+    s.push_str("| 命令 | オペランド | コード | 語数 | FR | 説明 |\n");
+    s.push_str("|------|------------|--------|------|----|------|\n");
+
+    for i in INSTRUCTIONS.iter() {
+        s.push_str(&format!("| {} | {} | #{:0>2X} | {} | {} | {} |\n",
+                            i.mnemonic, i.format.syntax(), i.code, i.words(), i.flags.name(), i.description));
+    }
+
+    s
+}
+
+#[test]
+fn test_instructions() {
+
+    assert!(is_opcode("NOP"));
+    assert!(!is_opcode("IN"));
+    assert_eq!(get_opcode("CALL"), 0x80);
+    assert_eq!(lookup("LD", R1R2).unwrap().code, 0x14);
+    assert!(lookup("ST", R1R2).is_none());
+    assert_eq!(formats("ADDA"), vec![RAdrX, R1R2]);
+    assert_eq!(lookup_code(0xf0).unwrap().mnemonic, "SVC");
+
+    // 命令コードが重複していないこと
+    for i in INSTRUCTIONS.iter() {
+        assert_eq!(lookup_code(i.code), Some(i));
     }
 }
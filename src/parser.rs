@@ -0,0 +1,362 @@
+use ast::{Span,Spanned,Error,ErrorKind,Operand,Macro,Statement,Line,Program};
+use assembler::is_assembler;
+use constant::{Constant,label_error};
+use opcode::{is_opcode,lookup,usage,Instruction};
+use opcode::Format::*;
+use register::Register;
+use token::{tokenize,Token,TokenType};
+
+// ソース全体を構文木にする
+//
+// エラーのある行は読み飛ばして続け，正しく読めた行とエラーの両方を返す
+pub fn parse(src: &str) -> (Vec<Line>, Vec<Error>) {
+
+    let mut lines: Vec<Line> = Vec::new();
+    let mut errors: Vec<Error> = Vec::new();
+
+    for (i, code) in src.lines().enumerate() {
+        match parse_line(i, code) {
+            Ok(Some(l)) => lines.push(l),
+            Ok(None) => {},
+            Err(e) => errors.push(e),
+        }
+    }
+
+    (lines, errors)
+}
+
+// START から END までをプログラムにまとめる
+pub fn parse_programs(src: &str) -> (Vec<Program>, Vec<Error>) {
+
+    let (lines, mut errors) = parse(src);
+    let mut programs: Vec<Program> = Vec::new();
+    let mut current: Option<Program> = None;
+
+    for line in lines {
+
+        match (current.take(), &line.statement) {
+            (None, &Statement::Start(_)) => {
+                let name = line.label.as_ref().map_or(String::new(), |l| l.node.to_string());
+                current = Some(Program{name, lines: vec![line]});
+            },
+            (None, _) => {
+                errors.push(Error::new(ErrorKind::MissingStart, line.opcode.span, "Program must begin with START".to_string()));
+            },
+            (Some(p), &Statement::Start(_)) => {
+                errors.push(Error::new(ErrorKind::MissingEnd, line.opcode.span, format!("Missing END before START of `{}`", line.label.as_ref().map_or("", |l| &l.node))));
+                programs.push(p);
+                let name = line.label.as_ref().map_or(String::new(), |l| l.node.to_string());
+                current = Some(Program{name, lines: vec![line]});
+            },
+            (Some(mut p), &Statement::End) => {
+                p.lines.push(line);
+                programs.push(p);
+            },
+            (Some(mut p), _) => {
+                p.lines.push(line);
+                current = Some(p);
+            },
+        }
+    }
+
+    // ENDがなければソースの末尾を指す
+    if let Some(p) = current {
+        let last = src.lines().count().saturating_sub(1);
+        let len = src.lines().last().map_or(0, |l| l.len());
+        errors.push(Error::new(ErrorKind::MissingEnd, Span::new(last, len, len), format!("Missing END of `{}`", p.name)));
+        programs.push(p);
+    }
+
+    (programs, errors)
+}
+
+// 1行を構文木にする
+//
+// 空行と注釈だけの行はNone
+pub fn parse_line(number: usize, code: &str) -> Result<Option<Line>, Error> {
+
+    let tokens = tokenize(number, code)?;
+
+    let comment = tokens
+        .iter()
+        .find(|t| t.kind == TokenType::Comment)
+        .map(|t| Spanned::new(t.value.to_string(), t.span));
+
+    let label = match tokens.first() {
+        Some(t) if t.kind == TokenType::Label => {
+            if let Some(e) = label_error(&t.value) {
+                return Err(Error::new(ErrorKind::InvalidLabel, t.span, format!("{}: `{}`", e, t.value)));
+            }
+            Some(Spanned::new(t.value.to_string(), t.span))
+        },
+        _ => None,
+    };
+
+    let opcode = match tokens.iter().find(|t| t.kind == TokenType::Opcode) {
+        Some(t) => Spanned::new(t.value.to_string(), t.span),
+        None => match label {
+            Some(l) => return Err(Error::new(ErrorKind::MissingInstruction, l.span, format!("Missing instruction after label `{}`", l.node))),
+            None => return Ok(None),
+        },
+    };
+
+    let args = tokens
+        .iter()
+        .filter(|t| t.kind == TokenType::Operand)
+        .collect::<Vec<&Token>>();
+
+    let span = match args.last() {
+        Some(t) => label.as_ref().map_or(opcode.span, |l| l.span).to(t.span),
+        None => label.as_ref().map_or(opcode.span, |l| l.span).to(opcode.span),
+    };
+
+    let op: &str = &opcode.node;
+
+    let statement = if is_assembler(op) {
+        assembler_statement(op, &label, &opcode, &args)?
+    } else if let Some(m) = Macro::parse(op) {
+        macro_statement(m, &opcode, &args)?
+    } else if is_opcode(op) {
+        let operands = args.iter().map(|t| operand(t)).collect::<Result<Vec<Spanned<Operand>>, Error>>()?;
+        let inst = instruction(&opcode, &operands)?;
+        Statement::Instruction(inst, operands)
+    } else {
+        return Err(Error::new(ErrorKind::UnknownInstruction, opcode.span, format!("Unknown instruction `{}`", op)));
+    };
+
+    Ok(Some(Line{label, opcode, statement, comment, span}))
+}
+
+fn operand(t: &Token) -> Result<Spanned<Operand>, Error> {
+
+    let s: &str = &t.value;
+
+    let node = if let Some(r) = Register::parse(s) {
+        Operand::Register(r)
+    } else if let Some(c) = s.strip_prefix('=') {
+        match Constant::parse(c) {
+            Some(Constant::Address(_)) | None => return Err(Error::new(ErrorKind::InvalidConstant, t.span, format!("Invalid literal `{}`", s))),
+            Some(Constant::Char(ref v)) if v.is_empty() => return Err(Error::new(ErrorKind::InvalidConstant, t.span, "Empty character constant".to_string())),
+            Some(c) => Operand::Literal(c),
+        }
+    } else {
+        match Constant::parse(s) {
+            Some(c) => Operand::Constant(c),
+            None => return Err(invalid_constant(t)),
+        }
+    };
+
+    Ok(Spanned::new(node, t.span))
+}
+
+fn invalid_constant(t: &Token) -> Error {
+
+    let s: &str = &t.value;
+
+    // 数字で始まらないものはラベルの書き間違いとみなす
+    match label_error(s) {
+        Some(e) if !s.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '#' || c == '\'') => Error::new(ErrorKind::InvalidLabel, t.span, format!("{}: `{}`", e, s)),
+        _ => Error::new(ErrorKind::InvalidConstant, t.span, format!("Invalid constant `{}`", s)),
+    }
+}
+
+fn assembler_statement(op: &str, label: &Option<Spanned<String>>, opcode: &Spanned<String>, args: &[&Token]) -> Result<Statement, Error> {
+
+    let too_many = |t: &Token| Error::new(ErrorKind::InvalidOperand, t.span, format!("Too many operands for {}", op));
+
+    match op {
+
+        "START" => {
+
+            // STARTの時は，ラベル必須
+            if label.is_none() {
+                return Err(Error::new(ErrorKind::InvalidStatement, opcode.span, "START needs a label".to_string()));
+            }
+
+            // オペランドは１つのみ
+            if let Some(t) = args.get(1) {
+                return Err(too_many(t));
+            }
+
+            match args.first() {
+                Some(t) => match label_error(&t.value) {
+                    None => Ok(Statement::Start(Some(Spanned::new(t.value.to_string(), t.span)))),
+                    Some(e) => Err(Error::new(ErrorKind::InvalidLabel, t.span, format!("{}: `{}`", e, t.value))),
+                },
+                None => Ok(Statement::Start(None)),
+            }
+        },
+
+        "END" => {
+
+            // ENDのときはラベルつけられない
+            if let Some(l) = label {
+                return Err(Error::new(ErrorKind::InvalidStatement, l.span, "END can't have a label".to_string()));
+            }
+
+            match args.first() {
+                Some(t) => Err(too_many(t)),
+                None => Ok(Statement::End),
+            }
+        },
+
+        "DS" => {
+
+            if let Some(t) = args.get(1) {
+                return Err(too_many(t));
+            }
+
+            match args.first() {
+                Some(t) => match Constant::parse(&t.value) {
+                    Some(Constant::Decimal(v)) if v >= 0 => Ok(Statement::Ds(v as u16)),
+                    _ => Err(Error::new(ErrorKind::InvalidOperand, t.span, format!("DS needs a word count: `{}`", t.value))),
+                },
+                None => Err(Error::new(ErrorKind::InvalidOperand, opcode.span, "DS needs a word count".to_string())),
+            }
+        },
+
+        _ => {
+
+            if args.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidOperand, opcode.span, "DC needs at least one constant".to_string()));
+            }
+
+            let mut constants: Vec<Spanned<Constant>> = Vec::new();
+
+            for t in args {
+                match Constant::parse(&t.value) {
+                    Some(Constant::Char(ref v)) if v.is_empty() => return Err(Error::new(ErrorKind::InvalidConstant, t.span, "Empty character constant".to_string())),
+                    Some(c) => constants.push(Spanned::new(c, t.span)),
+                    None => return Err(invalid_constant(t)),
+                }
+            }
+
+            Ok(Statement::Dc(constants))
+        },
+    }
+}
+
+fn macro_statement(m: Macro, opcode: &Spanned<String>, args: &[&Token]) -> Result<Statement, Error> {
+
+    let operands = args.iter().map(|t| operand(t)).collect::<Result<Vec<Spanned<Operand>>, Error>>()?;
+
+    match m {
+
+        Macro::In | Macro::Out => {
+
+            if operands.len() != 2 {
+                return Err(Error::new(ErrorKind::InvalidOperand, opcode.span, format!("{} needs two labels: `{} buffer,length`", opcode.node, opcode.node)));
+            }
+
+            if let Some(o) = operands.iter().find(|o| o.node.label().is_none()) {
+                return Err(Error::new(ErrorKind::InvalidOperand, o.span, format!("Operand of {} must be a label", opcode.node)));
+            }
+        },
+
+        Macro::Rpush | Macro::Rpop => {
+            if let Some(o) = operands.first() {
+                return Err(Error::new(ErrorKind::InvalidOperand, o.span, format!("Too many operands for {}", opcode.node)));
+            }
+        },
+    }
+
+    Ok(Statement::Macro(m, operands))
+}
+
+// オペランドの並びから命令表の行を決める
+fn instruction(opcode: &Spanned<String>, args: &[Spanned<Operand>]) -> Result<&'static Instruction, Error> {
+
+    let op: &str = &opcode.node;
+
+    let is_register = |o: &Spanned<Operand>| o.node.register().is_some();
+
+    let format = match args {
+        [] => NoOperand,
+        [r] if is_register(r) => R,
+        [r1, r2] if is_register(r1) && is_register(r2) => R1R2,
+        [r, ..] if is_register(r) => RAdrX,
+        _ => AdrX,
+    };
+
+    let span = match args.last() {
+        Some(o) => opcode.span.to(o.span),
+        None => opcode.span,
+    };
+
+    let inst = match lookup(op, format) {
+        Some(inst) => inst,
+        None => {
+            let expected = usage(op)
+                .iter()
+                .map(|u| format!("`{}`", u))
+                .collect::<Vec<String>>()
+                .join(" or ");
+            return Err(Error::new(ErrorKind::InvalidOperand, span, format!("Invalid operands for {}: expected {}", op, expected)));
+        }
+    };
+
+    // adrとxの位置
+    let (adr, max) = match format {
+        RAdrX => (1, 3),
+        AdrX => (0, 2),
+        _ => return Ok(inst),
+    };
+
+    if let Some(o) = args.get(max) {
+        return Err(Error::new(ErrorKind::InvalidOperand, o.span, format!("Too many operands for {}", op)));
+    }
+
+    match args[adr].node {
+        Operand::Register(_) => return Err(Error::new(ErrorKind::InvalidOperand, args[adr].span, format!("Operand `{}` of {} is not adr", args[adr].node.register().unwrap(), op))),
+        Operand::Constant(Constant::Char(_)) => return Err(Error::new(ErrorKind::InvalidOperand, args[adr].span, format!("Character constant can't be adr of {} (use a literal)", op))),
+        _ => {},
+    }
+
+    // xはGR0を除くGR
+    if let Some(x) = args.get(adr + 1) {
+        match x.node.register() {
+            Some(r) if r.is_index() => {},
+            _ => return Err(Error::new(ErrorKind::InvalidIndex, x.span, format!("Index register of {} needs GR1 to GR7", op))),
+        }
+    }
+
+    Ok(inst)
+}
+
+#[test]
+fn test_parse_line() {
+
+    let l = parse_line(2, "LOOP  LD GR1,='AB',GR2 ; 注釈").unwrap().unwrap();
+    assert_eq!(l.label, Some(Spanned::new("LOOP".to_string(), Span::new(2, 0, 4))));
+    assert_eq!(l.statement.r(), Some(Register::GR1));
+    assert_eq!(l.statement.literal(), Some(&Constant::Char("AB".to_string())));
+    assert_eq!(l.statement.x(), Some(Register::GR2));
+    assert_eq!(l.statement.words(), 2);
+    assert_eq!(l.comment.unwrap().node, "; 注釈");
+    assert_eq!(l.span, Span::new(2, 0, 22));
+
+    let l = parse_line(0, "\tDC\t-1,#FFFF,'a,b',END").unwrap().unwrap();
+    assert_eq!(l.statement, Statement::Dc(vec![
+        Spanned::new(Constant::Decimal(-1), Span::new(0, 4, 6)),
+        Spanned::new(Constant::Hex(0xffff), Span::new(0, 7, 12)),
+        Spanned::new(Constant::Char("a,b".to_string()), Span::new(0, 13, 18)),
+        Spanned::new(Constant::Address("END".to_string()), Span::new(0, 19, 22)),
+    ]));
+    assert_eq!(l.statement.words(), 6);
+
+    assert_eq!(parse_line(0, "   ; 注釈"), Ok(None));
+
+    let e = parse_line(0, " LD GR1,'A'").unwrap_err();
+    assert_eq!(e.span, Span::new(0, 8, 11));
+    let e = parse_line(0, " JUMP GR1").unwrap_err();
+    assert_eq!(e.message, "Invalid operands for JUMP: expected `JUMP adr[,x]`");
+    assert!(parse_line(0, " LD GR1,X,GR0").is_err());
+    assert!(parse_line(0, " ld GR1,X").is_err());
+    assert!(parse_line(0, "lower START").is_err());
+    assert!(parse_line(0, "L END").is_err());
+
+    let (programs, errors) = parse_programs("A START\n RET\n END\nB START\n RET\n");
+    assert_eq!(programs.iter().map(|p| p.name.as_str()).collect::<Vec<&str>>(), vec!["A", "B"]);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "Missing END of `B`");
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+
+use comet2::{Comet2,Hook};
+use console::Console;
+use disasm::decode;
+use token::SymbolTable;
+
+// 命令ごとのサイクル数
+//
+// 1行に「命令名 サイクル数」を書く．「* サイクル数」は書いていない命令のもの (既定は1)．
+// 「;」から行末までは注釈
+#[derive(Debug,Clone,PartialEq)]
+pub struct CostModel {
+    pub costs: HashMap<String,u64>,
+    pub default: u64,
+}
+
+impl CostModel {
+
+    pub fn parse(s: &str) -> Result<CostModel, String> {
+
+        let mut model = CostModel{costs: HashMap::new(), default: 1};
+
+        for (i, line) in s.lines().enumerate() {
+
+            let line = line.split(';').next().unwrap_or("");
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+
+            match fields.as_slice() {
+                [] => {},
+                [name, n] => {
+                    let n = n.parse::<u64>().map_err(|_| format!("{}: Invalid cycles: `{}`", i + 1, n))?;
+                    if *name == "*" {
+                        model.default = n;
+                    } else {
+                        model.costs.insert(name.to_string(), n);
+                    }
+                },
+                _ => return Err(format!("{}: Expected `MNEMONIC CYCLES`", i + 1)),
+            }
+        }
+
+        Ok(model)
+    }
+
+    pub fn cost(&self, mnemonic: &str) -> u64 {
+        self.costs.get(mnemonic).cloned().unwrap_or(self.default)
+    }
+}
+
+// 命令数とサイクル数
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub struct Cost {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+impl Cost {
+    fn add(&mut self, other: Cost) {
+        self.count += other.count;
+        self.cycles += other.cycles;
+    }
+
+    fn sub(self, other: Cost) -> Cost {
+        Cost{count: self.count - other.count, cycles: self.cycles - other.cycles}
+    }
+}
+
+// CALLで呼ばれたサブルーチンごとの集計
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub struct Function {
+    pub calls: u64,
+    // そのサブルーチン自身で実行した分
+    pub own: Cost,
+    // 呼び出したサブルーチンも含めた分 (再帰は外側の呼び出しだけ数える)
+    pub total: Cost,
+}
+
+// 呼び出し元と先の組ごとの集計
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub struct Edge {
+    pub calls: u64,
+    pub total: Cost,
+}
+
+// 実行中のサブルーチン
+struct Frame {
+    function: u16,
+    // 呼ばれたときの全体の命令数とサイクル数
+    start: Cost,
+}
+
+// 番地ごとの実行回数を数え，CALLとRETからサブルーチンの呼び出し関係を集計する
+pub struct Profiler {
+    pub counts: HashMap<u16,Cost>,
+    pub functions: HashMap<u16,Function>,
+    pub edges: HashMap<(u16,u16),Edge>,
+    pub totals: Cost,
+    model: Option<CostModel>,
+    stack: Vec<Frame>,
+}
+
+impl Profiler {
+
+    pub fn new(model: Option<CostModel>) -> Profiler {
+        Profiler{
+            counts: HashMap::new(),
+            functions: HashMap::new(),
+            edges: HashMap::new(),
+            totals: Cost::default(),
+            model,
+            stack: Vec::new(),
+        }
+    }
+
+    // 実行中のサブルーチンを含めた集計
+    //
+    // 終了した後はスタックに残っているのは最初のプログラムだけになる
+    fn unwound(&self) -> (HashMap<u16,Function>, HashMap<(u16,u16),Edge>) {
+
+        let mut functions = self.functions.clone();
+        let mut edges = self.edges.clone();
+
+        for (i, frame) in self.stack.iter().enumerate().rev() {
+            let total = self.totals.sub(frame.start);
+            if !self.stack[..i].iter().any(|f| f.function == frame.function) {
+                functions.entry(frame.function).or_default().total.add(total);
+            }
+            if i > 0 {
+                edges.entry((self.stack[i - 1].function, frame.function)).or_default().total.add(total);
+            }
+        }
+
+        (functions, edges)
+    }
+
+    // ラベルごとの命令数と，サブルーチンの呼び出し関係を表示する
+    pub fn report(&self, labels: &SymbolTable) -> String {
+
+        let names = Names::new(labels);
+        let cycles = self.model.is_some();
+
+        let percent = |n: u64| if self.totals.count == 0 { 0.0 } else { n as f64 * 100.0 / self.totals.count as f64 };
+
+        let mut s = String::new();
+
+        // その番地以前で最も近いラベルにまとめる
+        let mut flat: HashMap<String,Cost> = HashMap::new();
+        for (addr, cost) in &self.counts {
+            flat.entry(names.enclosing(*addr)).or_default().add(*cost);
+        }
+
+        let mut flat = flat.into_iter().collect::<Vec<(String,Cost)>>();
+        flat.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(&b.0)));
+
+        s.push_str("Flat profile:\n");
+        s.push_str(&format!("{:>10} {:>7}{}  label\n", "count", "%", if cycles { format!(" {:>10}", "cycles") } else { String::new() }));
+
+        for (name, cost) in &flat {
+            let c = if cycles { format!(" {:>10}", cost.cycles) } else { String::new() };
+            s.push_str(&format!("{:>10} {:>6.1}%{}  {}\n", cost.count, percent(cost.count), c, name));
+        }
+
+        let c = if cycles { format!(" {:>10}", self.totals.cycles) } else { String::new() };
+        s.push_str(&format!("{:>10} {:>6.1}%{}  (total)\n", self.totals.count, 100.0, c));
+
+        // サブルーチンごとに，呼び出し元 (<-) と呼び出し先 (->) を添える
+        let (functions, edges) = self.unwound();
+
+        let mut order = functions.iter().collect::<Vec<(&u16,&Function)>>();
+        order.sort_by(|a, b| b.1.total.count.cmp(&a.1.total.count).then(a.0.cmp(b.0)));
+
+        s.push_str("\nCall graph:\n");
+        s.push_str(&format!("{:<16} {:>8} {:>10} {:>10} {:>7}{}\n", "function", "calls", "self", "total", "%",
+                            if cycles { format!(" {:>10} {:>10}", "self cyc", "total cyc") } else { String::new() }));
+
+        for (addr, f) in order {
+
+            let c = if cycles { format!(" {:>10} {:>10}", f.own.cycles, f.total.cycles) } else { String::new() };
+            s.push_str(&format!("{:<16} {:>8} {:>10} {:>10} {:>6.1}%{}\n",
+                                names.function(*addr), f.calls, f.own.count, f.total.count, percent(f.total.count), c));
+
+            let mut callers = edges.iter().filter(|((_, to), _)| to == addr).collect::<Vec<(&(u16,u16),&Edge)>>();
+            callers.sort_by_key(|((from, _), _)| *from);
+
+            for ((from, _), e) in callers {
+                s.push_str(&format!("  <- {:<11} {:>8}\n", names.function(*from), e.calls));
+            }
+
+            let mut callees = edges.iter().filter(|((from, _), _)| from == addr).collect::<Vec<(&(u16,u16),&Edge)>>();
+            callees.sort_by(|a, b| b.1.total.count.cmp(&a.1.total.count).then((a.0).1.cmp(&(b.0).1)));
+
+            for ((_, to), e) in callees {
+                let c = if cycles { format!(" {:>10} {:>10}", "", e.total.cycles) } else { String::new() };
+                s.push_str(&format!("  -> {:<11} {:>8} {:>10} {:>10} {:>6.1}%{}\n",
+                                    names.function(*to), e.calls, "", e.total.count, percent(e.total.count), c));
+            }
+        }
+
+        s
+    }
+}
+
+impl<C: Console> Hook<C> for Profiler {
+
+    fn before(&mut self, cpu: &Comet2<C>) -> Result<(), String> {
+        if self.stack.is_empty() {
+            self.stack.push(Frame{function: cpu.pr, start: self.totals});
+            self.functions.entry(cpu.pr).or_default().calls += 1;
+        }
+        Ok(())
+    }
+
+    fn after(&mut self, cpu: &Comet2<C>, pr: u16) -> Result<(), String> {
+
+        let d = match decode(&[cpu.read(pr), cpu.read(pr.wrapping_add(1))]) {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+
+        let cost = Cost{count: 1, cycles: self.model.as_ref().map_or(1, |m| m.cost(d.inst.mnemonic))};
+
+        self.counts.entry(pr).or_default().add(cost);
+        self.totals.add(cost);
+
+        let current = self.stack.last().map_or(pr, |f| f.function);
+        self.functions.entry(current).or_default().own.add(cost);
+
+        match d.inst.mnemonic {
+            "CALL" => {
+                self.stack.push(Frame{function: cpu.pr, start: self.totals});
+                self.functions.entry(cpu.pr).or_default().calls += 1;
+                self.edges.entry((current, cpu.pr)).or_default().calls += 1;
+            },
+            // スタックが空のRET (終了) では最初のプログラムを残す
+            "RET" if self.stack.len() > 1 => {
+                let frame = self.stack.pop().unwrap();
+                let total = self.totals.sub(frame.start);
+                if !self.stack.iter().any(|f| f.function == frame.function) {
+                    self.functions.entry(frame.function).or_default().total.add(total);
+                }
+                let caller = self.stack.last().unwrap().function;
+                self.edges.entry((caller, frame.function)).or_default().total.add(total);
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+}
+
+// 番地からラベル名を引く
+struct Names {
+    // 番地の順
+    sorted: Vec<(u16,String)>,
+}
+
+impl Names {
+
+    fn new(labels: &SymbolTable) -> Names {
+        let mut sorted = labels.iter().map(|(name, addr)| (*addr, name.to_string())).collect::<Vec<(u16,String)>>();
+        sorted.sort();
+        sorted.dedup_by_key(|(addr, _)| *addr);
+        Names{sorted}
+    }
+
+    // その番地以前で最も近いラベル
+    fn enclosing(&self, addr: u16) -> String {
+        match self.sorted.binary_search_by_key(&addr, |(a, _)| *a) {
+            Ok(i) => self.sorted[i].1.to_string(),
+            Err(0) => format!("#{:0>4X}", addr),
+            Err(i) => self.sorted[i - 1].1.to_string(),
+        }
+    }
+
+    fn function(&self, addr: u16) -> String {
+        match self.sorted.binary_search_by_key(&addr, |(a, _)| *a) {
+            Ok(i) => self.sorted[i].1.to_string(),
+            Err(_) => format!("#{:0>4X}", addr),
+        }
+    }
+}
+
+#[test]
+fn test_profile() {
+
+    use assembler::assemble;
+    use console::Buffer;
+    use debuginfo::DebugInfo;
+    use linker::link;
+
+    let src = "\
+MAIN     START
+         LAD     GR1,3
+LOOP     CALL    DOUBLE
+         SUBA    GR1,=1
+         JNZ     LOOP
+         RET
+DOUBLE   ADDA    GR2,GR2
+         CALL    INC
+         RET
+INC      LAD     GR2,1,GR2
+         RET
+         END
+";
+
+    let objects = assemble(src).unwrap();
+    let info = DebugInfo::link(&objects);
+    let mut cpu = Comet2::load_with_console(&link(&objects).unwrap(), 0, Buffer::new(""));
+
+    let model = CostModel::parse("; 分岐は2\nCALL 2\nRET 2\n* 1\n").unwrap();
+    let mut profiler = Profiler::new(Some(model));
+
+    cpu.run_with(100, &mut [&mut profiler]).unwrap();
+
+    let main = info.labels["MAIN"];
+    let double = info.labels["DOUBLE"];
+    let inc = info.labels["INC"];
+
+    assert_eq!(profiler.totals, Cost{count: 26, cycles: 39});
+    assert_eq!(profiler.counts[&double], Cost{count: 3, cycles: 3});
+
+    let (functions, edges) = profiler.unwound();
+
+    assert_eq!(functions[&main], Function{calls: 1, own: Cost{count: 11, cycles: 15}, total: Cost{count: 26, cycles: 39}});
+    assert_eq!(functions[&double].calls, 3);
+    assert_eq!(functions[&double].total, Cost{count: 15, cycles: 24});
+    assert_eq!(functions[&inc].own, Cost{count: 6, cycles: 9});
+    assert_eq!(edges[&(double, inc)], Edge{calls: 3, total: Cost{count: 6, cycles: 9}});
+
+    let report = profiler.report(&info.labels);
+    assert!(report.contains("         9   34.6%         15  DOUBLE\n"));
+    assert!(report.contains("  <- DOUBLE             3\n"));
+    assert!(report.contains("        26  100.0%         39  (total)\n"));
+}
@@ -1,20 +1,74 @@
-const REGISTER: [&str; 8] = ["GR0", "GR1", "GR2", "GR3", "GR4", "GR5", "GR6" , "GR7"];
+use std::fmt;
 
-pub fn is_register(s: &str) -> bool {
-    match s {
-        "GR0" | "GR1" | "GR2" | "GR3" | "GR4" | "GR5" | "GR6" | "GR7" => true,
-        _ => false,
+use self::Register::*;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub enum Register {
+    GR0,
+    GR1,
+    GR2,
+    GR3,
+    GR4,
+    GR5,
+    GR6,
+    GR7,
+}
+
+pub const REGISTERS: [Register; 8] = [GR0, GR1, GR2, GR3, GR4, GR5, GR6, GR7];
+
+impl Register {
+
+    pub fn parse(s: &str) -> Option<Register> {
+        REGISTERS.iter().find(|r| r.name() == s).cloned()
+    }
+
+    pub fn from_number(n: u16) -> Option<Register> {
+        REGISTERS.get(n as usize).cloned()
+    }
+
+    pub fn number(self) -> u16 {
+        self as u16
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GR0 => "GR0",
+            GR1 => "GR1",
+            GR2 => "GR2",
+            GR3 => "GR3",
+            GR4 => "GR4",
+            GR5 => "GR5",
+            GR6 => "GR6",
+            GR7 => "GR7",
+        }
+    }
+
+    // 指標レジスタにはGR0を使えない
+    pub fn is_index(self) -> bool {
+        self != GR0
     }
 }
 
-pub fn is_index_register(s: &str) -> bool {
-    match s {
-        "GR1" | "GR2" | "GR3" | "GR4" | "GR5" | "GR6" | "GR7" => true,
-        _ => false,
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
     }
 }
 
-pub fn get_register_number(s: &str) -> u16 {
-    let v = REGISTER.to_vec();
-    v.iter().position(|&r| r == s).unwrap() as u16
+pub fn is_register(s: &str) -> bool {
+    Register::parse(s).is_some()
+}
+
+pub fn is_index_register(s: &str) -> bool {
+    Register::parse(s).is_some_and(|r| r.is_index())
+}
+
+#[test]
+fn test_register() {
+    assert_eq!(Register::parse("GR3"), Some(GR3));
+    assert_eq!(Register::parse("gr3"), None);
+    assert_eq!(GR7.number(), 7);
+    assert_eq!(Register::from_number(8), None);
+    assert!(!is_index_register("GR0"));
+    assert!(is_index_register("GR1"));
 }
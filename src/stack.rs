@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+
+use ast::{Line,Macro,Span,Statement};
+use cfg::{Graph,Subroutine};
+
+// スタックの使い方の誤り
+#[derive(Debug,Clone,PartialEq)]
+pub struct Problem {
+    pub subroutine: String,
+    pub span: Span,
+    pub message: String,
+    // 入口からそこまでにたどったブロック
+    pub path: Vec<String>,
+}
+
+// サブルーチンが使うスタックの語数
+//
+// ループで積む数が変わるものや再帰するものは None (決まらない)
+#[derive(Debug,Clone,PartialEq)]
+pub struct Usage {
+    pub id: String,
+    // 自分で積む最大
+    pub local: Option<u32>,
+    // 呼び出し先 (戻り番地を含む) も合わせた最大
+    pub worst: Option<u32>,
+    // 定義の見つからない呼び出し先 (戻り番地だけ数える)
+    pub unknown: Vec<String>,
+}
+
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Report {
+    pub usages: Vec<Usage>,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    // 最初のプログラムの入口から実行したときの最大
+    pub fn total(&self) -> Option<u32> {
+        self.usages.first().and_then(|u| u.worst)
+    }
+}
+
+// 命令で積む語数の増減と，その途中で一番多く積んだ語数 (マクロは展開後)
+fn effect(line: &Line) -> (i32, i32) {
+    match line.statement {
+        Statement::Macro(Macro::Rpush, _) => (7, 7),
+        Statement::Macro(Macro::Rpop, _) => (-7, 0),
+        // IN と OUT は GR1 と GR2 を退避する
+        Statement::Macro(_, _) => (0, 2),
+        Statement::Instruction(inst, _) if inst.mnemonic == "PUSH" => (1, 1),
+        Statement::Instruction(inst, _) if inst.mnemonic == "POP" => (-1, 0),
+        _ => (0, 0),
+    }
+}
+
+// 自分で積む最大と，呼び出し先とそのときに積んでいる語数
+type Local = (Option<i32>, Vec<(String,i32)>);
+
+fn is_ret(line: &Line) -> bool {
+    match line.statement {
+        Statement::Instruction(inst, _) => inst.mnemonic == "RET",
+        _ => false,
+    }
+}
+
+// 入口で0語として各ブロックの先頭で積んでいる語数を求める
+//
+// ループで積む数が変わるのは (桁ごとに PUSH するなど) よくあるので，
+// 後ろへの分岐で合流したときは誤りにせず，最大を決まらないものにする
+fn local(sub: &Subroutine, problems: &mut Vec<Problem>) -> Local {
+
+    let index: HashMap<&str,usize> = sub.blocks.iter().enumerate().map(|(i, b)| (b.name.as_str(), i)).collect();
+
+    let mut depth: Vec<Option<i32>> = vec![None; sub.blocks.len()];
+    let mut pred: Vec<Option<usize>> = vec![None; sub.blocks.len()];
+    let mut peak = Some(0);
+    let mut calls: Vec<(String,i32)> = Vec::new();
+
+    let path = |pred: &[Option<usize>], mut b: usize| {
+        let mut v = vec![sub.blocks[b].name.to_string()];
+        while let Some(p) = pred[b] {
+            v.push(sub.blocks[p].name.to_string());
+            b = p;
+        }
+        v.reverse();
+        v
+    };
+
+    if sub.blocks.is_empty() {
+        return (peak, calls);
+    }
+
+    depth[0] = Some(0);
+    let mut work = vec![0];
+
+    'blocks: while let Some(b) = work.pop() {
+
+        let block = &sub.blocks[b];
+        let mut d = depth[b].unwrap_or(0);
+
+        for l in &block.lines {
+
+            let (delta, top) = effect(l);
+            peak = peak.map(|p| p.max(d + top));
+            d += delta;
+
+            if d < 0 {
+                problems.push(Problem{
+                    subroutine: sub.id.to_string(),
+                    span: l.span,
+                    message: format!("{} without a matching push in `{}`", l.opcode.node, sub.name),
+                    path: path(&pred, b),
+                });
+                continue 'blocks;
+            }
+
+            if is_ret(l) && d != 0 {
+                problems.push(Problem{
+                    subroutine: sub.id.to_string(),
+                    span: l.span,
+                    message: format!("{} word(s) left on the stack at RET of `{}`", d, sub.name),
+                    path: path(&pred, b),
+                });
+            }
+        }
+
+        // CALL はブロックの最後にある
+        if let Some(c) = block.calls.first() {
+            calls.push((c.to_string(), d));
+        }
+
+        for (to, _) in &block.succs {
+
+            let s = index[to.as_str()];
+
+            match depth[s] {
+                None => {
+                    depth[s] = Some(d);
+                    pred[s] = Some(b);
+                    work.push(s);
+                },
+                Some(e) if e == d => {},
+                Some(_) if sub.blocks[s].line <= block.line => peak = None,
+                Some(_) => {
+                    let mut v = path(&pred, b);
+                    v.push(to.to_string());
+                    problems.push(Problem{
+                        subroutine: sub.id.to_string(),
+                        span: sub.blocks[s].lines[0].span,
+                        message: format!("Stack depth differs between paths reaching here in `{}`", sub.name),
+                        path: v,
+                    });
+                },
+            }
+        }
+    }
+
+    (peak, calls)
+}
+
+// 呼び出し先も合わせた最大
+fn worst(id: &str, locals: &HashMap<String,Local>, memo: &mut HashMap<String,Option<i32>>, visiting: &mut Vec<String>) -> Option<i32> {
+
+    if let Some(w) = memo.get(id) {
+        return *w;
+    }
+
+    // 再帰
+    if visiting.iter().any(|v| v == id) {
+        return None;
+    }
+
+    let (peak, calls) = match locals.get(id) {
+        Some(l) => l,
+        None => return Some(0),
+    };
+
+    visiting.push(id.to_string());
+
+    let mut w = *peak;
+
+    for (callee, d) in calls {
+        w = match (w, worst(callee, locals, memo, visiting)) {
+            (Some(w), Some(c)) => Some(w.max(d + 1 + c)),
+            _ => None,
+        };
+    }
+
+    visiting.pop();
+    memo.insert(id.to_string(), w);
+
+    w
+}
+
+// サブルーチンごとのスタックの使用量と，PUSH と POP の釣り合わないところ
+//
+// 呼び出している標準サブルーチンの使用量も含める
+pub fn analyze(graph: &Graph) -> Report {
+
+    let subroutines = graph.with_stdlib().subroutines;
+
+    let mut report = Report::default();
+    let mut locals: HashMap<String,Local> = HashMap::new();
+
+    // 標準サブルーチンの位置は別のソースのものなので誤りは報告しない
+    for (i, sub) in subroutines.iter().enumerate() {
+        let l = if i < graph.subroutines.len() {
+            local(sub, &mut report.problems)
+        } else {
+            local(sub, &mut Vec::new())
+        };
+        locals.insert(sub.id.to_string(), l);
+    }
+
+    let mut memo: HashMap<String,Option<i32>> = HashMap::new();
+
+    for sub in &subroutines {
+
+        let (peak, ref calls) = locals[&sub.id];
+
+        let mut unknown: Vec<String> = Vec::new();
+
+        for (callee, _) in calls {
+            if !locals.contains_key(callee) && !unknown.contains(callee) {
+                unknown.push(callee.to_string());
+            }
+        }
+
+        report.usages.push(Usage{
+            id: sub.id.to_string(),
+            local: peak.map(|p| p as u32),
+            worst: worst(&sub.id, &locals, &mut memo, &mut Vec::new()).map(|w| w as u32),
+            unknown,
+        });
+    }
+
+    report
+}
+
+#[test]
+fn test_stack() {
+
+    use cfg;
+
+    let src = "\
+MAIN     START
+         RPUSH
+         CALL    SUB
+         CALL    DIVU
+         RPOP
+         RET
+SUB      PUSH    0,GR1
+         PUSH    0,GR2
+         LD      GR1,GR1
+         JZE     SKIP
+         POP     GR2
+SKIP     POP     GR1
+         CALL    EXT
+         RET
+         END
+";
+
+    let report = analyze(&cfg::build(src).unwrap());
+
+    let messages = report.problems.iter().map(|p| (p.span.line, p.message.to_string(), p.path.join(" -> "))).collect::<Vec<_>>();
+
+    assert_eq!(messages, vec![
+        (11, "Stack depth differs between paths reaching here in `SUB`".to_string(), "SUB -> MAIN:11 -> SKIP".to_string()),
+        (13, "1 word(s) left on the stack at RET of `SUB`".to_string(), "SUB -> SKIP -> MAIN:14".to_string()),
+    ]);
+
+    // SUB は2語で，EXT は戻り番地だけ数える．DIVU は3語積む
+    assert_eq!(report.usages[0], Usage{id: "MAIN".to_string(), local: Some(7), worst: Some(7 + 1 + 3), unknown: Vec::new()});
+    assert_eq!(report.usages[1], Usage{id: "MAIN.SUB".to_string(), local: Some(2), worst: Some(2), unknown: vec!["EXT".to_string()]});
+    assert_eq!(report.usages[2].id, "DIVU");
+    assert_eq!(report.total(), Some(11));
+
+    let src = "\
+MAIN     START
+         PUSH    0,GR1
+         JZE     OUT
+LOOP     PUSH    0,GR1
+         JNZ     LOOP
+OUT      POP     GR1
+         POP     GR1
+         RET
+         END
+";
+
+    let report = analyze(&cfg::build(src).unwrap());
+    assert_eq!(report.usages[0].local, None);
+    assert_eq!(report.problems.len(), 2);
+    assert_eq!(report.problems[1].message, "POP without a matching push in `MAIN`");
+}
@@ -0,0 +1,185 @@
+use archive::Archive;
+use assembler::assemble;
+
+// 同梱しているサブルーチン (名前, 概要, ソース)
+//
+// 呼び出し規約は各ソースの先頭の注釈を参照
+pub const ROUTINES: [(&str, &str, &str); 12] = [
+    ("MULU", "符号なし乗算", include_str!("../stdlib/mulu.casl2")),
+    ("MULS", "符号付き乗算", include_str!("../stdlib/muls.casl2")),
+    ("DIVU", "符号なし除算", include_str!("../stdlib/divu.casl2")),
+    ("DIVS", "符号付き除算", include_str!("../stdlib/divs.casl2")),
+    ("UTOA", "符号なし整数を10進の文字列に変換", include_str!("../stdlib/utoa.casl2")),
+    ("ITOA", "符号付き整数を10進の文字列に変換", include_str!("../stdlib/itoa.casl2")),
+    ("ATOI", "10進の文字列を符号付き整数に変換", include_str!("../stdlib/atoi.casl2")),
+    ("XTOA", "値を4桁の16進の文字列に変換", include_str!("../stdlib/xtoa.casl2")),
+    ("ATOX", "16進の文字列を値に変換", include_str!("../stdlib/atox.casl2")),
+    ("MEMCPY", "語の並びを複写", include_str!("../stdlib/memcpy.casl2")),
+    ("MEMSET", "領域を同じ値で埋める", include_str!("../stdlib/memset.casl2")),
+    ("STRCMP", "文字列の比較", include_str!("../stdlib/strcmp.casl2")),
+];
+
+pub fn source(name: &str) -> Option<&'static str> {
+    ROUTINES
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .map(|(_, _, src)| *src)
+}
+
+// 全てのサブルーチンをアセンブルしてアーカイブにする
+pub fn archive() -> Archive {
+
+    let mut archive = Archive::new();
+
+    for (name, _, src) in ROUTINES.iter() {
+        match assemble(src) {
+            Ok(objects) => {
+                // 「stdlib NAME」でソースを表示できるので，その名前をファイル名にする
+                for mut obj in objects {
+                    obj.file = format!("stdlib:{}", name);
+                    archive.insert(obj);
+                }
+            },
+            Err(e) => panic!("{}: {}", name, e[0]),
+        }
+    }
+
+    archive
+}
+
+#[test]
+fn test_stdlib_archive() {
+
+    use linker::{link,resolve_members};
+
+    let archives = vec![archive()];
+
+    for (name, _, _) in ROUTINES.iter() {
+        let obj = archives[0].find(name).unwrap();
+        assert_eq!(&obj.name, name);
+
+        // 他のサブルーチンを呼ぶものも含めて，単独でリンクできること
+        let mut objects = vec![obj.clone()];
+        resolve_members(&mut objects, &archives);
+        assert!(link(&objects).is_ok());
+    }
+
+    assert!(source("ITOA").unwrap().contains("ITOA     START"));
+    assert!(source("PRINTF").is_none());
+}
+
+#[test]
+fn test_stdlib_routines() {
+
+    use comet2::Comet2;
+    use linker::{link,resolve_members};
+
+    let archives = vec![archive()];
+
+    // レジスタに値を入れてサブルーチンを呼び，戻ったところで止める
+    let call = |name: &str, regs: &[u16], data: &[(u16, &str)]| -> Comet2 {
+
+        let mut src = "MAIN     START\n".to_string();
+        for i in 0..regs.len() {
+            src.push_str(&format!("         LD      GR{},R{}\n", i + 1, i + 1));
+        }
+        src.push_str(&format!("         CALL    {}\n         RET\n", name));
+        for (i, v) in regs.iter().enumerate() {
+            src.push_str(&format!("R{}       DC      {}\n", i + 1, v));
+        }
+        src.push_str("         END\n");
+
+        let mut objects = assemble(&src).unwrap();
+        resolve_members(&mut objects, &archives);
+
+        let mut cpu = Comet2::load(&link(&objects).unwrap(), 0);
+        for (addr, s) in data {
+            for (i, c) in s.chars().enumerate() {
+                cpu.write(addr + i as u16, c as u16);
+            }
+        }
+
+        cpu.run(1_000_000).unwrap();
+        cpu
+    };
+
+    let string = |cpu: &Comet2, addr: u16| -> String {
+        (0..cpu.gr[0]).map(|i| (cpu.read(addr + i) as u8) as char).collect()
+    };
+
+    let values: [u16; 14] = [0, 1, 2, 3, 7, 10, 255, 256, 1000, 12345, 32767, 32768, 40000, 65535];
+
+    for &a in values.iter() {
+        for &b in values.iter() {
+
+            let cpu = call("MULU", &[a, b], &[]);
+            assert_eq!((cpu.gr[0], cpu.gr[1], cpu.gr[2]), (a.wrapping_mul(b), a, b), "MULU {} {}", a, b);
+
+            let p = (a as i16 as i32) * (b as i16 as i32);
+            let cpu = call("MULS", &[a, b], &[]);
+            assert_eq!((cpu.gr[0], cpu.of), (p as u16, !(-32768..=32767).contains(&p)), "MULS {} {}", a, b);
+
+            let cpu = call("DIVU", &[a, b], &[]);
+            match (a.checked_div(b), a.checked_rem(b)) {
+                (Some(q), Some(r)) => assert_eq!((cpu.gr[0], cpu.gr[1], cpu.of), (q, r, false), "DIVU {} {}", a, b),
+                _ => assert!(cpu.of, "DIVU {} 0", a),
+            }
+
+            let (x, y) = (a as i16 as i32, b as i16 as i32);
+            let cpu = call("DIVS", &[a, b], &[]);
+            if y == 0 {
+                assert!(cpu.of, "DIVS {} 0", x);
+            } else {
+                // 商は0に向かって切り捨て，余りは被除数と同じ符号
+                let q = x / y;
+                assert_eq!((cpu.gr[0], cpu.gr[1], cpu.of), (q as u16, (x % y) as u16, q > 32767), "DIVS {} {}", x, y);
+            }
+        }
+
+        let buf = 0x4000;
+
+        let cpu = call("UTOA", &[a, buf], &[]);
+        assert_eq!(string(&cpu, buf), a.to_string());
+        let cpu = call("ITOA", &[a, buf], &[]);
+        assert_eq!(string(&cpu, buf), (a as i16).to_string());
+        let cpu = call("XTOA", &[a, buf], &[]);
+        assert_eq!(string(&cpu, buf), format!("{:0>4X}", a));
+
+        for s in [(a as i16).to_string(), format!("+{}", a % 1000), format!("{:x}", a), format!("{:X}", a)].iter() {
+
+            let cpu = call("ATOI", &[buf, s.len() as u16], &[(buf, s)]);
+            match s.parse::<i16>() {
+                Ok(v) => assert_eq!((cpu.gr[0], cpu.of), (v as u16, false), "ATOI {}", s),
+                Err(_) => assert!(cpu.of, "ATOI {}", s),
+            }
+
+            let cpu = call("ATOX", &[buf, s.len() as u16], &[(buf, s)]);
+            // 5桁以上は下位16ビットになる
+            match u32::from_str_radix(s, 16) {
+                Ok(v) if !s.starts_with('+') => assert_eq!((cpu.gr[0], cpu.of), (v as u16, false), "ATOX {}", s),
+                _ => assert!(cpu.of, "ATOX {}", s),
+            }
+        }
+    }
+
+    // 範囲の端と，収まらないもの，数字がないもの
+    for s in ["32767", "-32768", "+00032767", "32768", "-32769", "70000", "-", "+", ""].iter() {
+        let cpu = call("ATOI", &[0x5000, s.len() as u16], &[(0x5000, s)]);
+        match s.parse::<i16>() {
+            Ok(v) => assert_eq!((cpu.gr[0], cpu.of), (v as u16, false), "ATOI {}", s),
+            Err(_) => assert!(cpu.of, "ATOI {}", s),
+        }
+    }
+
+    let cpu = call("MEMCPY", &[0x6000, 0x5000, 5], &[(0x5000, "hello!")]);
+    assert_eq!(&cpu.memory[0x6000..0x6006], &[104, 101, 108, 108, 111, 0]);
+
+    let cpu = call("MEMSET", &[0x6000, 7, 5], &[]);
+    assert_eq!(&cpu.memory[0x6000..0x6006], &[7, 7, 7, 7, 7, 0]);
+
+    for (a, b) in [("abc", "abd"), ("abc", "abc"), ("abd", "abc"), ("ab", "abc"), ("abc", "ab"), ("", ""), ("", "a"), ("a", "")].iter() {
+        let cpu = call("STRCMP", &[0x5000, a.len() as u16, 0x6000, b.len() as u16], &[(0x5000, a), (0x6000, b)]);
+        let expected = (a > b) as i16 - (a < b) as i16;
+        assert_eq!((cpu.gr[0] as i16, cpu.sf, cpu.zf), (expected, expected < 0, expected == 0), "STRCMP {} {}", a, b);
+    }
+}
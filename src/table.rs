@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use comet2::{Comet2,Hook};
+use console::Console;
+use debuginfo::DebugInfo;
+use register::Register;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TableFormat {
+    Markdown,
+    Csv,
+}
+
+impl TableFormat {
+    pub fn parse(s: &str) -> Option<TableFormat> {
+        match s {
+            "markdown" | "md" => Some(TableFormat::Markdown),
+            "csv" => Some(TableFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Value {
+    Register(Register),
+    Sp,
+    Pr,
+    // OF, SF, ZF の順の番号
+    Flag(usize),
+    Memory(u16),
+}
+
+// 表の1列
+//
+// GR1，SP，PR，OF，ラベル，ラベル+数 のあとに「:d」のように表示形式を付けられる．
+// 表示形式は x (16進，既定)，d (符号付き)，u (符号なし)，b (2進)
+#[derive(Debug,Clone,PartialEq)]
+pub struct Column {
+    pub name: String,
+    value: Value,
+    radix: char,
+}
+
+impl Column {
+
+    pub fn parse(spec: &str, info: &DebugInfo) -> Result<Column, String> {
+
+        let (name, radix) = match spec.rfind(':') {
+            Some(i) if spec[i+1..].len() == 1 && "xdub".contains(&spec[i+1..]) => (&spec[..i], spec[i+1..].chars().next().unwrap()),
+            Some(i) => return Err(format!("Unknown format `{}`: use x, d, u or b", &spec[i+1..])),
+            None => (spec, 'x'),
+        };
+
+        let value = match name {
+            "SP" => Value::Sp,
+            "PR" => Value::Pr,
+            "OF" => Value::Flag(0),
+            "SF" => Value::Flag(1),
+            "ZF" => Value::Flag(2),
+            _ => match Register::parse(name) {
+                Some(r) => Value::Register(r),
+                None => Value::Memory(info.resolve(name)?),
+            },
+        };
+
+        Ok(Column{name: name.to_string(), value, radix})
+    }
+
+    fn show<C: Console>(&self, cpu: &Comet2<C>) -> String {
+
+        let v = match self.value {
+            Value::Register(r) => cpu.gr[r.number() as usize],
+            Value::Sp => cpu.sp,
+            Value::Pr => cpu.pr,
+            Value::Flag(i) => return ([cpu.of, cpu.sf, cpu.zf][i] as u8).to_string(),
+            Value::Memory(addr) => cpu.read(addr),
+        };
+
+        match self.radix {
+            'd' => (v as i16).to_string(),
+            'u' => v.to_string(),
+            'b' => format!("{:0>16b}", v),
+            _ => format!("#{:0>4X}", v),
+        }
+    }
+}
+
+// 試験のトレース問題のように，決めた番地に来るたびに値を1行ずつ記録する表
+//
+// 行はその番地の命令を実行する前の値
+pub struct TraceTable {
+    pub columns: Vec<Column>,
+    pub points: Vec<u16>,
+    pub rows: Vec<Vec<String>>,
+    names: HashMap<u16,String>,
+}
+
+impl TraceTable {
+
+    // columns は列，points は記録する番地 (LOC)
+    pub fn new(columns: &[String], points: &[String], info: &DebugInfo) -> Result<TraceTable, String> {
+
+        let columns = columns
+            .iter()
+            .map(|s| Column::parse(s, info))
+            .collect::<Result<Vec<Column>,String>>()?;
+
+        let mut names: HashMap<u16,String> = HashMap::new();
+        let mut addrs: Vec<u16> = Vec::new();
+
+        for p in points {
+            let addr = info.resolve(p)?;
+            names.entry(addr).or_insert_with(|| p.to_string());
+            addrs.push(addr);
+        }
+
+        Ok(TraceTable{columns, points: addrs, rows: Vec::new(), names})
+    }
+
+    fn header(&self) -> Vec<String> {
+        let mut v = vec!["step".to_string(), "at".to_string()];
+        v.extend(self.columns.iter().map(|c| c.name.to_string()));
+        v
+    }
+
+    pub fn to_markdown(&self) -> String {
+
+        let header = self.header();
+
+        let widths = (0..header.len())
+            .map(|i| self.rows.iter().map(|r| r[i].chars().count()).chain(Some(header[i].len())).max().unwrap_or(0))
+            .collect::<Vec<usize>>();
+
+        let line = |cells: &[String]| {
+            let cells = cells.iter().zip(widths.iter()).map(|(c, w)| format!("{:<1$}", c, w)).collect::<Vec<String>>();
+            format!("| {} |\n", cells.join(" | "))
+        };
+
+        let mut s = line(&header);
+        s.push_str(&format!("|{}|\n", widths.iter().map(|w| "-".repeat(w + 2)).collect::<Vec<String>>().join("|")));
+
+        for row in &self.rows {
+            s.push_str(&line(row));
+        }
+
+        s
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut s = self.header().join(",") + "\n";
+        for row in &self.rows {
+            s.push_str(&row.join(","));
+            s.push('\n');
+        }
+        s
+    }
+}
+
+impl<C: Console> Hook<C> for TraceTable {
+
+    fn before(&mut self, cpu: &Comet2<C>) -> Result<(), String> {
+
+        if !self.points.contains(&cpu.pr) {
+            return Ok(());
+        }
+
+        let mut row = vec![cpu.steps.to_string(), self.names[&cpu.pr].to_string()];
+        row.extend(self.columns.iter().map(|c| c.show(cpu)));
+        self.rows.push(row);
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_trace_table() {
+
+    use assembler::assemble;
+    use console::Buffer;
+    use linker::link;
+
+    let src = "\
+MAIN     START
+         LAD     GR1,3
+         LAD     GR2,0
+LOOP     ADDA    GR2,GR1
+         ST      GR2,SUM
+         SUBA    GR1,=1
+         JNZ     LOOP
+FIN      RET
+SUM      DS      1
+         END
+";
+
+    let objects = assemble(src).unwrap();
+    let info = DebugInfo::link(&objects);
+    let mut cpu = Comet2::load_with_console(&link(&objects).unwrap(), 0, Buffer::new(""));
+
+    let columns = ["GR1:d", "GR2", "SUM:u", "ZF"].iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    let mut table = TraceTable::new(&columns, &["LOOP".to_string(), "FIN".to_string()], &info).unwrap();
+
+    cpu.run_with(100, &mut [&mut table]).unwrap();
+
+    assert_eq!(table.to_markdown(), "\
+| step | at   | GR1 | GR2   | SUM | ZF |
+|------|------|-----|-------|-----|----|
+| 2    | LOOP | 3   | #0000 | 0   | 0  |
+| 6    | LOOP | 2   | #0003 | 3   | 0  |
+| 10   | LOOP | 1   | #0005 | 5   | 0  |
+| 14   | FIN  | 0   | #0006 | 6   | 1  |
+");
+
+    assert!(table.to_csv().starts_with("step,at,GR1,GR2,SUM,ZF\n2,LOOP,3,#0000,0,0\n"));
+
+    assert!(Column::parse("GR1:z", &info).is_err());
+    assert!(Column::parse("NONE", &info).is_err());
+}
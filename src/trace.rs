@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use comet2::{Comet2,Hook};
+use console::Console;
+use debuginfo::DebugInfo;
+use disasm::{decode,format_operands};
+use history::Snapshot;
+use json::Json;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TraceFormat {
+    // 1行に1つのJSON (JSON Lines)
+    Jsonl,
+    Csv,
+}
+
+impl TraceFormat {
+    pub fn parse(s: &str) -> Option<TraceFormat> {
+        match s {
+            "jsonl" | "json" => Some(TraceFormat::Jsonl),
+            "csv" => Some(TraceFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+// 記録する命令の番地の範囲 (両端を含む)．空なら全て記録する
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Filter {
+    pub ranges: Vec<(u16,u16)>,
+}
+
+impl Filter {
+
+    // FROM-TO，プログラム名 (そのプログラム全体)，LOC (その番地だけ)
+    pub fn parse(specs: &[String], info: &DebugInfo) -> Result<Filter, String> {
+
+        let mut ranges: Vec<(u16,u16)> = Vec::new();
+
+        for spec in specs {
+            if let Some(i) = spec.find('-') {
+                ranges.push((info.resolve(&spec[..i])?, info.resolve(&spec[i+1..])?));
+            } else if let Some((start, end)) = info.program(spec) {
+                if start < end {
+                    ranges.push((start, end - 1));
+                }
+            } else {
+                let addr = info.resolve(spec)?;
+                ranges.push((addr, addr));
+            }
+        }
+
+        Ok(Filter{ranges})
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|(start, end)| *start <= addr && addr <= *end)
+    }
+}
+
+const FLAGS: [&str; 3] = ["OF", "SF", "ZF"];
+
+// 1命令分の記録
+struct Record {
+    step: u64,
+    pr: u16,
+    label: Option<String>,
+    instruction: String,
+    // 変わったレジスタ (GR0〜GR7, SP) と新しい値
+    registers: Vec<(String,u16)>,
+    flags: Vec<(&'static str,bool)>,
+    // 書き込んだ番地と値
+    writes: Vec<(u16,u16)>,
+}
+
+impl Record {
+
+    fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("step", Json::Number(self.step as i64)),
+            ("pr", Json::from(self.pr)),
+            ("label", self.label.as_ref().map_or(Json::Null, |s| Json::string(s))),
+            ("instruction", Json::string(&self.instruction)),
+            ("registers", Json::Object(self.registers.iter().map(|(r, v)| (r.to_string(), Json::from(*v))).collect())),
+            ("flags", Json::Object(self.flags.iter().map(|(f, v)| (f.to_string(), Json::from(*v))).collect())),
+            ("writes", Json::Array(self.writes.iter().map(|(addr, v)| Json::object(vec![
+                ("addr", Json::from(*addr)),
+                ("value", Json::from(*v)),
+            ])).collect())),
+        ])
+    }
+
+    fn to_csv(&self) -> String {
+
+        let registers = self.registers.iter().map(|(r, v)| format!("{}=#{:0>4X}", r, v)).collect::<Vec<String>>();
+        let flags = self.flags.iter().map(|(f, v)| format!("{}={}", f, *v as u8)).collect::<Vec<String>>();
+        let writes = self.writes.iter().map(|(addr, v)| format!("#{:0>4X}=#{:0>4X}", addr, v)).collect::<Vec<String>>();
+
+        [
+            self.step.to_string(),
+            format!("#{:0>4X}", self.pr),
+            self.label.clone().unwrap_or_default(),
+            self.instruction.to_string(),
+            registers.join(" "),
+            flags.join(" "),
+            writes.join(" "),
+        ].iter().map(|s| csv_field(s)).collect::<Vec<String>>().join(",")
+    }
+}
+
+const CSV_HEADER: &str = "step,pr,label,instruction,registers,flags,writes";
+
+// カンマや引用符を含むものだけ引用符で囲む
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// 実行した命令を1つずつ書き出す
+//
+// 記録はためずにそのまま書き出すので，長い実行でもメモリを使わない
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+    filter: Filter,
+    names: HashMap<u16,String>,
+    // 実行中の命令の，実行前のレジスタと命令
+    current: Option<(Snapshot,String)>,
+    started: bool,
+}
+
+impl<W: Write> Tracer<W> {
+
+    pub fn new(out: W, format: TraceFormat, filter: Filter, info: &DebugInfo) -> Tracer<W> {
+
+        let mut sorted: Vec<(&String,&u16)> = info.labels.iter().collect();
+        sorted.sort();
+
+        let mut names: HashMap<u16,String> = HashMap::new();
+        for (name, addr) in sorted {
+            names.entry(*addr).or_insert_with(|| name.to_string());
+        }
+
+        Tracer{out, format, filter, names, current: None, started: false}
+    }
+
+    // 書き出しきれていない分を書き出す
+    pub fn finish(&mut self) -> Result<(), String> {
+        self.out.flush().map_err(|e| e.to_string())
+    }
+
+    fn instruction<C: Console>(&self, cpu: &Comet2<C>, pr: u16) -> String {
+        match decode(&[cpu.read(pr), cpu.read(pr.wrapping_add(1))]) {
+            Some(d) => format!("{} {}", d.inst.mnemonic, format_operands(&d, &self.names)).trim_end().to_string(),
+            None => String::new(),
+        }
+    }
+
+    fn record<C: Console>(&self, cpu: &Comet2<C>, pr: u16, instruction: String, before: &Snapshot) -> Record {
+
+        let mut registers: Vec<(String,u16)> = Vec::new();
+
+        for (i, v) in cpu.gr.iter().enumerate() {
+            if *v != before.gr[i] {
+                registers.push((format!("GR{}", i), *v));
+            }
+        }
+
+        if cpu.sp != before.sp {
+            registers.push(("SP".to_string(), cpu.sp));
+        }
+
+        let flags = [cpu.of, cpu.sf, cpu.zf]
+            .iter()
+            .zip([before.of, before.sf, before.zf].iter())
+            .zip(FLAGS.iter())
+            .filter(|((after, before), _)| after != before)
+            .map(|((after, _), name)| (*name, *after))
+            .collect();
+
+        // 同じ番地に何度か書いたときは最後の値だけにする
+        let mut writes: Vec<(u16,u16)> = Vec::new();
+        for (addr, _) in &cpu.writes {
+            if !writes.iter().any(|(a, _)| a == addr) {
+                writes.push((*addr, cpu.read(*addr)));
+            }
+        }
+
+        Record{
+            step: cpu.steps,
+            pr,
+            label: self.names.get(&pr).cloned(),
+            instruction,
+            registers,
+            flags,
+            writes,
+        }
+    }
+}
+
+impl<W: Write, C: Console> Hook<C> for Tracer<W> {
+
+    fn before(&mut self, cpu: &Comet2<C>) -> Result<(), String> {
+
+        if !self.started && self.format == TraceFormat::Csv {
+            writeln!(self.out, "{}", CSV_HEADER).map_err(|e| e.to_string())?;
+        }
+        self.started = true;
+
+        self.current = if self.filter.contains(cpu.pr) {
+            Some((Snapshot::of(cpu), self.instruction(cpu, cpu.pr)))
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+
+    fn after(&mut self, cpu: &Comet2<C>, pr: u16) -> Result<(), String> {
+
+        let (before, instruction) = match self.current.take() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        let record = self.record(cpu, pr, instruction, &before);
+
+        match self.format {
+            TraceFormat::Jsonl => writeln!(self.out, "{}", record.to_json()),
+            TraceFormat::Csv => writeln!(self.out, "{}", record.to_csv()),
+        }.map_err(|e| e.to_string())
+    }
+}
+
+#[test]
+fn test_trace() {
+
+    use assembler::assemble;
+    use console::Buffer;
+    use linker::link;
+
+    let src = "\
+MAIN     START
+         LAD     GR1,2
+LOOP     ST      GR1,BUF
+         SUBA    GR1,=1
+         JNZ     LOOP
+         RET
+BUF      DS      1
+         END
+";
+
+    let objects = assemble(src).unwrap();
+    let code = link(&objects).unwrap();
+    let info = DebugInfo::link(&objects);
+
+    let mut cpu = Comet2::load_with_console(&code, 0, Buffer::new(""));
+    let mut tracer = Tracer::new(Vec::new(), TraceFormat::Jsonl, Filter::default(), &info);
+    cpu.run_with(100, &mut [&mut tracer]).unwrap();
+
+    let out = String::from_utf8(tracer.out).unwrap();
+    let lines = out.lines().collect::<Vec<&str>>();
+
+    assert_eq!(lines.len(), 8);
+    assert_eq!(lines[0], r#"{"step":1,"pr":0,"label":"MAIN","instruction":"LAD GR1,2","registers":{"GR1":2},"flags":{},"writes":[]}"#);
+    assert_eq!(lines[1], r#"{"step":2,"pr":2,"label":"LOOP","instruction":"ST GR1,BUF","registers":{},"flags":{},"writes":[{"addr":9,"value":2}]}"#);
+    assert_eq!(lines[5], r#"{"step":6,"pr":4,"label":null,"instruction":"SUBA GR1,#000A","registers":{"GR1":0},"flags":{"ZF":true},"writes":[]}"#);
+
+    // LOOPからJNZまでだけをCSVで
+    let filter = Filter::parse(&["LOOP-LOOP+5".to_string()], &info).unwrap();
+    let mut cpu = Comet2::load_with_console(&code, 0, Buffer::new(""));
+    let mut tracer = Tracer::new(Vec::new(), TraceFormat::Csv, filter, &info);
+    cpu.run_with(100, &mut [&mut tracer]).unwrap();
+
+    let out = String::from_utf8(tracer.out).unwrap();
+    let lines = out.lines().collect::<Vec<&str>>();
+
+    assert_eq!(lines.len(), 7);
+    assert_eq!(lines[0], CSV_HEADER);
+    assert_eq!(lines[1], "2,#0002,LOOP,\"ST GR1,BUF\",,,#0009=#0002");
+    assert_eq!(lines[5], "6,#0004,,\"SUBA GR1,#000A\",GR1=#0000,ZF=1,");
+
+    assert_eq!(Filter::parse(&["MAIN".to_string()], &info), Ok(Filter{ranges: vec![(0, 10)]}));
+    assert!(Filter::parse(&["NONE".to_string()], &info).is_err());
+}
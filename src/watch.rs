@@ -0,0 +1,200 @@
+use comet2::{Comet2,Hook};
+use console::Console;
+use debuginfo::DebugInfo;
+use expr::Expr;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Kind {
+    // その番地の命令を実行する前
+    Break,
+    // その番地に書き込んだ後
+    Write,
+    // その番地を読み出した後
+    Read,
+    // 読み書きのどちらか
+    Access,
+    // 条件が偽から真に変わったとき (番地はない)
+    When,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Break => "Breakpoint",
+            Kind::When => "Condition",
+            Kind::Write => "Watchpoint",
+            Kind::Read => "Read watchpoint",
+            Kind::Access => "Access watchpoint",
+        }
+    }
+}
+
+// ブレークポイントとウォッチポイント
+//
+// 条件 (「if 式」) があれば，それが真のときだけ止まる
+#[derive(Debug,Clone,PartialEq)]
+pub struct Watchpoint {
+    pub kind: Kind,
+    pub addr: u16,
+    pub condition: Option<Expr>,
+    // 指定されたとおりの文字列
+    pub spec: String,
+    // When の直前の値
+    last: bool,
+}
+
+impl Watchpoint {
+
+    // 「LOC」「LOC if 式」，When なら「式」
+    pub fn parse<C: Console>(kind: Kind, spec: &str, info: &DebugInfo, cpu: &Comet2<C>) -> Result<Watchpoint, String> {
+
+        let spec = spec.trim();
+
+        if kind == Kind::When {
+            let e = Expr::parse(spec, &info.labels)?;
+            let last = e.holds(cpu);
+            return Ok(Watchpoint{kind, addr: 0, condition: Some(e), spec: spec.to_string(), last});
+        }
+
+        let (loc, condition) = match spec.find(" if ") {
+            Some(i) => (spec[..i].trim(), Some(Expr::parse(&spec[i+4..], &info.labels)?)),
+            None => (spec, None),
+        };
+
+        Ok(Watchpoint{kind, addr: info.resolve(loc)?, condition, spec: spec.to_string(), last: false})
+    }
+
+    // 条件のないもの
+    pub fn at(kind: Kind, addr: u16) -> Watchpoint {
+        Watchpoint{kind, addr, condition: None, spec: format!("#{:0>4X}", addr), last: false}
+    }
+
+    fn condition_holds<C: Console>(&self, cpu: &Comet2<C>) -> bool {
+        self.condition.as_ref().is_none_or(|e| e.holds(cpu))
+    }
+
+    // 実行する前に止まるか
+    pub fn before<C: Console>(&self, cpu: &Comet2<C>) -> bool {
+        self.kind == Kind::Break && cpu.pr == self.addr && self.condition_holds(cpu)
+    }
+
+    // 1命令実行した後に止まるなら，その理由を返す
+    pub fn after<C: Console>(&mut self, cpu: &Comet2<C>) -> Option<String> {
+
+        let old = cpu.writes.iter().find(|(addr, _)| *addr == self.addr).map(|(_, v)| *v);
+        let read = cpu.reads.contains(&self.addr);
+
+        let hit = match self.kind {
+            Kind::Break => return None,
+            Kind::When => {
+                let now = self.condition_holds(cpu);
+                let changed = now && !self.last;
+                self.last = now;
+                changed
+            },
+            Kind::Write => old.is_some() && self.condition_holds(cpu),
+            Kind::Read => read && self.condition_holds(cpu),
+            Kind::Access => (read || old.is_some()) && self.condition_holds(cpu),
+        };
+
+        if !hit {
+            return None;
+        }
+
+        let value = cpu.read(self.addr);
+
+        Some(match (self.kind, old) {
+            (Kind::When, _) => format!("{}: {}", self.kind.name(), self.spec),
+            (_, Some(old)) => format!("{}: {} #{:0>4X} -> #{:0>4X}", self.kind.name(), self.spec, old, value),
+            _ => format!("{}: {} = #{:0>4X}", self.kind.name(), self.spec, value),
+        })
+    }
+}
+
+// 実行中にウォッチポイントや条件が成り立ったら止めるもの
+pub struct Stopper {
+    pub points: Vec<Watchpoint>,
+}
+
+impl<C: Console> Hook<C> for Stopper {
+
+    fn before(&mut self, cpu: &Comet2<C>) -> Result<(), String> {
+        match self.points.iter().find(|w| w.before(cpu)) {
+            Some(w) => Err(format!("Breakpoint: {} at #{:0>4X}", w.spec, cpu.pr)),
+            None => Ok(()),
+        }
+    }
+
+    fn after(&mut self, cpu: &Comet2<C>, pr: u16) -> Result<(), String> {
+        for w in &mut self.points {
+            if let Some(s) = w.after(cpu) {
+                return Err(format!("{} after #{:0>4X}", s, pr));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_watchpoint() {
+
+    use assembler::assemble;
+    use console::Buffer;
+    use linker::link;
+
+    let src = "\
+MAIN     START
+         LAD     GR1,3
+LOOP     ST      GR1,CNT
+         LD      GR2,CNT
+         SUBA    GR1,=1
+         JNZ     LOOP
+         RET
+CNT      DS      1
+         END
+";
+
+    let objects = assemble(src).unwrap();
+    let info = DebugInfo::link(&objects);
+    let code = link(&objects).unwrap();
+
+    let cpu = Comet2::load_with_console(&code, 0, Buffer::new(""));
+
+    let points = vec![
+        Watchpoint::parse(Kind::Write, "CNT if [CNT] == 1", &info, &cpu).unwrap(),
+    ];
+
+    let mut cpu = Comet2::load_with_console(&code, 0, Buffer::new(""));
+    let mut stopper = Stopper{points};
+    assert_eq!(cpu.run_with(100, &mut [&mut stopper]),
+               Err("Watchpoint: CNT if [CNT] == 1 #0002 -> #0001 after #0002".to_string()));
+
+    // 読み出しと，条件が真になったとき
+    let mut cpu = Comet2::load_with_console(&code, 0, Buffer::new(""));
+    let mut read = Watchpoint::parse(Kind::Read, "CNT", &info, &cpu).unwrap();
+    let mut when = Watchpoint::parse(Kind::When, "GR1 == 1 && !ZF", &info, &cpu).unwrap();
+
+    cpu.step().unwrap();
+    cpu.step().unwrap();
+    assert_eq!(read.after(&cpu), None);
+    cpu.step().unwrap();
+    assert_eq!(read.after(&cpu), Some("Read watchpoint: CNT = #0003".to_string()));
+
+    let mut hits = 0;
+    while cpu.step().unwrap() == ::comet2::State::Running {
+        if when.after(&cpu).is_some() {
+            hits += 1;
+            assert_eq!(cpu.gr[1], 1);
+        }
+    }
+    assert_eq!(hits, 1);
+
+    let b = Watchpoint::parse(Kind::Break, "LOOP if GR1 == 2", &info, &cpu).unwrap();
+    cpu.pr = 2;
+    cpu.gr[1] = 2;
+    assert!(b.before(&cpu));
+    cpu.gr[1] = 3;
+    assert!(!b.before(&cpu));
+
+    assert!(Watchpoint::parse(Kind::Write, "CNT if GR9", &info, &cpu).is_err());
+}